@@ -10,11 +10,11 @@ use tempfile::TempDir;
 fn store_write<E: KvsEngine>(r: &mut StdRng, store: &E) {
     for i in 1..=100 {
         let key_len = r.gen_range(1..=100000);
-        let key_content = std::iter::repeat('a').take(key_len).collect::<String>();
+        let key_content = "a".repeat(key_len);
         let key = format!("key{}{}", i, key_content);
 
         let val_len = r.gen_range(1..=100000);
-        let val = std::iter::repeat('a').take(val_len).collect::<String>();
+        let val = "a".repeat(val_len);
 
         store.set(key, val).unwrap();
     }
@@ -23,15 +23,15 @@ fn store_write<E: KvsEngine>(r: &mut StdRng, store: &E) {
 fn store_read<E: KvsEngine>(r: &mut StdRng, store: &E) {
     for i in 1..=100 {
         let key_len = r.gen_range(1..=100000);
-        let key_content = std::iter::repeat('a').take(key_len).collect::<String>();
+        let key_content = "a".repeat(key_len);
         let key = format!("key{}{}", i, key_content);
 
         let val_len = r.gen_range(1..=100000);
-        let expected_val = std::iter::repeat('a').take(val_len).collect::<String>();
+        let expected_val = "a".repeat(val_len);
 
         let get_val = store.get(key).unwrap().unwrap();
 
-        assert_eq!(expected_val, get_val);
+        assert_eq!(expected_val.into_bytes(), get_val);
     }
 }
 
@@ -4,7 +4,7 @@ use std::sync::mpsc::{self, Receiver, Sender};
 use criterion::Criterion;
 use criterion::{criterion_group, criterion_main};
 use kvs::{thread_pool::*, SledStore};
-use kvs::{KvServer, KvStore, KvsClient, KvsError};
+use kvs::{KvServer, KvStore, KvsClient};
 use tempfile::TempDir;
 
 const SERVER_SOCKET_ADDR: &str = "127.0.0.1:4000";
@@ -19,8 +19,8 @@ fn client_write_workload(pool: impl ThreadPool) {
         pool.spawn(move || {
             let key = format!("key_{}", i);
             let value = format!("value_{}", i);
-            let response = KvsClient::set(key, value, SERVER_SOCKET_ADDR);
-            assert!(matches!(response.status, KvsError::ErrOk));
+            let mut client = KvsClient::connect(SERVER_SOCKET_ADDR).unwrap();
+            client.set(key, value).unwrap();
             sender.send(0).unwrap();
         });
     }
@@ -37,9 +37,9 @@ fn client_read_workload(pool: impl ThreadPool) {
         pool.spawn(move || {
             let key = format!("key_{}", i);
             let value = format!("value_{}", i);
-            let response = KvsClient::get(key, SERVER_SOCKET_ADDR);
-            assert!(matches!(response.status, KvsError::ErrOk));
-            assert_eq!(response.value, value);
+            let mut client = KvsClient::connect(SERVER_SOCKET_ADDR).unwrap();
+            let response = client.get(key).unwrap();
+            assert_eq!(response, Some(value.into_bytes()));
             sender.send(0).unwrap();
         });
     }
@@ -63,9 +63,10 @@ fn write_queued_kvstore(c: &mut Criterion) {
                 // server stop signal
                 let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
                     mpsc::channel();
-                let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+                let server =
+                    KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
                 let handle = std::thread::spawn(move || {
-                    server.start();
+                    server.start().unwrap();
                 });
 
                 c.iter(|| {
@@ -97,9 +98,10 @@ fn read_queued_kvstore(c: &mut Criterion) {
                 // server stop signal
                 let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
                     mpsc::channel();
-                let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+                let server =
+                    KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
                 let handle = std::thread::spawn(move || {
-                    server.start();
+                    server.start().unwrap();
                 });
                 // set target
                 let pool = SharedQueueThreadPool::new(4).unwrap();
@@ -134,9 +136,10 @@ fn write_rayon_kvstore(c: &mut Criterion) {
                 // server stop signal
                 let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
                     mpsc::channel();
-                let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+                let server =
+                    KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
                 let handle = std::thread::spawn(move || {
-                    server.start();
+                    server.start().unwrap();
                 });
 
                 c.iter(|| {
@@ -168,9 +171,10 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                 // server stop signal
                 let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
                     mpsc::channel();
-                let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+                let server =
+                    KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
                 let handle = std::thread::spawn(move || {
-                    server.start();
+                    server.start().unwrap();
                 });
                 // set target
                 let pool = RayonThreadPool::new(4).unwrap();
@@ -205,9 +209,10 @@ fn write_rayon_sledengine(c: &mut Criterion) {
                 // server stop signal
                 let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
                     mpsc::channel();
-                let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+                let server =
+                    KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
                 let handle = std::thread::spawn(move || {
-                    server.start();
+                    server.start().unwrap();
                 });
 
                 c.iter(|| {
@@ -239,9 +244,10 @@ fn read_rayon_sledengine(c: &mut Criterion) {
                 // server stop signal
                 let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
                     mpsc::channel();
-                let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+                let server =
+                    KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
                 let handle = std::thread::spawn(move || {
-                    server.start();
+                    server.start().unwrap();
                 });
                 // set target
                 let pool = RayonThreadPool::new(4).unwrap();
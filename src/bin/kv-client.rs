@@ -2,10 +2,13 @@ extern crate clap;
 use std::env;
 extern crate failure_derive;
 
-use clap::{App, Arg};
-use kvs::{KvsClient, KvsError};
+use clap::{App, Arg, ArgMatches};
+use kvs::{Condition, KvsClient, KvsError};
 use log::info;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::process::exit;
+use std::time::Duration;
 
 fn main() {
     env_logger::init();
@@ -16,6 +19,23 @@ fn main() {
         .subcommand(
             App::new("get")
                 .arg(Arg::new("KEY").required(true).index(1))
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .help("write the value as is, without a trailing newline"),
+                )
+                .arg(
+                    Arg::new("base64")
+                        .long("base64")
+                        .help("write the value base64-encoded")
+                        .conflicts_with("raw"),
+                )
+                .arg(
+                    Arg::new("with-version")
+                        .long("with-version")
+                        .help("write `version<TAB>value`")
+                        .conflicts_with("raw"),
+                )
                 .arg(
                     Arg::new("addr")
                         .long("addr")
@@ -26,7 +46,44 @@ fn main() {
         .subcommand(
             App::new("set")
                 .arg(Arg::new("KEY").index(1).required(true))
-                .arg(Arg::new("VALUE").index(2).required(true))
+                .arg(Arg::new("VALUE").index(2).required_unless_present("file"))
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .help("read the value from this file, `-` for stdin")
+                        .conflicts_with("VALUE")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("base64")
+                        .long("base64")
+                        .help("the value is base64-encoded"),
+                )
+                .arg(
+                    Arg::new("ttl")
+                        .long("ttl")
+                        .help("seconds after which the key expires")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("if-absent")
+                        .long("if-absent")
+                        .help("only set the key if it does not exist"),
+                )
+                .arg(
+                    Arg::new("if-equals")
+                        .long("if-equals")
+                        .help("only set the key if it holds this value")
+                        .conflicts_with("if-absent")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("if-version")
+                        .long("if-version")
+                        .help("only set the key if it is at this version, 0 if it does not exist")
+                        .conflicts_with_all(&["if-absent", "if-equals"])
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::new("addr")
                         .long("addr")
@@ -42,48 +99,236 @@ fn main() {
                     .default_value("127.0.0.1:4000"),
             ),
         )
+        .subcommand(
+            App::new("ttl")
+                .about("print the seconds left before the key expires")
+                .arg(Arg::new("KEY").required(true))
+                .arg(
+                    Arg::new("addr")
+                        .long("addr")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            App::new("persist")
+                .about("make the key never expire")
+                .arg(Arg::new("KEY").required(true))
+                .arg(
+                    Arg::new("addr")
+                        .long("addr")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            App::new("scan")
+                .about("print the pairs in key order, one `key<TAB>value` per line")
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("first key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help("end key, excluded")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .help("only the keys starting with it")
+                        .conflicts_with_all(&["from", "to"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .help("max number of pairs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("base64")
+                        .long("base64")
+                        .help("write the keys and values base64-encoded"),
+                )
+                .arg(
+                    Arg::new("addr")
+                        .long("addr")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            App::new("backup")
+                .about("have the server copy its store to DIR, a missing or empty directory in its backup directory")
+                .arg(Arg::new("DIR").required(true))
+                .arg(
+                    Arg::new("addr")
+                        .long("addr")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
         .arg(Arg::new("version").short('V'))
         .get_matches();
 
-    match matches.subcommand() {
+    let result = match matches.subcommand() {
         Some(("get", sub_m)) => {
-            let key = String::from(sub_m.value_of("KEY").unwrap());
+            let key = sub_m.value_of("KEY").unwrap();
             let addr = sub_m.value_of("addr").unwrap();
 
-            let response = KvsClient::get(key, addr);
-            info!("{:?}", response);
-            match response.status {
-                KvsError::ErrKeyNotFound => {
-                    println!("{}", response.status);
-                }
-                KvsError::ErrOk => {
-                    println!("{}", response.value);
+            let value = KvsClient::connect(addr).and_then(|mut client| client.get_versioned(key));
+            value.and_then(|value| {
+                info!("{:?}", value);
+                let text = |value: &[u8]| {
+                    if sub_m.is_present("base64") {
+                        base64::encode(value)
+                    } else {
+                        String::from_utf8_lossy(value).into_owned()
+                    }
+                };
+                match value {
+                    Some((value, _)) if sub_m.is_present("raw") => {
+                        let mut stdout = io::stdout();
+                        stdout.write_all(&value)?;
+                        stdout.flush()?;
+                    }
+                    Some((value, version)) if sub_m.is_present("with-version") => {
+                        println!("{}\t{}", version, text(&value))
+                    }
+                    Some((value, _)) => println!("{}", text(&value)),
+                    None => println!("{}", KvsError::ErrKeyNotFound),
                 }
-            }
+                Ok(())
+            })
         }
         Some(("set", sub_m)) => {
-            let key = String::from(sub_m.value_of("KEY").unwrap());
-            let value = String::from(sub_m.value_of("VALUE").unwrap());
+            let key = sub_m.value_of("KEY").unwrap();
             let addr = sub_m.value_of("addr").unwrap();
 
-            KvsClient::set(key, value, addr);
+            let ttl = if sub_m.is_present("ttl") {
+                Some(sub_m.value_of_t("ttl").unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
+
+            let condition = if sub_m.is_present("if-absent") {
+                Some(Condition::Absent)
+            } else if let Some(expected) = sub_m.value_of("if-equals") {
+                Some(Condition::Equals(expected.into()))
+            } else if sub_m.is_present("if-version") {
+                Some(Condition::Version(
+                    sub_m.value_of_t("if-version").unwrap_or_else(|e| e.exit()),
+                ))
+            } else {
+                None
+            };
+
+            read_value(sub_m).and_then(|value| {
+                let mut client = KvsClient::connect(addr)?;
+                match (condition, ttl) {
+                    (Some(condition), ttl) => {
+                        client.set_if(key, value, condition, ttl.map(Duration::from_secs))
+                    }
+                    (None, Some(ttl)) => client.set_with_ttl(key, value, Duration::from_secs(ttl)),
+                    (None, None) => client.set(key, value),
+                }
+            })
         }
         Some(("rm", sub_m)) => {
-            let key = String::from(sub_m.value_of("KEY").unwrap());
+            let key = sub_m.value_of("KEY").unwrap();
+            let addr = sub_m.value_of("addr").unwrap();
+
+            KvsClient::connect(addr).and_then(|mut client| client.remove(key))
+        } // rm was used
+        Some(("ttl", sub_m)) => {
+            let key = sub_m.value_of("KEY").unwrap();
             let addr = sub_m.value_of("addr").unwrap();
 
-            let response = KvsClient::remove(key, addr);
+            let ttl = match KvsClient::connect(addr).and_then(|mut client| client.ttl(key)) {
+                Err(KvsError::ErrKeyNotFound) => Ok(None),
+                result => result.map(Some),
+            };
+            ttl.map(|ttl| match ttl {
+                // rounded up, a key is only gone once its last second has passed
+                Some(Some(ttl)) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                Some(None) => println!("No expiry"),
+                None => println!("{}", KvsError::ErrKeyNotFound),
+            })
+        }
+        Some(("persist", sub_m)) => {
+            let key = sub_m.value_of("KEY").unwrap();
+            let addr = sub_m.value_of("addr").unwrap();
 
-            match response.status {
-                KvsError::ErrKeyNotFound => {
-                    eprintln!("Key not found");
-                    exit(1);
+            KvsClient::connect(addr).and_then(|mut client| client.persist(key))
+        }
+        Some(("scan", sub_m)) => {
+            let addr = sub_m.value_of("addr").unwrap();
+            let limit = if sub_m.is_present("limit") {
+                Some(sub_m.value_of_t("limit").unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
+
+            let pairs =
+                KvsClient::connect(addr).and_then(|mut client| match sub_m.value_of("prefix") {
+                    Some(prefix) => client.scan_prefix(prefix, limit),
+                    None => client.scan(
+                        sub_m.value_of("from").map(Vec::from),
+                        sub_m.value_of("to").map(Vec::from),
+                        limit,
+                    ),
+                });
+            let show = |bytes: &[u8]| {
+                if sub_m.is_present("base64") {
+                    base64::encode(bytes)
+                } else {
+                    String::from_utf8_lossy(bytes).into_owned()
                 }
-                KvsError::ErrOk => {}
-            }
-        } // rm was used
+            };
+            pairs.map(|pairs| {
+                for (key, value) in pairs {
+                    println!("{}\t{}", show(&key), show(&value));
+                }
+            })
+        }
+        Some(("backup", sub_m)) => {
+            let dir = sub_m.value_of("DIR").unwrap();
+            let addr = sub_m.value_of("addr").unwrap();
+
+            KvsClient::connect(addr).and_then(|mut client| client.backup(dir))
+        }
         _ => {
             panic!("unknown err");
         }
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+/// the value of `set`: the argument, or the content of `--file`, decoded with `--base64`
+fn read_value(matches: &ArgMatches) -> kvs::Result<Vec<u8>> {
+    let value = match matches.value_of("file") {
+        Some("-") => {
+            let mut value = Vec::new();
+            io::stdin().read_to_end(&mut value)?;
+            value
+        }
+        Some(path) => fs::read(path)?,
+        None => Vec::from(matches.value_of("VALUE").unwrap()),
+    };
+    if !matches.is_present("base64") {
+        return Ok(value);
     }
+    // tolerate the newline an editor or `base64` leaves at the end of a file
+    let encoded = String::from_utf8_lossy(&value);
+    Ok(base64::decode(encoded.trim_end()).unwrap_or_else(|err| {
+        eprintln!("invalid base64 value: {}", err);
+        exit(1);
+    }))
 }
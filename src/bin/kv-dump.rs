@@ -0,0 +1,165 @@
+extern crate clap;
+
+use clap::{App, Arg};
+use kvs::{now_millis, KvsError, Request, SledStore};
+use kvs::{DumpEntry, DumpFormat, DumpWriter, KvStore, KvStoreOptions, KvsClient, KvsEngine};
+use std::env::current_dir;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+
+/// the pairs asked to a server at once
+const PAGE: usize = 1000;
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("kvs-dump")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("write every live pair of a store, or of a server, as a dump")
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .help(
+                    "the store directory, opened read-write and repaired as a server would \
+                     [default: the current directory]",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("engine")
+                .long("engine")
+                .help("the engine of the store directory")
+                .possible_values(["kvs", "sled"])
+                .default_value("kvs"),
+        )
+        .arg(
+            Arg::new("file-prefix")
+                .long("file-prefix")
+                .help("kvs: prefix of the log file names [default: log]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("addr")
+                .long("addr")
+                .help("dump the store of this server instead of a directory")
+                .conflicts_with_all(&["dir", "file-prefix"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .possible_values(["json", "binary"])
+                .default_value("json"),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .help("the dump file [default: stdout]")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let format = match matches.value_of("format").unwrap() {
+        "binary" => DumpFormat::Binary,
+        _ => DumpFormat::Json,
+    };
+    let output: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(File::create(path).unwrap_or_else(|err| {
+            eprintln!("can not create {}: {}", path, err);
+            exit(1);
+        })),
+        None => Box::new(io::stdout()),
+    };
+
+    let result = DumpWriter::new(BufWriter::new(output), format).and_then(|mut writer| {
+        match matches.value_of("addr") {
+            Some(addr) => dump_server(addr, &mut writer)?,
+            None => {
+                let dir = matches
+                    .value_of("dir")
+                    .map_or_else(|| current_dir().unwrap(), PathBuf::from);
+                match matches.value_of("engine").unwrap() {
+                    "sled" => dump_store(&SledStore::open(dir)?, &mut writer)?,
+                    _ => {
+                        let mut options = KvStoreOptions::new();
+                        if let Some(prefix) = matches.value_of("file-prefix") {
+                            options = options.file_prefix(prefix);
+                        }
+                        dump_store(&KvStore::open_with_options(dir, options)?, &mut writer)?
+                    }
+                }
+            }
+        }
+        eprintln!("{} pairs dumped", writer.count());
+        writer.finish().map(|_| ())
+    });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+/// dump the pairs of a store, nothing else writes to it while it is open
+///
+/// The store is opened read-write, the dump changes it like a server would:
+/// the kvs engine cuts off a torn tail and removes an unfinished compaction
+/// when opening it, the sled engine sweeps its expired pairs.
+fn dump_store<E: KvsEngine, W: Write>(store: &E, writer: &mut DumpWriter<W>) -> kvs::Result<()> {
+    for pair in store.scan(.., None)? {
+        let (key, value) = pair?;
+        let ttl = match store.ttl(key.clone()) {
+            Ok(ttl) => ttl,
+            // expired since the scan
+            Err(KvsError::ErrKeyNotFound) => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write(&DumpEntry {
+            key,
+            value,
+            expires_at: ttl.map(|ttl| now_millis() + ttl.as_millis() as u64),
+        })?;
+    }
+    Ok(())
+}
+
+/// dump the pairs of a server a page at a time, the pairs written meanwhile
+/// may or may not be in the dump
+fn dump_server<W: Write>(addr: &str, writer: &mut DumpWriter<W>) -> kvs::Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let mut from = None;
+    loop {
+        let pairs = client.scan(from, None, Some(PAGE))?;
+        // the next page starts right after the last key of a full one
+        from = match pairs.last() {
+            Some((key, _)) if pairs.len() == PAGE => {
+                let mut next = key.clone();
+                next.push(0);
+                Some(next)
+            }
+            _ => None,
+        };
+        // the ttls of the page, pipelined
+        for (key, _) in &pairs {
+            client.send(Request::TTL { key: key.clone() })?;
+        }
+        for (key, value) in pairs {
+            let ttl = match client.receive()?.into_result() {
+                Ok(response) => response.ttl.map(Duration::from_millis),
+                // removed or expired since the scan
+                Err(KvsError::ErrKeyNotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            writer.write(&DumpEntry {
+                key,
+                value,
+                expires_at: ttl.map(|ttl| now_millis() + ttl.as_millis() as u64),
+            })?;
+        }
+        if from.is_none() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,100 @@
+extern crate clap;
+
+use clap::{App, Arg};
+use kvs::{FsckReport, LogFormat};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("kvs-fsck")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("check the logs of a kvs store directory that is not open, exit with 1 if it is damaged")
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .help("the store directory [default: the current directory]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("file-prefix")
+                .long("file-prefix")
+                .help("prefix of the log file names")
+                .default_value("log"),
+        )
+        .arg(
+            Arg::new("repair")
+                .long("repair")
+                .help("write every record that can be salvaged to a new store in this missing or empty directory")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let dir = matches
+        .value_of("dir")
+        .map_or_else(|| current_dir().unwrap(), PathBuf::from);
+    let prefix = matches.value_of("file-prefix").unwrap();
+    let report = match matches.value_of("repair") {
+        Some(dest) => FsckReport::repair(dir, prefix, dest),
+        None => FsckReport::check(dir, prefix),
+    };
+    let report = report.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(1);
+    });
+
+    for log in &report.logs {
+        let format = match log.format {
+            Some(LogFormat::Json) => "json".to_owned(),
+            Some(LogFormat::Binary(version)) => format!("binary v{}", version),
+            None => "empty".to_owned(),
+        };
+        println!(
+            "{}: {}, {} bytes, {} records, {} batches",
+            log.path.display(),
+            format,
+            log.len,
+            log.records,
+            log.batches
+        );
+        for range in &log.corrupt {
+            println!(
+                "  corrupt from {} to {}: {}",
+                range.start, range.end, range.reason
+            );
+        }
+        if log.dropped > 0 {
+            println!("  {} records of unfinished write batches", log.dropped);
+        }
+        if log.hint == Some(false) {
+            println!("  hint does not match the log");
+        }
+    }
+    for idx in &report.gaps {
+        println!("gap: no log {}", idx);
+    }
+    for (idx, paths) in &report.duplicates {
+        let paths: Vec<_> = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        println!("duplicated log {}: {}", idx, paths.join(", "));
+    }
+    for path in &report.orphaned_hints {
+        println!("orphaned hint: {}", path.display());
+    }
+    for path in &report.unfinished_compactions {
+        println!("unfinished compaction: {}", path.display());
+    }
+    if let Some(dest) = matches.value_of("repair") {
+        println!("{} records salvaged to {}", report.salvaged, dest);
+    }
+    if report.is_clean() {
+        println!("clean");
+    } else {
+        println!("damaged");
+        exit(1);
+    }
+}
@@ -0,0 +1,194 @@
+extern crate clap;
+
+use clap::{App, Arg, ArgMatches};
+use kvs::{CorruptRange, LogEntry, LogFormat, LogInspector, Record};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() {
+    env_logger::init();
+    let dir_args = [
+        Arg::new("dir")
+            .long("dir")
+            .help("the store directory [default: the current directory]")
+            .takes_value(true),
+        Arg::new("file-prefix")
+            .long("file-prefix")
+            .help("prefix of the log file names")
+            .default_value("log"),
+        Arg::new("base64")
+            .long("base64")
+            .help("write the keys and values base64-encoded"),
+    ];
+    let matches = App::new("kvs-inspect")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("print what the logs of a kvs store directory hold")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            App::new("records")
+                .about("print the records of the logs: offset, length, type, key, version, expiry time")
+                .args(dir_args.clone())
+                .arg(
+                    Arg::new("log")
+                        .long("log")
+                        .help("only the log with this index")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .help("only the records of this key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .help("only the records of the keys starting with it")
+                        .conflicts_with("key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("values")
+                        .long("values")
+                        .help("print the values too"),
+                ),
+        )
+        .subcommand(
+            App::new("history")
+                .about("print every record of a key, in the order they were written")
+                .args(dir_args.clone())
+                .arg(Arg::new("KEY").required(true)),
+        )
+        .subcommand(
+            App::new("summary")
+                .about("print the live and stale bytes of every log")
+                .args(dir_args),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        Some(("records", sub_m)) => {
+            let inspector = inspector(sub_m);
+            let logs = match sub_m.value_of("log") {
+                Some(_) => Ok(vec![sub_m.value_of_t("log").unwrap_or_else(|e| e.exit())]),
+                None => inspector.logs(),
+            };
+            let key = sub_m.value_of("key").map(|key| key.as_bytes());
+            let prefix = sub_m.value_of("prefix").unwrap_or("").as_bytes();
+            logs.and_then(|logs| {
+                for file in logs {
+                    let records = inspector.records(file)?;
+                    println!("log {}: {}", file, format_name(records.format()));
+                    for entry in records {
+                        let entry = entry?;
+                        // batch markers have no key, they are only listed unfiltered
+                        let shown = match &entry.record {
+                            Record::Kv(kv) => {
+                                key.map_or(kv.key.starts_with(prefix), |key| kv.key == key)
+                            }
+                            _ => key.is_none() && prefix.is_empty(),
+                        };
+                        if shown {
+                            println!("{}", line(sub_m, &entry, sub_m.is_present("values")));
+                        }
+                    }
+                }
+                Ok(())
+            })
+        }
+        Some(("history", sub_m)) => {
+            let key = sub_m.value_of("KEY").unwrap();
+            inspector(sub_m).history(key.as_bytes()).map(|history| {
+                for entry in &history.entries {
+                    println!("log {}\t{}", entry.file, line(sub_m, entry, true));
+                }
+                report_damage(history.damaged.iter().map(|(file, range)| (*file, range)));
+            })
+        }
+        Some(("summary", sub_m)) => inspector(sub_m).summary().map(|summaries| {
+            println!("log\tformat\tbytes\trecords\tlive records\tlive bytes\tstale bytes");
+            for summary in &summaries {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    summary.file,
+                    format_name(summary.format),
+                    summary.len,
+                    summary.records,
+                    summary.live_records,
+                    summary.live_bytes,
+                    summary.stale_bytes
+                );
+            }
+            report_damage(summaries.iter().filter_map(|summary| {
+                let range = summary.damaged.as_ref()?;
+                Some((summary.file, range))
+            }));
+        }),
+        _ => unreachable!(),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn inspector(matches: &ArgMatches) -> LogInspector {
+    let dir = matches
+        .value_of("dir")
+        .map_or_else(|| current_dir().unwrap(), PathBuf::from);
+    LogInspector::new(dir, matches.value_of("file-prefix").unwrap())
+}
+
+/// tell where the logs stopped being read, and fail if any did
+fn report_damage<'a>(damaged: impl Iterator<Item = (u64, &'a CorruptRange)>) {
+    let mut failed = false;
+    for (file, range) in damaged {
+        eprintln!(
+            "log {} is damaged at offset {}, the rest of it was not read: {}",
+            file, range.start, range.reason
+        );
+        failed = true;
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn format_name(format: Option<LogFormat>) -> String {
+    match format {
+        Some(LogFormat::Json) => "json".to_owned(),
+        Some(LogFormat::Binary(version)) => format!("binary v{}", version),
+        None => "empty".to_owned(),
+    }
+}
+
+/// `offset<TAB>len<TAB>type` followed by the key, the version and the
+/// expiry time of a put, and its value if asked for
+fn line(matches: &ArgMatches, entry: &LogEntry, with_value: bool) -> String {
+    let show = |bytes: &[u8]| {
+        if matches.is_present("base64") {
+            base64::encode(bytes)
+        } else {
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+    };
+    let record = match &entry.record {
+        Record::Kv(kv) if kv.version == 0 => format!("del\t{}", show(&kv.key)),
+        Record::Kv(kv) => {
+            let mut record = format!("put\t{}\tv{}", show(&kv.key), kv.version);
+            if let Some(expires_at) = kv.expires_at {
+                record += &format!("\texpires at {}", expires_at);
+            }
+            if with_value {
+                record += &format!("\t{}", show(&kv.value));
+            }
+            record
+        }
+        Record::BatchBegin(count) => format!("batch\t{} records", count),
+        Record::BatchCommit => "commit".to_owned(),
+    };
+    format!("{}\t{}\t{}", entry.offset, entry.len, record)
+}
@@ -0,0 +1,180 @@
+extern crate clap;
+
+use clap::{App, Arg};
+use kvs::{now_millis, WriteBatch};
+use kvs::{DumpReader, KvStore, KvStoreOptions, KvsClient, KvsEngine, Request, SledStore};
+use std::cell::RefCell;
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+
+/// the pairs written in one batch
+const BATCH: usize = 1000;
+/// the requests sent to a server before their responses are read
+const IN_FLIGHT: usize = 64;
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("kvs-restore")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("load a dump of kvs-dump into an empty store, or server")
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .help("the store directory, created if missing [default: the current directory]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("engine")
+                .long("engine")
+                .help("the engine of the store directory")
+                .possible_values(["kvs", "sled"])
+                .default_value("kvs"),
+        )
+        .arg(
+            Arg::new("file-prefix")
+                .long("file-prefix")
+                .help("kvs: prefix of the log file names [default: log]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("addr")
+                .long("addr")
+                .help("load the dump into the store of this server instead of a directory")
+                .conflicts_with_all(&["dir", "file-prefix"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("input")
+                .long("input")
+                .help("the dump file, in either format [default: stdin]")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let input: Box<dyn BufRead> = match matches.value_of("input") {
+        Some(path) => Box::new(BufReader::new(File::open(path).unwrap_or_else(|err| {
+            eprintln!("can not open {}: {}", path, err);
+            exit(1);
+        }))),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let result = DumpReader::new(input).and_then(|dump| match matches.value_of("addr") {
+        Some(addr) => restore_server(addr, dump),
+        None => {
+            let dir = matches
+                .value_of("dir")
+                .map_or_else(|| current_dir().unwrap(), PathBuf::from);
+            fs::create_dir_all(&dir)?;
+            match matches.value_of("engine").unwrap() {
+                "sled" => restore_store(&SledStore::open(dir)?, dump),
+                _ => {
+                    let mut options = KvStoreOptions::new();
+                    if let Some(prefix) = matches.value_of("file-prefix") {
+                        options = options.file_prefix(prefix);
+                    }
+                    restore_store(&KvStore::open_with_options(dir, options)?, dump)
+                }
+            }
+        }
+    });
+    match result {
+        Ok(count) => eprintln!("{} pairs restored", count),
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    }
+}
+
+/// load the dump into an empty store
+fn restore_store<E: KvsEngine, R: BufRead>(store: &E, dump: DumpReader<R>) -> kvs::Result<u64> {
+    if store.scan(.., Some(1))?.next().is_some() {
+        return Err(not_empty());
+    }
+    load(
+        dump,
+        |batch| store.write_batch(batch),
+        |key, value, ttl| store.set_with_ttl(key, value, ttl),
+    )
+}
+
+/// load the dump into the empty store of a server, the writes are pipelined
+fn restore_server<R: BufRead>(addr: &str, dump: DumpReader<R>) -> kvs::Result<u64> {
+    let client = RefCell::new(KvsClient::connect(addr)?);
+    if !client.borrow_mut().scan(None, None, Some(1))?.is_empty() {
+        return Err(not_empty());
+    }
+    let send = |request| {
+        let mut client = client.borrow_mut();
+        if client.in_flight() == IN_FLIGHT {
+            client.receive()?.into_result()?;
+        }
+        client.send(request).map(|_| ())
+    };
+    let count = load(
+        dump,
+        |batch| send(Request::BATCH { batch }),
+        |key, value, ttl| {
+            send(Request::SET {
+                key,
+                value,
+                ttl: Some(ttl.as_millis() as u64),
+                condition: None,
+            })
+        },
+    )?;
+    let mut client = client.borrow_mut();
+    while client.in_flight() > 0 {
+        client.receive()?.into_result()?;
+    }
+    Ok(count)
+}
+
+/// write the pairs of the dump in batches, the pairs with an expiry time
+/// one by one with the time they have left, and return how many were written
+fn load<R: BufRead>(
+    dump: DumpReader<R>,
+    mut write_batch: impl FnMut(WriteBatch) -> kvs::Result<()>,
+    mut set_with_ttl: impl FnMut(Vec<u8>, Vec<u8>, Duration) -> kvs::Result<()>,
+) -> kvs::Result<u64> {
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for entry in dump {
+        let entry = entry?;
+        match entry.expires_at {
+            None => {
+                batch.set(entry.key, entry.value);
+                if batch.len() == BATCH {
+                    write_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            Some(expires_at) => {
+                // expired since the dump was taken
+                let now = now_millis();
+                if expires_at <= now {
+                    continue;
+                }
+                let ttl = Duration::from_millis(expires_at - now);
+                set_with_ttl(entry.key, entry.value, ttl)?;
+            }
+        }
+        count += 1;
+    }
+    if !batch.is_empty() {
+        write_batch(batch)?;
+    }
+    Ok(count)
+}
+
+fn not_empty() -> kvs::KvsError {
+    kvs::KvsError::ErrIo(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "the store is not empty, a dump is only restored into an empty store",
+    ))
+}
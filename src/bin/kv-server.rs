@@ -2,16 +2,60 @@ extern crate clap;
 extern crate failure_derive;
 extern crate num_cpus;
 
-use clap::{App, Arg};
-use kvs::{thread_pool::*, KvServer, KvStore, SledStore};
+use clap::{App, Arg, ArgMatches};
+use kvs::{
+    thread_pool::*, CompactionTrigger, EngineOptions, KvServer, KvStore, KvStoreOptions, KvsEngine,
+    RespServer, SledStore, SyncPolicy,
+};
 #[allow(unused)]
 use log::{debug, error, info, warn, LevelFilter};
+use serde::Deserialize;
 use std::env;
 use std::env::current_dir;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
 use std::sync::{
     mpsc,
     mpsc::{Receiver, Sender},
 };
+use std::thread;
+use std::time::Duration;
+
+/// the settings of a `--config` file, flags given on the command line win
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    addr: Option<String>,
+    engine: Option<String>,
+    sync: Option<String>,
+    sync_interval_ms: Option<u64>,
+    sync_bytes: Option<u64>,
+    max_file_size: Option<u64>,
+    compaction_stale_records: Option<u64>,
+    compaction_stale_ratio: Option<f64>,
+    compaction_min_stale_bytes: Option<u64>,
+    file_prefix: Option<String>,
+    read_buffer_size: Option<usize>,
+    idle_timeout_ms: Option<u64>,
+    threads: Option<u32>,
+    backup_dir: Option<String>,
+    resp_addr: Option<String>,
+    http_addr: Option<String>,
+}
+
+/// the value of a flag, or else of the config file
+fn setting<T>(matches: &ArgMatches, name: &str, config: Option<T>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if matches.is_present(name) {
+        Some(matches.value_of_t(name).unwrap_or_else(|e| e.exit()))
+    } else {
+        config
+    }
+}
 
 fn main() {
     env_logger::Builder::new()
@@ -23,42 +67,274 @@ fn main() {
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .help("JSON file with the settings below, by their flag names")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("addr")
                 .long("addr")
-                .takes_value(true)
-                .default_value("127.0.0.1:4000"),
+                .help("[default: 127.0.0.1:4000]")
+                .takes_value(true),
         )
         .arg(
             Arg::new("engine")
                 .long("engine")
-                .possible_values(&["kvs", "sled"])
-                .takes_value(true)
-                .default_value("kvs"),
+                .help("[default: kvs]")
+                .possible_values(["kvs", "sled"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sync")
+                .long("sync")
+                .help("when writes are synced to the disk [default: never with kvs, always with sled]")
+                .possible_values(["never", "always", "periodic"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sync-interval-ms")
+                .long("sync-interval-ms")
+                .help("max milliseconds between two syncs with --sync periodic [default: 1000]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sync-bytes")
+                .long("sync-bytes")
+                .help("max bytes written between two syncs with --sync periodic [default: 1048576]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .help("kvs: bytes after which a new log is started [default: 67108864]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("compaction-stale-records")
+                .long("compaction-stale-records")
+                .help("kvs: compact once this many records are overwritten or removed")
+                .conflicts_with("compaction-stale-ratio")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("compaction-stale-ratio")
+                .long("compaction-stale-ratio")
+                .help("kvs: compact once this share of the logs is stale [default: 0.5]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("compaction-min-stale-bytes")
+                .long("compaction-min-stale-bytes")
+                .help("kvs: stale bytes needed by --compaction-stale-ratio [default: 1048576]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("file-prefix")
+                .long("file-prefix")
+                .help("kvs: prefix of the log file names [default: log]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("read-buffer-size")
+                .long("read-buffer-size")
+                .help("kvs: buffer size used to read the logs [default: 8192]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("resp-addr")
+                .long("resp-addr")
+                .help("also serve the Redis protocol (RESP) at this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("http-addr")
+                .long("http-addr")
+                .help("also serve the HTTP API at this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("idle-timeout-ms")
+                .long("idle-timeout-ms")
+                .help("milliseconds after which a connection without requests is closed [default: 60000]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("backup-dir")
+                .long("backup-dir")
+                .help("directory the backups asked by the clients are written in [default: backups refused]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .help("threads running the requests, of any number of connections [default: the CPUs]")
+                .takes_value(true),
         )
         .arg(Arg::new("version").short('V'))
         .get_matches();
 
-    let addr = matches.value_of("addr").unwrap();
-    let engine = matches.value_of("engine").unwrap();
+    let config: Config = match matches.value_of("config") {
+        Some(path) => {
+            let data = fs::read(path).unwrap_or_else(|err| {
+                error!("can not read {}: {}", path, err);
+                std::process::exit(1);
+            });
+            serde_json::from_slice(&data).unwrap_or_else(|err| {
+                error!("invalid config {}: {}", path, err);
+                std::process::exit(1);
+            })
+        }
+        None => Config::default(),
+    };
+
+    let addr =
+        setting(&matches, "addr", config.addr).unwrap_or_else(|| "127.0.0.1:4000".to_owned());
+    let engine = setting(&matches, "engine", config.engine).unwrap_or_else(|| "kvs".to_owned());
     info!("Addr: {}, Engine: {}", addr, engine);
 
-    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32).unwrap();
-    let (_, server_stop_rx): (Sender<i32>, Receiver<i32>) = mpsc::channel();
+    // each engine keeps the durability it had before the policy was
+    // configurable: kvs never synced its writes, sled flushed every one
+    let sync = setting(&matches, "sync", config.sync).unwrap_or_else(|| {
+        let default = if engine == "sled" { "always" } else { "never" };
+        default.to_owned()
+    });
+    let sync_policy = match sync.as_str() {
+        "never" => SyncPolicy::Never,
+        "always" => SyncPolicy::Always,
+        "periodic" => SyncPolicy::Periodic {
+            interval: Duration::from_millis(
+                setting(&matches, "sync-interval-ms", config.sync_interval_ms).unwrap_or(1000),
+            ),
+            bytes: setting(&matches, "sync-bytes", config.sync_bytes).unwrap_or(1 << 20),
+        },
+        _ => {
+            error!("unknown sync policy {}", sync);
+            std::process::exit(1);
+        }
+    };
+    info!("Sync policy: {:?}", sync_policy);
+    let options = EngineOptions { sync_policy };
+
+    let mut store_options = KvStoreOptions::from(options);
+    if let Some(bytes) = setting(&matches, "max-file-size", config.max_file_size) {
+        store_options = store_options.max_file_size(bytes);
+    }
+    // the stale ratio given as a flag wins over a record count from the config
+    let stale_records = if matches.is_present("compaction-stale-ratio") {
+        None
+    } else {
+        setting(
+            &matches,
+            "compaction-stale-records",
+            config.compaction_stale_records,
+        )
+    };
+    let stale_ratio = setting(
+        &matches,
+        "compaction-stale-ratio",
+        config.compaction_stale_ratio,
+    );
+    let min_stale_bytes = setting(
+        &matches,
+        "compaction-min-stale-bytes",
+        config.compaction_min_stale_bytes,
+    );
+    if let Some(records) = stale_records {
+        store_options = store_options.compaction_trigger(CompactionTrigger::StaleRecords(records));
+    } else if stale_ratio.is_some() || min_stale_bytes.is_some() {
+        store_options = store_options.compaction_trigger(CompactionTrigger::StaleRatio {
+            ratio: stale_ratio.unwrap_or(0.5),
+            min_bytes: min_stale_bytes.unwrap_or(1 << 20),
+        });
+    }
+    if let Some(prefix) = setting(&matches, "file-prefix", config.file_prefix) {
+        store_options = store_options.file_prefix(prefix);
+    }
+    if let Some(bytes) = setting(&matches, "read-buffer-size", config.read_buffer_size) {
+        store_options = store_options.read_buffer_size(bytes);
+    }
+
+    let idle_timeout = Duration::from_millis(
+        setting(&matches, "idle-timeout-ms", config.idle_timeout_ms).unwrap_or(60_000),
+    );
+    let threads = setting(&matches, "threads", config.threads).unwrap_or(num_cpus::get() as u32);
+    if threads == 0 {
+        error!("a thread at least is needed to run the requests");
+        std::process::exit(1);
+    }
+    info!("Threads: {}", threads);
+    let backup_dir = setting(&matches, "backup-dir", config.backup_dir);
+    if let Some(backup_dir) = &backup_dir {
+        info!("Backup dir: {}", backup_dir);
+    }
+    let resp_addr = setting(&matches, "resp-addr", config.resp_addr);
+    if let Some(resp_addr) = &resp_addr {
+        info!("RESP Addr: {}", resp_addr);
+    }
+    let http_addr = setting(&matches, "http-addr", config.http_addr);
+    if let Some(http_addr) = &http_addr {
+        info!("HTTP Addr: {}", http_addr);
+    }
+    let serving = Serving {
+        addr,
+        resp_addr,
+        http_addr,
+        idle_timeout,
+        threads,
+        backup_dir,
+    };
 
-    match engine {
+    let dir = current_dir().unwrap();
+    let result = match engine.as_str() {
         "kvs" => {
-            let store = KvStore::open(current_dir().unwrap()).unwrap();
-            let server = KvServer::new(store, pool, addr, server_stop_rx);
-            server.start();
-        }
-        "sled" => {
-            let store = SledStore::open(current_dir().unwrap()).unwrap();
-            let server = KvServer::new(store, pool, addr, server_stop_rx);
-            server.start();
+            KvStore::open_with_options(dir, store_options).and_then(|store| serve(store, &serving))
         }
+        "sled" => SledStore::open_with(dir, options).and_then(|store| serve(store, &serving)),
         _ => {
-            panic!("{} engine is not satisfied.", engine)
+            error!("{} engine is not satisfied.", engine);
+            std::process::exit(1);
         }
+    };
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+/// the settings of the servers, whatever the engine
+struct Serving {
+    addr: String,
+    resp_addr: Option<String>,
+    http_addr: Option<String>,
+    idle_timeout: Duration,
+    threads: u32,
+    backup_dir: Option<String>,
+}
+
+fn serve<E: KvsEngine>(store: E, serving: &Serving) -> kvs::Result<()> {
+    if let Some(resp_addr) = &serving.resp_addr {
+        let pool = SharedQueueThreadPool::new(serving.threads)?;
+        let (_, server_stop_rx): (Sender<i32>, Receiver<i32>) = mpsc::channel();
+        let server = RespServer::new(store.clone(), pool, resp_addr, server_stop_rx)?
+            .idle_timeout(serving.idle_timeout);
+        thread::spawn(move || {
+            if let Err(err) = server.start() {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        });
+    }
+    let pool = SharedQueueThreadPool::new(serving.threads)?;
+    let (_, server_stop_rx): (Sender<i32>, Receiver<i32>) = mpsc::channel();
+    let mut server = KvServer::new(store, pool, &serving.addr, server_stop_rx)?
+        .idle_timeout(serving.idle_timeout);
+    if let Some(http_addr) = &serving.http_addr {
+        server = server.http_addr(http_addr)?;
+    }
+    if let Some(backup_dir) = &serving.backup_dir {
+        server = server.backup_dir(backup_dir);
     }
+    server.start()
 }
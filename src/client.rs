@@ -1,19 +1,27 @@
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 
-use log::error;
-
-use crate::io::read_n;
-use crate::{Request, Response};
-use std::io::{Read, Write};
-use std::process::exit;
+use crate::proto::{read_message, write_message};
+use crate::{Condition, KvsError, Request, RequestFrame, Response, Result, WriteBatch};
+use crate::{Handshake, HandshakeReply, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
 /// kvsclient
-/// it can send network request to the kv server
+/// a connection to the kv server, which serves every request sent on it.
+/// It reconnects if the server closed the connection while it was idle.
+///
+/// Each connection starts with a handshake, which agrees on the protocol
+/// version and the features used. A server which speaks none of the
+/// versions of the client refuses the connection with `ErrProtocol`.
+///
+/// `send` and `receive` pipeline requests: many are sent before their
+/// responses are read, which come back in the same order.
 ///
 /// ```
 /// use std::net::TcpStream;
 /// use std::sync::mpsc::{self, Receiver, Sender};
-/// use kvs::{KvServer, KvStore, KvsClient, KvsError, thread_pool::*, SledStore};
+/// use kvs::{KvServer, KvStore, KvsClient, KvsError, Request, thread_pool::*, SledStore, WriteBatch};
 /// use tempfile::TempDir;
 ///
 /// const SERVER_SOCKET_ADDR: &str = "127.0.0.1:4000";
@@ -25,84 +33,459 @@ use std::process::exit;
 /// // server stop signal
 /// let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
 ///     mpsc::channel();
-/// let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+/// let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
 /// let handle = std::thread::spawn(move || {
-///     server.start();
+///     server.start().unwrap();
 /// });
 ///
 /// // client usage
-/// KvsClient::set("key".to_owned(), "value".to_owned(), "127.0.0.1:4000");
-/// let response = KvsClient::get("key".to_owned(), "127.0.0.1:4000");
-/// match response.status {
-///     KvsError::ErrKeyNotFound => {
-///         panic!("key no found");
-///     }
-///     KvsError::ErrOk => {
-///         assert_eq!("value", response.value);
-///     }
+/// let mut client = KvsClient::connect(SERVER_SOCKET_ADDR).unwrap();
+/// assert_eq!(client.protocol_version(), kvs::PROTOCOL_VERSION);
+/// assert!(client.has_feature("transactions"));
+/// client.set("key", &b"\x00binary\xff"[..]).unwrap();
+/// let value = client.get("key").unwrap();
+/// assert_eq!(value, Some(b"\x00binary\xff".to_vec()));
+/// assert!(matches!(
+///     client.remove("missing"),
+///     Err(KvsError::ErrKeyNotFound)
+/// ));
+/// // several writes in one request
+/// let mut batch = WriteBatch::new();
+/// batch.set("key2", "value2").remove("key");
+/// client.write_batch(batch).unwrap();
+/// assert_eq!(client.get("key").unwrap(), None);
+/// // a transaction fails if what it read was written before it commits
+/// let mut tx = client.begin_transaction().unwrap();
+/// assert_eq!(tx.get("key2").unwrap(), Some(b"value2".to_vec()));
+/// tx.set("key3", "value3").unwrap();
+/// client.set("key2", "value3").unwrap();
+/// assert!(matches!(tx.commit(), Err(KvsError::ErrConflict)));
+/// let mut tx = client.begin_transaction().unwrap();
+/// tx.remove("key2").unwrap();
+/// tx.commit().unwrap();
+/// assert_eq!(client.get("key2").unwrap(), None);
+/// // requests sent before their responses are read
+/// for i in 0..10 {
+///     client.send(Request::SET {
+///         key: format!("key{}", i).into(),
+///         value: b"value".to_vec(),
+///         ttl: None,
+///         condition: None,
+///     }).unwrap();
 /// }
+/// let id = client.send(Request::GET { key: b"key9".to_vec() }).unwrap();
+/// assert_eq!(client.in_flight(), 11);
+/// for _ in 0..10 {
+///     client.receive().unwrap().into_result().unwrap();
+/// }
+/// let response = client.receive().unwrap();
+/// assert_eq!((response.id, response.value), (id, b"value".to_vec()));
+/// drop(client);
 ///
 /// server_stop_tx.send(0).unwrap();
 /// TcpStream::connect(SERVER_SOCKET_ADDR).unwrap();
 /// handle.join().unwrap();
 /// ```
-pub struct KvsClient {}
+pub struct KvsClient {
+    addr: String,
+    connection: Option<Connection>, // `None` once it failed
+    next_id: u64,
+    in_flight: VecDeque<u64>, // the ids of the requests sent, not yet answered
+    version: u32,             // agreed on by the last handshake
+    features: Vec<String>,    // agreed on by the last handshake
+}
+
+/// the two ends of a connection, buffered
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
 
 impl KvsClient {
+    /// connect to the server at `addr`
+    pub fn connect(addr: &str) -> Result<KvsClient> {
+        let mut client = KvsClient {
+            addr: addr.to_owned(),
+            connection: None,
+            next_id: 1,
+            in_flight: VecDeque::new(),
+            version: 0,
+            features: Vec::new(),
+        };
+        client.reconnect()?;
+        Ok(client)
+    }
+
+    /// the protocol version spoken with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// whether the server has `feature`, one of `FEATURES`
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|has| has == feature)
+    }
+
+    /// send `request` without waiting for its response, return its id.
+    ///
+    /// The requests are buffered until `receive` is called. The server stops
+    /// reading the connection while its responses are not read, `receive`
+    /// must be called before the responses in flight outgrow the buffers of
+    /// the connection.
+    pub fn send(&mut self, request: Request) -> Result<u64> {
+        if self.in_flight.is_empty() && !self.connection.as_ref().is_some_and(is_open) {
+            self.reconnect()?;
+        }
+        let id = self.next_id;
+        let frame = RequestFrame { id, request };
+        let connection = self.connection.as_mut().expect("connected");
+        if let Err(err) = write_message(&mut connection.writer, &frame, &self.addr) {
+            self.fail();
+            return Err(err);
+        }
+        self.next_id += 1;
+        self.in_flight.push_back(id);
+        Ok(id)
+    }
+
+    /// the response to the oldest request sent and not answered yet, an
+    /// error status is a response like any other, see `Response::into_result`
+    pub fn receive(&mut self) -> Result<Response> {
+        let id = match self.in_flight.front() {
+            Some(id) => *id,
+            None => return Err(KvsError::ErrProtocol("no request in flight".to_owned())),
+        };
+        let connection = self.connection.as_mut().expect("connected");
+        let addr = &self.addr;
+        let response = connection
+            .writer
+            .flush()
+            .map_err(|cause| KvsError::ErrNetwork {
+                addr: addr.to_owned(),
+                cause,
+            })
+            .and_then(|_| read_message::<_, Response>(&mut connection.reader, addr));
+        match response {
+            Ok(response) if response.id == id => {
+                self.in_flight.pop_front();
+                Ok(response)
+            }
+            Ok(response) => {
+                self.fail();
+                Err(KvsError::ErrProtocol(format!(
+                    "response to request {} while waiting for {}",
+                    response.id, id
+                )))
+            }
+            Err(err) => {
+                self.fail();
+                Err(err)
+            }
+        }
+    }
+
+    /// the number of requests sent and not answered yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
     /// set
-    pub fn set(key: String, value: String, addr: &str) -> Response {
-        let mut stream = TcpStream::connect(&addr).unwrap_or_else(|err| {
-            error!("Error happened when connect {}, error: {}", addr, &err);
-            exit(1);
-        });
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::SET {
+            key: key.into(),
+            value: value.into(),
+            ttl: None,
+            condition: None,
+        };
+        let response = self.call(request)?;
+        expect_ok(response).map(|_| ())
+    }
+
+    /// set, the key expires once `ttl` has passed
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.require("ttl")?;
+        let request = Request::SET {
+            key: key.into(),
+            value: value.into(),
+            ttl: Some(ttl.as_millis() as u64),
+            condition: None,
+        };
+        let response = self.call(request)?;
+        expect_ok(response).map(|_| ())
+    }
 
+    /// set if `condition` holds, `ErrConditionFailed` otherwise,
+    /// the key expires once `ttl` has passed if given
+    pub fn set_if(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.require("conditional")?;
+        if ttl.is_some() {
+            self.require("ttl")?;
+        }
         let request = Request::SET {
-            key: key,
-            value: value,
+            key: key.into(),
+            value: value.into(),
+            ttl: ttl.map(|ttl| ttl.as_millis() as u64),
+            condition: Some(condition),
         };
+        let response = self.call(request)?;
+        expect_ok(response).map(|_| ())
+    }
 
-        let response = hand_rpc(request, &mut stream);
-        return response;
+    /// get, `None` if the key does not exist
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
     }
 
-    /// get
-    pub fn get(key: String, addr: &str) -> Response {
-        let mut stream = TcpStream::connect(&addr).unwrap_or_else(|err| {
-            error!("Error happened when connect {}, error: {}", addr, &err);
-            exit(1);
-        });
+    /// get with the version of the value, `None` if the key does not exist
+    pub fn get_versioned(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u32)>> {
+        let response = self.call(Request::GET { key: key.into() })?;
+        let version = response.version;
+        match expect_ok(response) {
+            Ok(value) => Ok(Some((value, version))),
+            Err(KvsError::ErrKeyNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-        let request = Request::GET { key: key };
+    /// rm, `ErrKeyNotFound` if the key does not exist
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let response = self.call(Request::RM { key: key.into() })?;
+        expect_ok(response).map(|_| ())
+    }
 
-        let response = hand_rpc(request, &mut stream);
-        return response;
+    /// apply all the writes of `batch` or none of them
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.require("batch")?;
+        let response = self.call(Request::BATCH { batch })?;
+        expect_ok(response).map(|_| ())
     }
 
-    /// rm
-    pub fn remove(key: String, addr: &str) -> Response {
-        let mut stream = TcpStream::connect(&addr).unwrap_or_else(|err| {
-            error!("Error happened when connect {}, error: {}", addr, &err);
-            exit(1);
-        });
+    /// start a transaction on the server, on a connection of its own. It is
+    /// rolled back by the server when it is dropped, or if it is not used for
+    /// a minute.
+    pub fn begin_transaction(&self) -> Result<RemoteTransaction> {
+        let mut client = KvsClient::connect(&self.addr)?;
+        client.require("transactions")?;
+        let response = client.call(Request::BEGIN)?;
+        let id = response.txn;
+        expect_ok(response)?;
+        Ok(RemoteTransaction { id, client })
+    }
 
-        let request = Request::RM { key: key };
+    /// have the server write a consistent copy of its store to `dest`, a
+    /// missing or empty directory relative to the backup directory of the
+    /// server, see `KvServer::backup_dir`
+    pub fn backup(&mut self, dest: &str) -> Result<()> {
+        self.require("backup")?;
+        let request = Request::BACKUP {
+            path: dest.to_owned(),
+        };
+        let response = self.call(request)?;
+        expect_ok(response).map(|_| ())
+    }
+
+    /// the time left before the key expires, `None` if it never does,
+    /// `ErrKeyNotFound` if the key does not exist
+    pub fn ttl(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        self.require("ttl")?;
+        let response = self.call(Request::TTL { key: key.into() })?;
+        let ttl = response.ttl;
+        expect_ok(response).map(|_| ttl.map(Duration::from_millis))
+    }
 
-        let response = hand_rpc(request, &mut stream);
-        return response;
+    /// make the key never expire, `ErrKeyNotFound` if the key does not exist
+    pub fn persist(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.require("ttl")?;
+        let response = self.call(Request::PERSIST { key: key.into() })?;
+        expect_ok(response).map(|_| ())
+    }
+
+    /// scan the pairs from `from` included to `to` excluded, in key order
+    pub fn scan(
+        &mut self,
+        from: Option<Vec<u8>>,
+        to: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.require("scan")?;
+        let response = self.call(Request::SCAN { from, to, limit })?;
+        expect_pairs(response)
+    }
+
+    /// scan the pairs with a key starting with `prefix`, in key order
+    pub fn scan_prefix(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.require("scan")?;
+        let request = Request::PREFIX {
+            prefix: prefix.into(),
+            limit,
+        };
+        let response = self.call(request)?;
+        expect_pairs(response)
+    }
+
+    /// send `request` and read its response, the requests in flight must
+    /// have been answered
+    fn call(&mut self, request: Request) -> Result<Response> {
+        if !self.in_flight.is_empty() {
+            return Err(KvsError::ErrProtocol(format!(
+                "{} requests in flight",
+                self.in_flight.len()
+            )));
+        }
+        self.send(request)?;
+        self.receive()
+    }
+
+    /// open a new connection and shake hands on it
+    fn reconnect(&mut self) -> Result<()> {
+        let mut connection = open(&self.addr)?;
+        let handshake = Handshake::new(
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION,
+            FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        );
+        let network = |cause| KvsError::ErrNetwork {
+            addr: self.addr.clone(),
+            cause,
+        };
+        write_message(&mut connection.writer, &handshake, &self.addr)?;
+        connection.writer.flush().map_err(network)?;
+        match read_message(&mut connection.reader, &self.addr)? {
+            HandshakeReply::Accepted { version, features } => {
+                self.version = version;
+                self.features = features;
+                self.connection = Some(connection);
+                Ok(())
+            }
+            HandshakeReply::Rejected { reason } => Err(KvsError::ErrProtocol(format!(
+                "{} refused the connection: {}",
+                self.addr, reason
+            ))),
+        }
+    }
+
+    /// `ErrProtocol` unless the server has `feature`
+    fn require(&self, feature: &str) -> Result<()> {
+        if self.has_feature(feature) {
+            Ok(())
+        } else {
+            Err(KvsError::ErrProtocol(format!(
+                "{} does not support {}",
+                self.addr, feature
+            )))
+        }
+    }
+
+    /// a connection which failed in the middle of a request is not reused,
+    /// the requests in flight on it are lost
+    fn fail(&mut self) {
+        self.connection = None;
+        self.in_flight.clear();
+    }
+}
+
+/// A transaction on a server, see `kvs::Transaction`
+pub struct RemoteTransaction {
+    id: u64,
+    client: KvsClient,
+}
+
+impl RemoteTransaction {
+    /// get kv pair
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let request = Request::TGET {
+            txn: self.id,
+            key: key.into(),
+        };
+        match expect_ok(self.client.call(request)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(KvsError::ErrKeyNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// set kv pair once the transaction commits
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::TSET {
+            txn: self.id,
+            key: key.into(),
+            value: value.into(),
+        };
+        expect_ok(self.client.call(request)?).map(|_| ())
+    }
+
+    /// remove kv pair once the transaction commits, `ErrKeyNotFound` if the
+    /// key does not exist
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::TRM {
+            txn: self.id,
+            key: key.into(),
+        };
+        expect_ok(self.client.call(request)?).map(|_| ())
+    }
+
+    /// apply the writes, `ErrConflict` if a key read was written since the
+    /// transaction started
+    pub fn commit(mut self) -> Result<()> {
+        let request = Request::COMMIT { txn: self.id };
+        expect_ok(self.client.call(request)?).map(|_| ())
+    }
+
+    /// drop the transaction and its writes
+    pub fn rollback(mut self) -> Result<()> {
+        let request = Request::ROLLBACK { txn: self.id };
+        expect_ok(self.client.call(request)?).map(|_| ())
     }
 }
 
-fn hand_rpc(request: Request, stream: &mut TcpStream) -> Response {
-    let request = serde_json::to_string(&request).unwrap();
-    let request_len = request.len() as u32;
-    stream.write(&request_len.to_be_bytes()).unwrap();
-    stream.write(request.as_bytes()).unwrap();
-    stream.flush().unwrap();
-
-    let mut buffer = [0; 4]; // request len
-    stream.read(&mut buffer).unwrap();
-    let request_len = u32::from_be_bytes(buffer);
-    let data = read_n(stream, request_len as u64);
-    let response: Response = serde_json::from_slice(&data).unwrap();
-    return response;
+/// the value of a successful response, or the error it reports
+fn expect_ok(response: Response) -> Result<Vec<u8>> {
+    response.into_result().map(|response| response.value)
+}
+
+/// the pairs of a successful scan
+fn expect_pairs(mut response: Response) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let pairs = std::mem::take(&mut response.pairs);
+    expect_ok(response).map(|_| pairs)
+}
+
+fn open(addr: &str) -> Result<Connection> {
+    let network = |cause| KvsError::ErrNetwork {
+        addr: addr.to_owned(),
+        cause,
+    };
+    let stream = TcpStream::connect(addr).map_err(network)?;
+    Ok(Connection {
+        reader: BufReader::new(stream.try_clone().map_err(network)?),
+        writer: BufWriter::new(stream),
+    })
+}
+
+/// whether the server has not closed the connection, it has nothing to
+/// read between two requests
+fn is_open(connection: &Connection) -> bool {
+    let stream = connection.reader.get_ref();
+    if !connection.reader.buffer().is_empty() || stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(
+        stream.peek(&mut [0]),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock
+    );
+    open && stream.set_nonblocking(false).is_ok()
 }
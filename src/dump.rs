@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+
+use crate::io::read_n;
+use crate::{KvsError, Result};
+
+/// magic bytes at the beginning of a binary dump
+const DUMP_MAGIC: [u8; 4] = *b"KVSD";
+/// current format version of binary dumps
+const DUMP_FORMAT_VERSION: u32 = 1;
+/// the tag of a pair in a binary dump
+const DUMP_PAIR: u8 = 1;
+/// the tag of the end of a binary dump
+const DUMP_END: u8 = 0;
+
+/// The encoding of a dump written by `DumpWriter`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// one JSON object per line, keys and values base64-encoded:
+    /// `{"key":"a2V5","value":"dmFsdWU=","expires_at":null}`
+    Json,
+    /// length-prefixed pairs after a header, ending with the number of pairs
+    /// so a truncated dump is detected:
+    ///
+    /// ```text
+    /// | magic | version: u32 | pairs... | 0: u8 | pair count: u64 |
+    /// pair: | 1: u8 | key len: u32 | key | value len: u32 | value | expires at: u64 |
+    /// ```
+    ///
+    /// where an expiry time of 0 means the pair never expires.
+    Binary,
+}
+
+/// A pair of a dump, whichever engine it came from
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpEntry {
+    /// key
+    pub key: Vec<u8>,
+    /// value
+    pub value: Vec<u8>,
+    /// when the pair expires, in milliseconds since the unix epoch,
+    /// `None` if it never does
+    pub expires_at: Option<u64>,
+}
+
+/// a line of a JSON dump
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEntry {
+    key: String,
+    value: String,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+/// Writes the pairs of a store as a dump
+///
+/// ```
+/// use kvs::{DumpEntry, DumpFormat, DumpReader, DumpWriter};
+///
+/// let mut writer = DumpWriter::new(Vec::new(), DumpFormat::Binary).unwrap();
+/// let entry = DumpEntry {
+///     key: b"key1".to_vec(),
+///     value: b"\x00value1".to_vec(),
+///     expires_at: None,
+/// };
+/// writer.write(&entry).unwrap();
+/// let dump = writer.finish().unwrap();
+///
+/// // the format is told by the dump itself
+/// let entries = DumpReader::new(&dump[..]).unwrap();
+/// assert_eq!(entries.collect::<kvs::Result<Vec<_>>>().unwrap(), vec![entry]);
+/// ```
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    format: DumpFormat,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// start a dump in `format`
+    pub fn new(mut writer: W, format: DumpFormat) -> Result<DumpWriter<W>> {
+        if format == DumpFormat::Binary {
+            writer.write_all(&DUMP_MAGIC)?;
+            writer.write_all(&DUMP_FORMAT_VERSION.to_be_bytes())?;
+        }
+        Ok(DumpWriter {
+            writer,
+            format,
+            count: 0,
+        })
+    }
+
+    /// append a pair
+    pub fn write(&mut self, entry: &DumpEntry) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                let line = JsonEntry {
+                    key: base64::encode(&entry.key),
+                    value: base64::encode(&entry.value),
+                    expires_at: entry.expires_at,
+                };
+                serde_json::to_writer(&mut self.writer, &line)?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                let mut buf = Vec::with_capacity(17 + entry.key.len() + entry.value.len());
+                buf.push(DUMP_PAIR);
+                buf.extend_from_slice(&(entry.key.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&entry.key);
+                buf.extend_from_slice(&(entry.value.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&entry.value);
+                buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_be_bytes());
+                self.writer.write_all(&buf)?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// the number of pairs written so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// end the dump, and return the writer
+    pub fn finish(mut self) -> Result<W> {
+        if self.format == DumpFormat::Binary {
+            self.writer.write_all(&[DUMP_END])?;
+            self.writer.write_all(&self.count.to_be_bytes())?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the pairs of a dump written by `DumpWriter`, in either format
+pub struct DumpReader<R: BufRead> {
+    reader: R,
+    format: DumpFormat,
+    // bytes read so far, for the errors
+    offset: u64,
+    count: u64,
+    done: bool,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    /// read a dump, its format is told by its first bytes
+    pub fn new(mut reader: R) -> Result<DumpReader<R>> {
+        let binary = reader.fill_buf()?.starts_with(&DUMP_MAGIC);
+        let mut dump = DumpReader {
+            reader,
+            format: if binary {
+                DumpFormat::Binary
+            } else {
+                DumpFormat::Json
+            },
+            offset: 0,
+            count: 0,
+            done: false,
+        };
+        if binary {
+            let header = dump.read_bytes(8)?;
+            let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            if version != DUMP_FORMAT_VERSION {
+                return Err(dump.invalid(format!("unsupported format version {}", version)));
+            }
+        }
+        Ok(dump)
+    }
+
+    /// the format of the dump
+    pub fn format(&self) -> DumpFormat {
+        self.format
+    }
+
+    fn next_json(&mut self) -> Result<Option<DumpEntry>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = self.reader.read_line(&mut line)?;
+            if len == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+            self.offset += len as u64;
+        }
+        let entry: JsonEntry =
+            serde_json::from_str(&line).map_err(|err| self.invalid(err.to_string()))?;
+        let decode = |field: &str| {
+            base64::decode(field).map_err(|err| self.invalid(format!("invalid base64: {}", err)))
+        };
+        let entry = DumpEntry {
+            key: decode(&entry.key)?,
+            value: decode(&entry.value)?,
+            expires_at: entry.expires_at,
+        };
+        self.offset += line.len() as u64;
+        Ok(Some(entry))
+    }
+
+    fn next_binary(&mut self) -> Result<Option<DumpEntry>> {
+        match self.read_bytes(1)?[0] {
+            DUMP_PAIR => {}
+            DUMP_END => {
+                let count = self.u64()?;
+                if count != self.count {
+                    let reason = format!("{} pairs read, the dump has {}", self.count, count);
+                    return Err(self.invalid(reason));
+                }
+                return Ok(None);
+            }
+            tag => return Err(self.invalid(format!("unknown tag {}", tag))),
+        }
+        let key_len = self.u32()?;
+        let key = self.read_bytes(key_len as u64)?;
+        let value_len = self.u32()?;
+        let value = self.read_bytes(value_len as u64)?;
+        let expires_at = Some(self.u64()?).filter(|&expires_at| expires_at != 0);
+        Ok(Some(DumpEntry {
+            key,
+            value,
+            expires_at,
+        }))
+    }
+
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        match read_n(&mut self.reader, len) {
+            Ok(bytes) => {
+                self.offset += len;
+                Ok(bytes)
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(self.invalid("truncated dump".to_owned()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(&self.read_bytes(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&self.read_bytes(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn invalid(&self, reason: String) -> KvsError {
+        KvsError::ErrInvalidDump {
+            offset: self.offset,
+            reason,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<DumpEntry>;
+
+    fn next(&mut self) -> Option<Result<DumpEntry>> {
+        if self.done {
+            return None;
+        }
+        let entry = match self.format {
+            DumpFormat::Json => self.next_json(),
+            DumpFormat::Binary => self.next_binary(),
+        };
+        match entry {
+            Ok(Some(entry)) => {
+                self.count += 1;
+                Some(Ok(entry))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            // nothing sensible follows an invalid pair
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
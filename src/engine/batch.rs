@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+/// A write of a `WriteBatch`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// set the key to the value, it never expires
+    Set {
+        /// key
+        key: Vec<u8>,
+        /// value
+        value: Vec<u8>,
+    },
+    /// remove the key, nothing happens if it does not exist
+    Remove {
+        /// key
+        key: Vec<u8>,
+    },
+}
+
+/// Writes applied all together or not at all by `KvsEngine::write_batch`
+///
+/// The writes are applied in order, a key written twice ends up as the last
+/// write left it.
+///
+/// ```
+/// use kvs::WriteBatch;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.set("from", "0").set("to", "10").remove("pending");
+/// assert_eq!(batch.len(), 3);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// an empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// set kv pair
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// remove kv pair
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// the number of writes
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// whether there is no write
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// the writes, in order
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// the writes, in order
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
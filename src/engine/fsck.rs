@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Write};
+use std::path::PathBuf;
+
+use super::hint::read_hint;
+use super::log_file::LogNames;
+use super::record::{
+    parse_record, read_log_format, write_kv, write_log_header, LogFormat, Record,
+    LOG_FORMAT_VERSION,
+};
+use super::util::KV;
+use crate::io::{create_empty_dir, lock_dir};
+use crate::Result;
+
+/// What `FsckReport::check` found in the files of a `KvStore` directory.
+///
+/// The store must not be open while it is checked. Gaps between the log
+/// indexes, hints without their log and unfinished compactions are what a
+/// crash during a compaction leaves, the store cleans them up when opened.
+/// A damaged sealed log, a hint that does not match its log or two files
+/// for the same log are not something opening the store can deal with.
+///
+/// ```
+/// use kvs::{FsckReport, KvStore, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// KvStore::open(temp_dir.path()).unwrap().set("key1", "value1").unwrap();
+/// let report = FsckReport::check(temp_dir.path(), "log").unwrap();
+/// assert!(report.is_clean());
+/// assert_eq!(report.logs[0].records, 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// the logs, in the order they are replayed
+    pub logs: Vec<LogReport>,
+    /// the indexes missing between the first and the last log
+    pub gaps: Vec<u64>,
+    /// the logs with more than one file, like `log_1` and `log_01`
+    pub duplicates: Vec<(u64, Vec<PathBuf>)>,
+    /// the hints without their log
+    pub orphaned_hints: Vec<PathBuf>,
+    /// the outputs of interrupted compactions
+    pub unfinished_compactions: Vec<PathBuf>,
+    /// the records written to the repaired directory by `FsckReport::repair`
+    pub salvaged: u64,
+}
+
+/// What `FsckReport::check` found in a log
+#[derive(Debug)]
+pub struct LogReport {
+    /// index of the log
+    pub index: u64,
+    /// the log file
+    pub path: PathBuf,
+    /// the format of the log, `None` if it is empty
+    pub format: Option<LogFormat>,
+    /// the length of the log
+    pub len: u64,
+    /// the puts and tombstones that would be replayed
+    pub records: u64,
+    /// the committed write batches
+    pub batches: u64,
+    /// the records of write batches cut short by damage or never committed
+    pub dropped: u64,
+    /// the damaged byte ranges
+    pub corrupt: Vec<CorruptRange>,
+    /// whether the hint of the log matches it, `None` if it has no hint
+    pub hint: Option<bool>,
+}
+
+/// Damaged bytes of a log
+#[derive(Debug, PartialEq)]
+pub struct CorruptRange {
+    /// the offset of the first damaged byte
+    pub start: u64,
+    /// the offset of the next valid record, or the log length
+    pub end: u64,
+    /// what is wrong at `start`
+    pub reason: String,
+}
+
+impl FsckReport {
+    /// check every file of the `KvStore` in `dir` whose logs are named
+    /// after `file_prefix`, without changing anything
+    pub fn check(dir: impl Into<PathBuf>, file_prefix: &str) -> Result<FsckReport> {
+        walk(&LogNames::new(&dir.into(), file_prefix), |_| Ok(()))
+    }
+
+    /// check the store like `check`, and write a store of every record that
+    /// can be salvaged from its logs to `dest`, which must be missing or empty.
+    ///
+    /// The records are written in the order they were replayed, a key whose
+    /// last records were damaged is back at the last record that was not.
+    pub fn repair(
+        dir: impl Into<PathBuf>,
+        file_prefix: &str,
+        dest: impl Into<PathBuf>,
+    ) -> Result<FsckReport> {
+        let dest = dest.into();
+        create_empty_dir(&dest)?;
+        let _lock = lock_dir(&dest, "kvs", LOG_FORMAT_VERSION)?;
+        let mut log = File::create(LogNames::new(&dest, file_prefix).log_path(1))?;
+        write_log_header(&mut log)?;
+        let mut report = walk(&LogNames::new(&dir.into(), file_prefix), |kv| {
+            write_kv(&mut log, kv).map(|_| ())
+        })?;
+        log.flush()?;
+        log.sync_all()?;
+        report.salvaged = report.logs.iter().map(|log| log.records).sum();
+        Ok(report)
+    }
+
+    /// whether the store opens with nothing cut off, and every file is
+    /// where it belongs
+    pub fn is_clean(&self) -> bool {
+        self.duplicates.is_empty()
+            && self
+                .logs
+                .iter()
+                .all(|log| log.corrupt.is_empty() && log.hint != Some(false))
+    }
+}
+
+/// a write batch read from a log, up to its commit record
+struct PendingBatch {
+    offset: u64,
+    count: u32,
+    records: Vec<KV>,
+}
+
+/// check the files of the store, and pass every record that would be
+/// replayed to `salvage`, in order
+fn walk(names: &LogNames, mut salvage: impl FnMut(&KV) -> io::Result<()>) -> Result<FsckReport> {
+    let mut report = FsckReport::default();
+    let logs = names.files_with_suffix("")?;
+    let mut by_index: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for (idx, path) in &logs {
+        by_index.entry(*idx).or_default().push(path.clone());
+    }
+    if let (Some(first), Some(last)) = (by_index.keys().next(), by_index.keys().last()) {
+        report.gaps = (*first..*last)
+            .filter(|idx| !by_index.contains_key(idx))
+            .collect();
+    }
+    report.duplicates = by_index
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .collect();
+    report.orphaned_hints = names
+        .files_with_suffix(".hint")?
+        .into_iter()
+        .filter(|(idx, _)| !names.log_path(*idx).exists())
+        .map(|(_, path)| path)
+        .collect();
+    report.unfinished_compactions = names
+        .files_with_suffix(".compact")?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+
+    for (idx, path) in logs {
+        let data = fs::read(&path)?;
+        let mut log = LogReport {
+            index: idx,
+            path,
+            format: None,
+            len: data.len() as u64,
+            records: 0,
+            batches: 0,
+            dropped: 0,
+            corrupt: Vec::new(),
+            hint: None,
+        };
+        let hint_path = names.hint_path(idx);
+        if hint_path.exists() {
+            log.hint = Some(read_hint(&hint_path, log.len).is_some());
+        }
+        check_log(&data, &mut log, &mut salvage)?;
+        report.logs.push(log);
+    }
+    Ok(report)
+}
+
+/// check the records of a log, resuming after a damaged range at the next
+/// record with a valid checksum
+fn check_log(
+    data: &[u8],
+    log: &mut LogReport,
+    salvage: &mut impl FnMut(&KV) -> io::Result<()>,
+) -> Result<()> {
+    let mut cursor = Cursor::new(data);
+    let format = match read_log_format(&mut cursor) {
+        Ok(Some(format)) => format,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            damaged(log, 0, log.len, &err.to_string());
+            return Ok(());
+        }
+    };
+    log.format = Some(format);
+    let mut offset = cursor.position();
+    let mut batch: Option<PendingBatch> = None;
+    while offset < log.len {
+        let (record, len) = match parse_record(&data[offset as usize..], format) {
+            Ok(Some(read)) => read,
+            Ok(None) => break,
+            Err(err) => {
+                if let Some(batch) = batch.take() {
+                    log.dropped += batch.records.len() as u64;
+                }
+                let end = resync(data, offset + 1, format);
+                damaged(log, offset, end, &err.to_string());
+                offset = end;
+                continue;
+            }
+        };
+        match record {
+            Record::Kv(kv) => match batch {
+                Some(ref mut batch) => batch.records.push(kv),
+                None => {
+                    salvage(&kv)?;
+                    log.records += 1;
+                }
+            },
+            Record::BatchBegin(count) => {
+                if let Some(batch) = batch.take() {
+                    log.dropped += batch.records.len() as u64;
+                    let reason = "write batch inside a write batch";
+                    damaged(log, offset, offset + len, reason);
+                }
+                batch = Some(PendingBatch {
+                    offset,
+                    count,
+                    records: Vec::new(),
+                });
+            }
+            Record::BatchCommit => match batch.take() {
+                Some(committed) if committed.records.len() == committed.count as usize => {
+                    for kv in &committed.records {
+                        salvage(kv)?;
+                    }
+                    log.records += committed.records.len() as u64;
+                    log.batches += 1;
+                }
+                uncommitted => {
+                    log.dropped += uncommitted.map_or(0, |batch| batch.records.len() as u64);
+                    let reason = "write batch commit without its records";
+                    damaged(log, offset, offset + len, reason);
+                }
+            },
+        }
+        offset += len;
+    }
+    if let Some(batch) = batch {
+        log.dropped += batch.records.len() as u64;
+        damaged(log, batch.offset, log.len, "uncommitted write batch");
+    }
+    Ok(())
+}
+
+/// report the bytes of `log` from `start` to `end` as damaged
+fn damaged(log: &mut LogReport, start: u64, end: u64, reason: &str) {
+    log.corrupt.push(CorruptRange {
+        start,
+        end,
+        reason: reason.to_owned(),
+    });
+}
+
+/// the offset of the first record with a valid checksum from `from` on, or
+/// the end of the log. Records without a checksum can not be told from
+/// damaged bytes, nothing after a damaged one is trusted.
+fn resync(data: &[u8], from: u64, format: LogFormat) -> u64 {
+    let with_crc = matches!(format, LogFormat::Binary(version) if version >= 2);
+    if with_crc {
+        for offset in from..data.len() as u64 {
+            if let Ok(Some(_)) = parse_record(&data[offset as usize..], format) {
+                return offset;
+            }
+        }
+    }
+    data.len() as u64
+}
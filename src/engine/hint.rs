@@ -0,0 +1,135 @@
+use crc32fast::Hasher;
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// magic bytes at the beginning of every hint file
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+/// current on-disk format version of hint files
+const HINT_FORMAT_VERSION: u32 = 2;
+/// magic + format version + length of the described log
+const HINT_HEADER_LEN: usize = 16;
+/// crc32 of everything before it
+const HINT_TRAILER_LEN: usize = 4;
+
+/// One record of a log, as described by its hint file.
+pub struct HintEntry {
+    /// key
+    pub key: Vec<u8>,
+    /// offset of the record in the log
+    pub offset: u64,
+    /// whether the record is a tombstone
+    pub tombstone: bool,
+    /// version of the key set by the record
+    pub version: u32,
+    /// expiry time of the record, in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+}
+
+/// Write the hint file of a log of `log_len` bytes, and sync it.
+///
+/// A hint lists the key, offset, tombstone flag, version and expiry time
+/// of every record of a sealed log, so the index can be rebuilt without
+/// reading the values:
+///
+/// ```text
+/// | magic | version: u32 | log len: u64 | entries... | crc: u32 |
+/// entry: | key len: u32 | key | offset: u64 | tombstone: u8 | version: u32 | expires at: u64 |
+/// ```
+///
+/// where an expiry time of 0 means the record never expires. Hints of
+/// format version 1 had neither versions nor expiry times, their logs are
+/// replayed instead.
+pub fn write_hint(path: &Path, log_len: u64, entries: &[HintEntry]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(HINT_HEADER_LEN + HINT_TRAILER_LEN);
+    buf.extend_from_slice(&HINT_MAGIC);
+    buf.extend_from_slice(&HINT_FORMAT_VERSION.to_be_bytes());
+    buf.extend_from_slice(&log_len.to_be_bytes());
+    for entry in entries {
+        buf.extend_from_slice(&(entry.key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&entry.key);
+        buf.extend_from_slice(&entry.offset.to_be_bytes());
+        buf.push(entry.tombstone as u8);
+        buf.extend_from_slice(&entry.version.to_be_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_be_bytes());
+    }
+    let mut hasher = Hasher::new();
+    hasher.update(&buf);
+    buf.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+    let mut file = File::create(path)?;
+    file.write_all(&buf)?;
+    file.sync_all()
+}
+
+/// read the hint file of a log of `log_len` bytes.
+/// Return `None` if there is no hint, or if it does not match the log.
+pub fn read_hint(path: &Path, log_len: u64) -> Option<Vec<HintEntry>> {
+    let data = fs::read(path).ok()?;
+    let entries = parse_hint(&data, log_len);
+    if entries.is_none() {
+        warn!("{} is invalid, replay the whole log", path.display());
+    }
+    entries
+}
+
+fn parse_hint(data: &[u8], log_len: u64) -> Option<Vec<HintEntry>> {
+    if data.len() < HINT_HEADER_LEN + HINT_TRAILER_LEN || data[..4] != HINT_MAGIC {
+        return None;
+    }
+    let (body, crc) = data.split_at(data.len() - HINT_TRAILER_LEN);
+    let mut hasher = Hasher::new();
+    hasher.update(body);
+    if hasher.finalize().to_be_bytes() != crc {
+        return None;
+    }
+    let mut cursor = Cursor { data: body, pos: 4 };
+    if cursor.u32()? != HINT_FORMAT_VERSION || cursor.u64()? != log_len {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    while cursor.pos < body.len() {
+        let key_len = cursor.u32()? as usize;
+        let key = cursor.take(key_len)?.to_vec();
+        let offset = cursor.u64()?;
+        let tombstone = cursor.take(1)?[0] != 0;
+        let version = cursor.u32()?;
+        let expires_at = Some(cursor.u64()?).filter(|&expires_at| expires_at != 0);
+        entries.push(HintEntry {
+            key,
+            offset,
+            tombstone,
+            version,
+            expires_at,
+        });
+    }
+    Some(entries)
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Some(u32::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Some(u64::from_be_bytes(buf))
+    }
+}
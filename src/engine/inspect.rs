@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Seek};
+use std::path::PathBuf;
+
+use super::fsck::CorruptRange;
+use super::log_file::{read_error, LogNames};
+use super::record::{read_log_format, read_record, LogFormat, Record};
+use super::util::now_millis;
+use crate::{KvsError, Result};
+
+/// Reads the logs of a `KvStore` directory record by record, to see what
+/// was written where.
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine, LogInspector, Record};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("key1", "value1").unwrap();
+/// store.remove("key1").unwrap();
+/// drop(store);
+///
+/// let inspector = LogInspector::new(temp_dir.path(), "log");
+/// let history = inspector.history(b"key1").unwrap();
+/// assert_eq!(history.entries.len(), 2);
+/// assert!(matches!(&history.entries[1].record, Record::Kv(kv) if kv.version == 0));
+/// assert!(history.damaged.is_empty());
+/// ```
+pub struct LogInspector {
+    names: LogNames,
+}
+
+/// A record of a log, with where it is
+pub struct LogEntry {
+    /// index of the log
+    pub file: u64,
+    /// offset of the record in the log
+    pub offset: u64,
+    /// length of the record on disk
+    pub len: u64,
+    /// the record
+    pub record: Record,
+}
+
+/// Every record of a key, see `LogInspector::history`
+pub struct KeyHistory {
+    /// the records of the key, in the order they were written
+    pub entries: Vec<LogEntry>,
+    /// the logs read only up to a damaged record, by index
+    pub damaged: Vec<(u64, CorruptRange)>,
+}
+
+/// How much of a log is still needed, as of now
+#[derive(Debug, PartialEq)]
+pub struct LogSummary {
+    /// index of the log
+    pub file: u64,
+    /// the format of the log, `None` if it is empty
+    pub format: Option<LogFormat>,
+    /// the length of the log
+    pub len: u64,
+    /// the records of the log, batch markers included
+    pub records: u64,
+    /// the records holding the current value of their key
+    pub live_records: u64,
+    /// the bytes of the live records
+    pub live_bytes: u64,
+    /// the bytes of the other records, dropped by the next compaction
+    pub stale_bytes: u64,
+    /// the damaged record the log was read up to, the bytes from it on are
+    /// neither live nor stale
+    pub damaged: Option<CorruptRange>,
+}
+
+impl LogInspector {
+    /// inspect the logs of the `KvStore` in `dir`, named after `file_prefix`
+    pub fn new(dir: impl Into<PathBuf>, file_prefix: &str) -> LogInspector {
+        LogInspector {
+            names: LogNames::new(&dir.into(), file_prefix),
+        }
+    }
+
+    /// the indexes of the logs, in order
+    pub fn logs(&self) -> Result<Vec<u64>> {
+        self.names.log_indexes()
+    }
+
+    /// the records of the log `file` in order, up to the first damaged one
+    pub fn records(&self, file: u64) -> Result<LogRecords> {
+        let path = self.names.log_path(file);
+        let mut reader = BufReader::new(File::open(&path)?);
+        let format = read_log_format(&mut reader).map_err(|err| read_error(&path, 0, err))?;
+        Ok(LogRecords {
+            file,
+            offset: reader.stream_position()?,
+            path,
+            reader,
+            format,
+            done: false,
+        })
+    }
+
+    /// every record of `key` in the logs, in the order they were written.
+    /// A damaged log is read up to the damage, the logs after it still are.
+    pub fn history(&self, key: &[u8]) -> Result<KeyHistory> {
+        let mut history = KeyHistory {
+            entries: Vec::new(),
+            damaged: Vec::new(),
+        };
+        for file in self.logs()? {
+            let len = self.names.log_path(file).metadata()?.len();
+            let records = match self.records(file) {
+                Ok(records) => records,
+                Err(err) => {
+                    history.damaged.push((file, damage(err, len)?));
+                    continue;
+                }
+            };
+            for entry in records {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        history.damaged.push((file, damage(err, len)?));
+                        break;
+                    }
+                };
+                if matches!(&entry.record, Record::Kv(kv) if kv.key == key) {
+                    history.entries.push(entry);
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    /// the live and stale bytes of every log. The last committed record of
+    /// a key is live unless it is a tombstone or has expired, like compaction
+    /// tells them. A damaged log is summed up to the damage, and a batch cut
+    /// short by it is stale like an uncommitted one.
+    pub fn summary(&self) -> Result<Vec<LogSummary>> {
+        let mut summaries = Vec::new();
+        // the last record of every key: its log and its length, whether it is live
+        let mut latest: HashMap<Vec<u8>, (usize, u64, bool)> = HashMap::new();
+        let now = now_millis();
+        for file in self.logs()? {
+            let len = self.names.log_path(file).metadata()?.len();
+            let mut summary = LogSummary {
+                file,
+                format: None,
+                len,
+                records: 0,
+                live_records: 0,
+                live_bytes: 0,
+                stale_bytes: 0,
+                damaged: None,
+            };
+            let records = match self.records(file) {
+                Ok(records) => records,
+                Err(err) => {
+                    summary.damaged = Some(damage(err, len)?);
+                    summaries.push(summary);
+                    continue;
+                }
+            };
+            summary.format = records.format;
+            let mut batch = None;
+            for entry in records {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        summary.damaged = Some(damage(err, len)?);
+                        break;
+                    }
+                };
+                summary.records += 1;
+                summary.stale_bytes += entry.len;
+                let kv = match entry.record {
+                    Record::Kv(kv) => kv,
+                    Record::BatchBegin(_) => {
+                        batch = Some(Vec::new());
+                        continue;
+                    }
+                    Record::BatchCommit => {
+                        for (key, len, live) in batch.take().unwrap_or_default() {
+                            latest.insert(key, (summaries.len(), len, live));
+                        }
+                        continue;
+                    }
+                };
+                let live = kv.version != 0 && !kv.is_expired(now);
+                match batch {
+                    Some(ref mut batch) => batch.push((kv.key, entry.len, live)),
+                    None => {
+                        latest.insert(kv.key, (summaries.len(), entry.len, live));
+                    }
+                }
+            }
+            summaries.push(summary);
+        }
+        for (log, len, live) in latest.into_values() {
+            if live {
+                let summary = &mut summaries[log];
+                summary.live_records += 1;
+                summary.live_bytes += len;
+                summary.stale_bytes -= len;
+            }
+        }
+        Ok(summaries)
+    }
+}
+
+/// the damage a log of `len` bytes was read up to, any other error is returned
+fn damage(err: KvsError, len: u64) -> Result<CorruptRange> {
+    match err {
+        KvsError::ErrCorrupted { offset, reason, .. } => Ok(CorruptRange {
+            start: offset,
+            end: len,
+            reason,
+        }),
+        err => Err(err),
+    }
+}
+
+/// The records of a log, see `LogInspector::records`
+pub struct LogRecords {
+    file: u64,
+    path: PathBuf,
+    reader: BufReader<File>,
+    format: Option<LogFormat>,
+    offset: u64,
+    done: bool,
+}
+
+impl LogRecords {
+    /// the format of the log, `None` if it is empty
+    pub fn format(&self) -> Option<LogFormat> {
+        self.format
+    }
+}
+
+impl Iterator for LogRecords {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Result<LogEntry>> {
+        let format = self.format?;
+        if self.done {
+            return None;
+        }
+        match read_record(&mut self.reader, format) {
+            Ok(Some((record, len))) => {
+                let entry = LogEntry {
+                    file: self.file,
+                    offset: self.offset,
+                    len,
+                    record,
+                };
+                self.offset += len;
+                Some(Ok(entry))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                // nothing sensible follows a damaged record
+                self.done = true;
+                Some(Err(read_error(&self.path, self.offset, err)))
+            }
+        }
+    }
+}
@@ -1,29 +1,163 @@
 use crate::engine::KvsEngine;
 use crate::error::{KvsError, Result};
-use crate::io::{get_sst_from_dir_with_prefix, own_dir_or_not, read_n, write_kv};
+use crate::io::{create_empty_dir, lock_dir, DirLock};
 use crossbeam::atomic::AtomicCell;
-use dashmap::DashMap;
+use crossbeam_skiplist::SkipMap;
+use log::{error, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering;
-use std::sync::{Arc, RwLock};
-use std::u64;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use super::util::KV;
+use super::hint::{read_hint, write_hint, HintEntry};
+use super::log_file::{read_error, LogFile, LogNames};
+use super::options::{EngineOptions, KvStoreOptions, SyncPolicy, SyncState};
+use super::record::{
+    encode_batch_begin, encode_batch_commit, encode_kv, read_log_format, read_record, write_kv,
+    write_log_header, LogFormat, Record, LOG_FORMAT_VERSION,
+};
+use super::util::{deadline, now_millis, time_left, KV};
+use super::{BatchOp, Condition, KvPairs, KvStoreTransaction, Snapshot, WriteBatch};
 
-const ONE_SST_FILE_MAX_SIZE: u64 = 1024;
-const UNCOMPACTED_KEY_COUNTS: u64 = 100;
-
-/// for log position
-#[derive(Clone)]
+/// for log position, with the version and expiry time of the record there
+#[derive(Clone, Copy, PartialEq, Eq)]
 struct FileOffset {
     file: u64,
     offset: u64,
+    len: u64,
+    version: u32, // 0 for a tombstone
+    expires_at: Option<u64>,
+    seq: u64, // of the write, 0 for the records replayed on open
+}
+
+impl FileOffset {
+    /// the version of the key at `now`, 0 if it is removed or expired
+    fn live_version(&self, now: u64) -> u32 {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now => 0,
+            _ => self.version,
+        }
+    }
+}
+
+/// what a compaction would reclaim, to decide when to run one
+#[derive(Default)]
+struct Garbage {
+    records: AtomicCell<u64>, // overwritten or removed records
+    bytes: AtomicCell<u64>,   // their size
+    total: AtomicCell<u64>,   // size of all the records of the logs
+}
+
+impl Garbage {
+    /// account for a record of `len` bytes replacing `old`
+    fn written(&self, len: u64, old: Option<FileOffset>) {
+        self.total.fetch_add(len);
+        if let Some(old) = old {
+            self.records.fetch_add(1);
+            self.bytes.fetch_add(old.len);
+        }
+    }
+
+    /// account for `len` bytes of records that are stale as soon as they are
+    /// written, like the markers of a write batch
+    fn stale(&self, len: u64) {
+        self.total.fetch_add(len);
+        self.bytes.fetch_add(len);
+    }
+}
+
+/// the position of the last record of every key, in key order, tombstones included
+///
+/// A skip list replaces an entry by unlinking it before linking the new one,
+/// so the positions are updated in place instead, readers always find the key.
+type Index = SkipMap<Vec<u8>, AtomicCell<FileOffset>>;
+
+/// index the record of `key` at `fo`, return the position of its previous record
+///
+/// Only writers holding the write handler and the replay call it, compaction
+/// removes keys under the write handler too.
+fn index_insert(index: &Index, key: Vec<u8>, fo: FileOffset) -> Option<FileOffset> {
+    match index.get(&key) {
+        Some(entry) => Some(entry.value().swap(fo)),
+        None => {
+            index.insert(key, AtomicCell::new(fo));
+            None
+        }
+    }
+}
+
+/// the live log files by index, readers clone a file out of it to keep it alive
+type LogFiles = RwLock<BTreeMap<u64, Arc<LogFile>>>;
+
+/// The writes a read sees, up to the sequence number `seq`, with the keys
+/// expiring by `now` gone
+#[derive(Clone, Copy)]
+pub(super) struct View {
+    seq: u64,
+    now: u64,
+}
+
+impl View {
+    /// every write so far
+    fn latest() -> View {
+        View {
+            seq: u64::MAX,
+            now: now_millis(),
+        }
+    }
+}
+
+/// The sequence numbers of the writes, and the records the live snapshots
+/// still see after their keys were written again.
+///
+/// Sequence numbers are not persisted, the snapshots do not outlive the store.
+#[derive(Default)]
+struct Versions {
+    seq: AtomicCell<u64>,                              // of the last write
+    live: Mutex<BTreeMap<u64, usize>>,                 // the snapshots by sequence number
+    history: Mutex<HashMap<Vec<u8>, Vec<FileOffset>>>, // older records, oldest first
+}
+
+impl Versions {
+    /// whether a live snapshot sees the write `seq` or a later one
+    fn pinned(&self, seq: u64) -> bool {
+        self.live.lock().unwrap().range(seq..).next().is_some()
+    }
+
+    /// the record of `key` the snapshots at `seq` see, `None` if the key
+    /// was not written yet
+    fn lookup(&self, key: &[u8], seq: u64) -> Option<FileOffset> {
+        let history = self.history.lock().unwrap();
+        history
+            .get(key)?
+            .iter()
+            .rev()
+            .find(|fo| fo.seq <= seq)
+            .cloned()
+    }
+
+    /// point the record of `key` at `old` to its copy at `new`
+    fn moved(
+        history: &mut HashMap<Vec<u8>, Vec<FileOffset>>,
+        key: &[u8],
+        old: FileOffset,
+        new: FileOffset,
+    ) {
+        if let Some(fo) = history
+            .get_mut(key)
+            .and_then(|versions| versions.iter_mut().find(|fo| **fo == old))
+        {
+            *fo = new;
+        }
+    }
 }
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores key/value pairs of bytes.
 /// It is a reader lock-free kv store, and it will compact automatically
 ///
 /// here is the usage
@@ -37,7 +171,7 @@ struct FileOffset {
 /// // set a kv
 /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
 /// // get a kv
-/// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// assert_eq!(store.get("key1").unwrap(), Some(b"value1".to_vec()));
 /// // rm a kv
 /// assert!(store.remove("key1".to_owned()).is_ok());
 /// // check it!
@@ -45,265 +179,1135 @@ struct FileOffset {
 /// ```
 #[derive(Clone)]
 pub struct KvStore {
-    current_dir: PathBuf,
-    index: Arc<DashMap<String, FileOffset>>,
-    reader_count: Arc<AtomicU32>, // readers count
+    names: LogNames,
+    options: Arc<KvStoreOptions>,
+    index: Arc<Index>,
+    files: Arc<LogFiles>,
     write_handler: Arc<RwLock<File>>,
     writer_index: Arc<RwLock<u64>>,
-    uncompacted: Arc<AtomicCell<u64>>, // repeated keys count, for compaction
+    garbage: Arc<Garbage>, // stale records, for compaction
+    versions: Arc<Versions>,
+    sync_state: Arc<Mutex<SyncState>>,
+    compaction: Arc<CompactionWorker>,
+    discarded_bytes: u64,
+    // released once the compaction above is stopped
+    _lock: Arc<DirLock>,
 }
 
 impl KvsEngine for KvStore {
+    type Transaction = KvStoreTransaction;
+
     /// set kv pair
     /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
-    fn set(&self, key: String, val: String) -> Result<()> {
-        if self.uncompacted.load() > UNCOMPACTED_KEY_COUNTS {
-            self.compaction();
-        }
-
+    fn set(&self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> Result<()> {
+        self.put(key.into(), val.into(), None, None)
+    }
+    /// set kv pair, with its expiry time in the record
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.put(key.into(), val.into(), Some(deadline(ttl)), None)
+    }
+    /// set kv pair, the condition is checked under the write lock
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+        condition: Condition,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.put(key.into(), val.into(), ttl.map(deadline), Some(condition))
+    }
+    /// get kv pair
+    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+    /// get kv pair, the version is the one of the record read
+    fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u32)>> {
+        self.get_at(&key.into(), View::latest())
+    }
+    /// remove kv pair
+    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
         let mut write_handler = self.write_handler.write().unwrap();
         let mut writer_index = self.writer_index.write().unwrap();
 
-        // if duplicate key insert, add uncompacted
-        if let Some(_) = self.index.get(&key) {
-            self.uncompacted.fetch_add(1);
+        // removed and expired keys are already gone
+        if self.live_version(&key) == 0 {
+            return Err(KvsError::ErrKeyNotFound);
+        }
+        let kv = KV::new(key, Vec::new(), 0);
+        self.append_locked(&mut write_handler, &mut writer_index, kv)
+    }
+    /// append the writes between a begin and a commit record, in one write
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if self.garbage_reached() {
+            self.compaction.trigger();
         }
 
-        let len = write_handler.metadata().unwrap().len();
-        let kv = KV::new(key.clone(), val, 1);
-        self.index.insert(
-            key,
-            FileOffset {
-                file: writer_index.clone(),
-                offset: len,
-            },
-        );
-        write_kv(&mut write_handler, kv);
+        let mut write_handler = self.write_handler.write().unwrap();
+        let mut writer_index = self.writer_index.write().unwrap();
+        self.write_batch_locked(&mut write_handler, &mut writer_index, batch)
+    }
+    /// the time left from the expiry time in the index
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let fo = match self.index.get(&key.into()) {
+            Some(entry) => entry.value().load(),
+            None => return Err(KvsError::ErrKeyNotFound),
+        };
+        if fo.live_version(now_millis()) == 0 {
+            return Err(KvsError::ErrKeyNotFound);
+        }
+        Ok(fo.expires_at.map(time_left))
+    }
+    /// append the pair again without its expiry time, at the same version
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let mut write_handler = self.write_handler.write().unwrap();
+        let mut writer_index = self.writer_index.write().unwrap();
 
-        if len > ONE_SST_FILE_MAX_SIZE {
-            *writer_index += 1;
-            let filename = format!("log_{}", writer_index);
-            let write_file_path = self.current_dir.clone().join(filename);
-            *write_handler = get_write_file_handler(write_file_path);
+        let kv = self.read_live(&key)?.ok_or(KvsError::ErrKeyNotFound)?;
+        if kv.expires_at.is_none() {
+            return Ok(());
         }
+        self.append_locked(&mut write_handler, &mut writer_index, kv.expiring(None))
+    }
+    /// scan kv pairs in key order, the values are read as the iteration goes
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvPairs<'_>> {
+        self.scan_at(range, limit, View::latest())
+    }
+    /// a transaction reading from a snapshot
+    fn begin_transaction(&self) -> Result<KvStoreTransaction> {
+        Ok(KvStoreTransaction::new(self.snapshot()))
+    }
+    /// hard-link the sealed logs with their hints and copy the active log up
+    /// to its length when the checkpoint starts, writes go on meanwhile
+    fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = dest.into();
+        create_empty_dir(&dest)?;
+        let _lock = lock_dir(&dest, "kvs", LOG_FORMAT_VERSION)?;
+        let names = LogNames::new(&dest, &self.options.file_prefix);
 
+        // no write is in progress, the active log ends with a whole record,
+        // and the logs held here are not deleted by a compaction until copied
+        let (logs, active_idx, active_len) = {
+            let write_handler = self.write_handler.read().unwrap();
+            let writer_index = self.writer_index.read().unwrap();
+            let logs: BTreeMap<u64, Arc<LogFile>> = self.files.read().unwrap().clone();
+            (logs, *writer_index, write_handler.metadata()?.len())
+        };
+        for &idx in logs.keys() {
+            let path = names.log_path(idx);
+            if idx == active_idx {
+                copy_prefix(&self.names.log_path(idx), &path, active_len)?;
+                continue;
+            }
+            link_or_copy(&self.names.log_path(idx), &path)?;
+            if self.names.hint_path(idx).exists() {
+                link_or_copy(&self.names.hint_path(idx), &names.hint_path(idx))?;
+            }
+            // the store only syncs its sealed logs if its sync policy asks for it
+            File::open(&path)?.sync_all()?;
+        }
         Ok(())
     }
-    /// get kv pair
-    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // let index = self.index.read().unwrap();
-        let current_dir = self.current_dir.clone();
+}
+
+impl KvStore {
+    /// A consistent view of the store as it is now, unchanged by the writes
+    /// that follow. The records it sees are kept, and compaction copies them,
+    /// until it is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        // a write in progress is either seen whole or not at all
+        let _write_handler = self.write_handler.read().unwrap();
+        let view = View {
+            seq: self.versions.seq.load(),
+            now: now_millis(),
+        };
+        *self
+            .versions
+            .live
+            .lock()
+            .unwrap()
+            .entry(view.seq)
+            .or_insert(0) += 1;
+        Snapshot::new(self.clone(), view)
+    }
 
-        if let Some(fo) = self.index.get(&key) {
-            self.reader_count.fetch_add(1, Ordering::SeqCst);
-            let res =
-                KvStore::get_value_by_file_index(current_dir.clone(), fo.file.clone(), fo.offset);
-            self.reader_count.fetch_sub(1, Ordering::SeqCst);
-            return res;
+    /// forget the snapshot at `view`, and the records only it still sees
+    pub(super) fn release(&self, view: View) {
+        let _write_handler = self.write_handler.read().unwrap();
+        let mut live = self.versions.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&view.seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&view.seq);
+            }
         }
-        return Ok(None);
+        // a record is seen by the snapshots from its write to the next write of its key
+        let mut history = self.versions.history.lock().unwrap();
+        history.retain(|key, versions| {
+            let latest = self
+                .index
+                .get(key)
+                .map_or(u64::MAX, |entry| entry.value().load().seq);
+            let mut ends = versions
+                .iter()
+                .skip(1)
+                .map(|fo| fo.seq)
+                .chain(Some(latest))
+                .collect::<Vec<_>>()
+                .into_iter();
+            versions.retain(|fo| {
+                let end = ends.next().unwrap_or(u64::MAX);
+                live.range(fo.seq..end).next().is_some()
+            });
+            !versions.is_empty()
+        });
     }
-    /// remove kv pair
-    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()> {
-        // let mut index = self.index.write().unwrap();
+
+    /// append the writes of a transaction reading from `view`, unless one of
+    /// the keys it read was written since
+    pub(super) fn commit(
+        &self,
+        view: View,
+        reads: &HashSet<Vec<u8>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        if self.garbage_reached() {
+            self.compaction.trigger();
+        }
+
         let mut write_handler = self.write_handler.write().unwrap();
-        let writer_index = self.writer_index.read().unwrap();
+        let mut writer_index = self.writer_index.write().unwrap();
 
-        {
-            // if hold the reference of the map, then insert will deadlock
-            let idx = self.index.get(&key);
-            match idx {
-                Some(_) => {
-                    self.uncompacted.fetch_add(1);
-                }
-                None => return Err(KvsError::ErrKeyNotFound),
+        // the view pins the records it sees, a record written since has a
+        // later sequence number
+        let seq = |fo: FileOffset| fo.seq;
+        for key in reads {
+            if self.lookup(key, view).map(seq) != self.lookup(key, View::latest()).map(seq) {
+                return Err(KvsError::ErrConflict);
             }
         }
-        let len = write_handler.metadata().unwrap().len();
-        self.index.insert(
-            key.clone(),
-            FileOffset {
-                file: *writer_index,
-                offset: len,
-            },
-        );
-        let kv = KV::new(key, "".to_owned(), 0);
-        write_kv(&mut write_handler, kv);
-        Ok(())
+        self.write_batch_locked(&mut write_handler, &mut writer_index, batch)
     }
-}
 
-impl KvStore {
-    fn compaction(&self) {
-        let current_dir = self.current_dir.clone();
+    /// get kv pair with its version, as seen by `view`
+    pub(super) fn get_at(&self, key: &[u8], view: View) -> Result<Option<(Vec<u8>, u32)>> {
+        let fo = match self.lookup(key, view) {
+            Some(fo) => fo,
+            None => return Ok(None),
+        };
+        if fo.live_version(view.now) == 0 {
+            return Ok(None);
+        }
+        Ok(self
+            .read_record(key, fo, view, None)?
+            .map(|kv| (kv.value, kv.version)))
+    }
+
+    /// scan kv pairs in key order as seen by `view`, the values are read as
+    /// the iteration goes. The logs live when the scan starts are held until
+    /// it is dropped, a log compacted away meanwhile is still read.
+    pub(super) fn scan_at<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+        view: View,
+    ) -> Result<KvPairs<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let logs = self.files.read().unwrap().clone();
+        let pairs = self.index.range(range).filter_map(move |entry| {
+            let key = entry.key();
+            let fo = match entry.value().load() {
+                fo if fo.seq <= view.seq => fo,
+                _ => self.versions.lookup(key, view.seq)?,
+            };
+            match self.read_value(key, fo, view, &logs) {
+                Ok(Some(value)) => Some(Ok((key.clone(), value))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        });
+        Ok(Box::new(pairs.take(limit.unwrap_or(usize::MAX))))
+    }
+
+    /// the record of `key` seen by `view`, `None` if the key was not written
+    /// yet or was removed by compaction
+    fn lookup(&self, key: &[u8], view: View) -> Option<FileOffset> {
+        let fo = self.index.get(key)?.value().load();
+        if fo.seq <= view.seq {
+            return Some(fo);
+        }
+        // a writer keeps the record it replaces before updating the index
+        self.versions.lookup(key, view.seq)
+    }
+
+    /// append a put at the next version of the key, if `condition` holds,
+    /// compacting first if enough records are stale
+    fn put(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        condition: Option<Condition>,
+    ) -> Result<()> {
+        if self.garbage_reached() {
+            self.compaction.trigger();
+        }
+
         let mut write_handler = self.write_handler.write().unwrap();
         let mut writer_index = self.writer_index.write().unwrap();
 
-        let len = write_handler.metadata().unwrap().len();
-        if len < ONE_SST_FILE_MAX_SIZE {
-            return;
+        let version = self.live_version(&key);
+        let holds = match condition {
+            None => true,
+            Some(Condition::Absent) => version == 0,
+            Some(Condition::Version(expected)) => version == expected,
+            Some(Condition::Equals(expected)) => {
+                version != 0 && self.read_live(&key)?.map(|kv| kv.value) == Some(expected)
+            }
+        };
+        if !holds {
+            return Err(KvsError::ErrConditionFailed);
         }
+        let kv = KV::new(key, value, version.checked_add(1).unwrap_or(1)).expiring(expires_at);
+        self.append_locked(&mut write_handler, &mut writer_index, kv)
+    }
 
-        let old_files = get_sst_from_dir_with_prefix(current_dir.clone(), "log_".to_owned());
+    /// the current version of `key`, 0 if it does not exist
+    fn live_version(&self, key: &[u8]) -> u32 {
+        self.index
+            .get(key)
+            .map_or(0, |entry| entry.value().load().live_version(now_millis()))
+    }
 
-        *writer_index += 1;
-        let mut filename = format!("log_{}", writer_index);
-        let mut file = current_dir.clone().join(filename);
-        *write_handler = get_write_file_handler(file);
+    /// append `kv` to the active log and index it, starting a new log once
+    /// the active one is full
+    fn append_locked(
+        &self,
+        write_handler: &mut File,
+        writer_index: &mut u64,
+        kv: KV,
+    ) -> Result<()> {
+        // readers may look the key up as soon as it is indexed, write it first
+        let record = encode_kv(&kv);
+        let offset = append(write_handler, &record)?;
+        let written = record.len() as u64;
+        self.index_written(*writer_index, offset, written, kv);
+        self.sync_written(write_handler, written)?;
+        self.seal_full(write_handler, writer_index, offset + written)
+    }
 
-        // 保存旧的 index，并且遍历来生成新的 sst
-        let old_index = (*self.index).clone();
+    /// append the writes of `batch` at the next versions of their keys
+    fn write_batch_locked(
+        &self,
+        write_handler: &mut File,
+        writer_index: &mut u64,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        // the versions left by the writes so far, for the later writes of a key
+        let mut versions = HashMap::new();
+        let mut kvs = Vec::with_capacity(batch.len());
+        for op in batch.into_ops() {
+            let (key, value) = match op {
+                BatchOp::Set { key, value } => (key, Some(value)),
+                BatchOp::Remove { key } => (key, None),
+            };
+            let version = versions
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| self.live_version(&key));
+            let kv = match value {
+                Some(value) => KV::new(key, value, version.checked_add(1).unwrap_or(1)),
+                // removed and expired keys are already gone
+                None if version == 0 => continue,
+                None => KV::new(key, Vec::new(), 0),
+            };
+            versions.insert(kv.key.clone(), kv.version);
+            kvs.push(kv);
+        }
+        if kvs.is_empty() {
+            return Ok(());
+        }
+        self.append_batch_locked(write_handler, writer_index, kvs)
+    }
 
-        for key_file_offset in &old_index {
-            let key = key_file_offset.key();
-            // 只处理仍然存在的 key，如果 key 不存在或者被删除了，那么就不需要写到新的里面去了
-            if let Some(fo) = old_index.get(key) {
-                let value = KvStore::get_value_by_file_index(
-                    current_dir.clone(),
-                    fo.file.clone(),
-                    fo.offset,
-                )
-                .unwrap()
-                .unwrap();
-
-                let len = write_handler.metadata().unwrap().len();
-                let kv = KV::new(key.clone(), value, 1);
-                self.index.insert(
-                    key.clone(),
-                    FileOffset {
-                        file: *writer_index,
-                        offset: len,
-                    },
-                );
-                write_kv(&mut write_handler, kv);
-                if len > ONE_SST_FILE_MAX_SIZE {
-                    *writer_index += 1;
-                    filename = format!("log_{}", writer_index);
-                    file = current_dir.clone().join(filename);
-                    *write_handler = get_write_file_handler(file);
+    /// append `kvs` to the active log as one write batch and index them,
+    /// starting a new log once the active one is full
+    fn append_batch_locked(
+        &self,
+        write_handler: &mut File,
+        writer_index: &mut u64,
+        kvs: Vec<KV>,
+    ) -> Result<()> {
+        let mut records = encode_batch_begin(kvs.len() as u32);
+        let mut positions = Vec::with_capacity(kvs.len());
+        for kv in &kvs {
+            let record = encode_kv(kv);
+            positions.push((records.len() as u64, record.len() as u64));
+            records.extend_from_slice(&record);
+        }
+        records.extend_from_slice(&encode_batch_commit());
+
+        // none of the batch is indexed before all of it is written
+        let offset = append(write_handler, &records)?;
+        let written = records.len() as u64;
+        let mut markers = written;
+        for (kv, (relative, len)) in kvs.into_iter().zip(positions) {
+            self.index_written(*writer_index, offset + relative, len, kv);
+            markers -= len;
+        }
+        self.garbage.stale(markers);
+        self.sync_written(write_handler, written)?;
+        self.seal_full(write_handler, writer_index, offset + written)
+    }
+
+    /// index `kv` written at `offset` of the log `file`
+    fn index_written(&self, file: u64, offset: u64, len: u64, kv: KV) {
+        let fo = FileOffset {
+            file,
+            offset,
+            len,
+            version: kv.version,
+            expires_at: kv.expires_at,
+            seq: self.versions.seq.fetch_add(1) + 1,
+        };
+        let entry = self.index.get(&kv.key);
+        let old = match entry {
+            // a snapshot still sees the record replaced, keep it before a
+            // reader can miss it in the index
+            Some(entry) if self.versions.pinned(entry.value().load().seq) => {
+                // compaction moves the records under the same lock
+                let mut history = self.versions.history.lock().unwrap();
+                let old = entry.value().load();
+                history.entry(kv.key).or_default().push(old);
+                entry.value().store(fo);
+                Some(old)
+            }
+            _ => index_insert(&self.index, kv.key, fo),
+        };
+        // if duplicate key insert, the old record is garbage
+        self.garbage.written(len, old);
+    }
+
+    /// start a new log if the active one, `end` bytes long, is full
+    fn seal_full(&self, write_handler: &mut File, writer_index: &mut u64, end: u64) -> Result<()> {
+        if end > self.options.max_file_size {
+            sync_sealed(&self.sync_state, write_handler)?;
+            *write_handler = new_active_log(
+                &self.names,
+                &self.files,
+                *writer_index + 1,
+                self.options.read_buffer_size,
+            )?;
+            *writer_index += 1;
+        }
+        Ok(())
+    }
+
+    /// read the value of `key` from the record at `fo`, `None` for a tombstone
+    /// or an expired pair, the logs held in `logs` are read even once retired
+    fn read_value(
+        &self,
+        key: &[u8],
+        fo: FileOffset,
+        view: View,
+        logs: &BTreeMap<u64, Arc<LogFile>>,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .read_record(key, fo, view, Some(logs))?
+            .map(|kv| kv.value))
+    }
+
+    /// the last record of `key`, `None` if it is removed or expired
+    fn read_live(&self, key: &[u8]) -> Result<Option<KV>> {
+        match self.index.get(key) {
+            Some(entry) => self.read_record(key, entry.value().load(), View::latest(), None),
+            None => Ok(None),
+        }
+    }
+
+    /// read the record of `key` at `fo`, `None` for a tombstone or a pair
+    /// expired in `view`, from the logs `held` by the reader if it has some
+    fn read_record(
+        &self,
+        key: &[u8],
+        mut fo: FileOffset,
+        view: View,
+        held: Option<&BTreeMap<u64, Arc<LogFile>>>,
+    ) -> Result<Option<KV>> {
+        loop {
+            // compaction drops the tombstones and the expired pairs nobody
+            // sees, there is nothing to read for them
+            if fo.live_version(view.now) == 0 {
+                return Ok(None);
+            }
+            // compaction swaps the index and the kept records before it retires
+            // a log, so if the log is gone the key has moved, look it up again
+            let held = held.and_then(|logs| logs.get(&fo.file)).cloned();
+            let log = match held.or_else(|| self.files.read().unwrap().get(&fo.file).cloned()) {
+                Some(log) => log,
+                None => {
+                    let moved = match self.lookup(key, view) {
+                        Some(moved) => moved,
+                        None => return Ok(None),
+                    };
+                    // a record left behind in a retired log is lost
+                    if moved == fo {
+                        return Err(KvsError::ErrCorrupted {
+                            path: self.names.log_path(fo.file).display().to_string(),
+                            offset: fo.offset,
+                            reason: "the record is in a log compacted away".to_owned(),
+                        });
+                    }
+                    fo = moved;
+                    continue;
                 }
-            } else {
-                self.index.remove(key);
+            };
+            let kv = log.read_kv(fo.offset)?;
+            // expired pairs are dropped by the next compaction
+            if kv.version == 0 || kv.is_expired(view.now) {
+                return Ok(None);
             }
+            return Ok(Some(kv));
         }
+    }
 
-        // 这里记录下当前有读者仍然在读的文件，不能删除这些 sst，因为读者还在读
-        // 从现在开始，已经更新了索引，现在再来读者也是去新的 sst 里面读，因此只要等到老的读者读完了，就可以安全的删除了
-        // 可以将 过期的 log 保存起来，下次 compaction 的时候再删除即可
-        while self.reader_count.load(Ordering::SeqCst) > 0 {}
-
-        // 删除旧的 sst
-        for filename in &old_files {
-            let file = current_dir.clone().join(filename.clone());
-            fs::remove_file(file).unwrap();
-        }
-    }
-
-    fn get_value_by_file_index(
-        current_dir: PathBuf,
-        file_idx: u64,
-        offset: u64,
-    ) -> Result<Option<String>> {
-        let filename = format!("log_{}", file_idx);
-        let filename = current_dir.clone().join(filename);
-        let mut reader = fs::OpenOptions::new().read(true).open(filename).unwrap();
-        reader.seek(SeekFrom::Start(offset)).unwrap();
-        let mut meta_buffer: [u8; 4] = [0; 4]; // 8 byte
-        reader.read(&mut meta_buffer).unwrap();
-        let key_len = u32::from_be_bytes(meta_buffer);
-        let data = read_n(&mut reader, key_len as u64);
-        let kv: KV = serde_json::from_slice(&data).unwrap();
-        if kv.version == 0 {
-            return Ok(None);
-        } else {
-            return Ok(Some(kv.value));
+    /// whether enough records are stale to compact the logs
+    fn garbage_reached(&self) -> bool {
+        garbage_reached(&self.options, &self.garbage)
+    }
+
+    /// apply the sync policy after `bytes` were appended to the active log
+    fn sync_written(&self, write_handler: &File, bytes: u64) -> Result<()> {
+        let mut sync_state = self.sync_state.lock().unwrap();
+        if sync_state.written(bytes) {
+            write_handler.sync_data()?;
+            sync_state.synced();
         }
+        Ok(())
     }
 
     /// Open the KvStore at a given path. Return the KvStore.
+    ///
+    /// A torn or corrupted record at the end of the active log is cut off
+    /// together with everything after it, see `discarded_bytes`.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, EngineOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: EngineOptions) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::from(options))
+    }
+
+    /// Open the KvStore at a given path with its full set of options.
+    ///
+    /// The options are not stored, a store must be reopened with the same
+    /// file prefix to find its logs again.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         // 应该根据传入的 目录，保存这个路径, 并且在之后进行读取每个文件
         let path: PathBuf = path.into();
+        let names = LogNames::new(&path, &options.file_prefix);
 
-        own_dir_or_not(path.clone(), "kvs");
-
-        let mut sst_files = get_sst_from_dir_with_prefix(path.clone(), "log".to_owned());
-        if sst_files.is_empty() {
-            sst_files.push("log_1".to_owned());
-        }
+        let lock = Arc::new(lock_dir(&path, "kvs", LOG_FORMAT_VERSION)?);
+        remove_unfinished_compactions(&names)?;
 
-        let write_file = sst_files.last().cloned().unwrap();
-        let write_file_path = path.join(write_file.clone());
+        // recover the index before appending anything to the active log
+        let index = Arc::new(SkipMap::new());
+        let files = Arc::new(RwLock::new(BTreeMap::new()));
+        let garbage = Arc::new(Garbage::default());
+        let discarded_bytes = read_all_index(
+            &names,
+            options.read_buffer_size,
+            &index,
+            &mut files.write().unwrap(),
+            &garbage,
+        )?;
 
-        let pos = write_file.find("_").unwrap();
-        let file_idx = write_file[(pos + 1)..].parse::<u64>().unwrap();
+        let mut file_idx = names.log_indexes()?.last().cloned().unwrap_or(1);
 
-        let write_handler = get_write_file_handler(write_file_path);
-        let store = KvStore {
-            index: Arc::new(DashMap::new()),
+        // only append to a log of the current format, otherwise start a new log,
+        // the old one will be upgraded by the next compaction
+        match log_format(&names.log_path(file_idx))? {
+            Some(LogFormat::Binary(LOG_FORMAT_VERSION)) | None => {}
+            Some(_) => file_idx += 1,
+        }
+        let write_handler = new_active_log(&names, &files, file_idx, options.read_buffer_size)?;
+        let options = Arc::new(options);
+        let compactor = Compactor {
+            names: names.clone(),
+            options: options.clone(),
+            index,
+            files,
             write_handler: Arc::new(RwLock::new(write_handler)),
-            reader_count: Arc::new(AtomicU32::new(0)),
             writer_index: Arc::new(RwLock::new(file_idx)),
-            current_dir: path,
-            uncompacted: Arc::new(AtomicCell::new(0)),
+            garbage,
+            versions: Arc::new(Versions::default()),
+            sync_state: Arc::new(Mutex::new(SyncState::new(options.sync_policy))),
+        };
+        let store = KvStore {
+            index: compactor.index.clone(),
+            write_handler: compactor.write_handler.clone(),
+            files: compactor.files.clone(),
+            writer_index: compactor.writer_index.clone(),
+            names,
+            options: options.clone(),
+            garbage: compactor.garbage.clone(),
+            versions: compactor.versions.clone(),
+            sync_state: compactor.sync_state.clone(),
+            compaction: Arc::new(CompactionWorker::spawn(compactor)),
+            discarded_bytes,
+            _lock: lock,
         };
-        store.init();
+        if let SyncPolicy::Periodic { interval, .. } = options.sync_policy {
+            spawn_flusher(
+                Arc::downgrade(&store.write_handler),
+                Arc::downgrade(&store.sync_state),
+                interval,
+            );
+        }
         Ok(store)
     }
 
     /// init kvstore, read all index into memory
-    pub fn init(&self) {
-        self.read_all_index();
+    pub fn init(&self) -> Result<()> {
+        read_all_index(
+            &self.names,
+            self.options.read_buffer_size,
+            &self.index,
+            &mut self.files.write().unwrap(),
+            &self.garbage,
+        )?;
+        Ok(())
+    }
+
+    /// The number of bytes cut off from the tail of the active log when the
+    /// store was opened, because the last records were torn or corrupted.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+}
+
+/// Rewrites the live records of the sealed logs into a new log in the
+/// background, while `set` keeps appending to the active log.
+struct Compactor {
+    names: LogNames,
+    options: Arc<KvStoreOptions>,
+    index: Arc<Index>,
+    files: Arc<LogFiles>,
+    write_handler: Arc<RwLock<File>>,
+    writer_index: Arc<RwLock<u64>>,
+    garbage: Arc<Garbage>,
+    versions: Arc<Versions>,
+    sync_state: Arc<Mutex<SyncState>>,
+}
+
+impl Compactor {
+    /// a failed compaction leaves the sealed logs in place, the next one
+    /// compacts them again
+    fn compaction(&self) -> Result<()> {
+        // the counter may have been reset by a compaction queued before this one
+        if !garbage_reached(&self.options, &self.garbage) {
+            return Ok(());
+        }
+        let read_buffer_size = self.options.read_buffer_size;
+
+        // seal the active log, every log before `compaction_idx` is compacted,
+        // `set` goes on with the log after it
+        let (compaction_idx, sealed_bytes) = {
+            let mut write_handler = self.write_handler.write().unwrap();
+            let mut writer_index = self.writer_index.write().unwrap();
+            sync_sealed(&self.sync_state, &write_handler)?;
+            let compaction_idx = *writer_index + 1;
+            *write_handler = new_active_log(
+                &self.names,
+                &self.files,
+                *writer_index + 2,
+                read_buffer_size,
+            )?;
+            *writer_index += 2;
+            // all the garbage so far is in the sealed logs
+            self.garbage.records.store(0);
+            self.garbage.bytes.store(0);
+            (compaction_idx, self.garbage.total.load())
+        };
+        let old_files: BTreeMap<u64, Arc<LogFile>> = self
+            .files
+            .read()
+            .unwrap()
+            .range(..compaction_idx)
+            .map(|(idx, log)| (*idx, log.clone()))
+            .collect();
+
+        // 保存旧的 index，并且遍历来生成新的 sst
+        // the new log is only visible under its final name once it is complete
+        let compacting_path = self.names.compacting_path(compaction_idx);
+        let mut compacting = get_write_file_handler(compacting_path.clone())?;
+        let mut moved = Vec::new();
+        let mut hints = Vec::new();
+        let mut copy = |key: &[u8], fo: FileOffset| -> Result<FileOffset> {
+            let kv = old_files[&fo.file].read_kv(fo.offset)?;
+            let offset = compacting.metadata()?.len();
+            let len = write_kv(&mut compacting, &kv)?;
+            hints.push(HintEntry {
+                key: key.to_vec(),
+                offset,
+                tombstone: kv.version == 0,
+                version: kv.version,
+                expires_at: kv.expires_at,
+            });
+            Ok(FileOffset {
+                file: compaction_idx,
+                offset,
+                len,
+                ..fo
+            })
+        };
+
+        // a writer moves the record it replaces into the history under the
+        // history lock, so holding it while both are read, every record of the
+        // old logs is in one of them. A record moved later is found in the
+        // index here, and followed into the history by the swap below.
+        let (old_history, old_index) = {
+            let history = self.versions.history.lock().unwrap();
+            let old_history: Vec<(Vec<u8>, FileOffset)> = history
+                .iter()
+                .flat_map(|(key, versions)| versions.iter().map(move |fo| (key.clone(), *fo)))
+                .filter(|(_, fo)| fo.file < compaction_idx)
+                .collect();
+            let old_index: Vec<(Vec<u8>, FileOffset)> = self
+                .index
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().load()))
+                .filter(|(_, fo)| fo.file < compaction_idx)
+                .collect();
+            (old_history, old_index)
+        };
+
+        // the records kept for the snapshots go first, replaying the new log
+        // ends with the last record of every key
+        let mut moved_history = Vec::new();
+        for (key, fo) in old_history {
+            let new_fo = copy(&key, fo)?;
+            // they are garbage again once the snapshots are dropped
+            self.garbage.records.fetch_add(1);
+            self.garbage.bytes.fetch_add(new_fo.len);
+            moved_history.push((key, fo, new_fo));
+        }
+
+        let now = now_millis();
+        for (key, fo) in old_index {
+            // 只处理仍然存在的 key，如果 key 不存在或者被删除了，那么就不需要写到新的里面去了
+            // removed and expired keys are not needed in the new log any more,
+            // unless a snapshot still sees them or a record before them
+            let seen = self.versions.pinned(fo.seq)
+                || self.versions.history.lock().unwrap().contains_key(&key);
+            if fo.live_version(now) == 0 && !seen {
+                moved.push((key, fo, None));
+            } else {
+                let new_fo = copy(&key, fo)?;
+                moved.push((key, fo, Some(new_fo)));
+            }
+        }
+        // the old logs are deleted below, the compacted one must be on disk
+        compacting.sync_data()?;
+        let compacted_len = compacting.metadata()?.len();
+        drop(compacting);
+        // a hint without its log is removed on open, so write it first
+        write_hint(&self.names.hint_path(compaction_idx), compacted_len, &hints)?;
+        fs::rename(&compacting_path, self.names.log_path(compaction_idx))?;
+        self.files.write().unwrap().insert(
+            compaction_idx,
+            Arc::new(LogFile::new(
+                &self.names,
+                compaction_idx,
+                LogFormat::Binary(LOG_FORMAT_VERSION),
+                read_buffer_size,
+            )),
+        );
+
+        // swap the index, unless `set` or `remove` wrote the key again meanwhile,
+        // then the record may have been kept for a snapshot
+        let mut removed = Vec::new();
+        {
+            let mut history = self.versions.history.lock().unwrap();
+            for (key, old_fo, new_fo) in moved {
+                match new_fo {
+                    Some(new_fo) => {
+                        if let Some(entry) = self.index.get(&key) {
+                            if entry.value().compare_exchange(old_fo, new_fo).is_err() {
+                                Versions::moved(&mut history, &key, old_fo, new_fo);
+                            }
+                        }
+                    }
+                    None => removed.push((key, old_fo)),
+                }
+            }
+            for (key, old_fo, new_fo) in moved_history {
+                Versions::moved(&mut history, &key, old_fo, new_fo);
+            }
+        }
+        // a writer must not update a tombstone between the check and its removal
+        {
+            let _write_handler = self.write_handler.write().unwrap();
+            for (key, old_fo) in removed {
+                if let Some(entry) = self.index.get(&key) {
+                    if entry.value().load() == old_fo {
+                        entry.remove();
+                    }
+                }
+            }
+        }
+
+        // 从现在开始，已经更新了索引，现在再来读者也是去新的 sst 里面读
+        // 旧的 sst 在最后一个还在读它的读者结束时删除
+        let mut files = self.files.write().unwrap();
+        for idx in old_files.keys() {
+            if let Some(log) = files.remove(idx) {
+                log.retire();
+            }
+        }
+        self.garbage.total.fetch_sub(sealed_bytes);
+        self.garbage.total.fetch_add(compacted_len);
+        Ok(())
+    }
+}
+
+/// The handle of the compaction thread, the thread is stopped and joined
+/// when the last clone of the store is dropped.
+struct CompactionWorker {
+    sender: Option<SyncSender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CompactionWorker {
+    fn spawn(compactor: Compactor) -> CompactionWorker {
+        // one pending request is enough, later ones are merged into it
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let handle = thread::spawn(move || {
+            for () in receiver {
+                if let Err(err) = compactor.compaction() {
+                    error!("compaction failed: {}", err);
+                }
+            }
+        });
+        CompactionWorker {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// ask for a compaction, without waiting for it
+    fn trigger(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(());
+        }
+    }
+}
+
+impl Drop for CompactionWorker {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
     }
+}
+
+/// hard-link `from` to `to`, or copy it if they are on different file systems
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    fs::hard_link(from, to).or_else(|_| fs::copy(from, to).map(|_| ()))
+}
 
-    fn read_all_index(&self) {
-        let current_dir = self.current_dir.clone();
+/// copy the first `len` bytes of `from` to `to`, and sync it
+fn copy_prefix(from: &Path, to: &Path, len: u64) -> io::Result<()> {
+    let mut reader = File::open(from)?.take(len);
+    let mut file = File::create(to)?;
+    let copied = io::copy(&mut reader, &mut file)?;
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "expected {} bytes in {}, read {}",
+                len,
+                from.display(),
+                copied
+            ),
+        ));
+    }
+    file.sync_all()
+}
 
-        // let mut index: HashMap<String, FileOffset> = HashMap::new();
-        let sst_files = get_sst_from_dir_with_prefix(current_dir.clone(), "log".to_owned());
-        for file in sst_files {
-            let pos = file.find("_").unwrap();
-            let file_idx = file[(pos + 1)..].parse::<u64>().unwrap();
+/// sync the active log before it is sealed and replaced
+fn sync_sealed(sync_state: &Mutex<SyncState>, write_handler: &File) -> Result<()> {
+    let mut sync_state = sync_state.lock().unwrap();
+    if sync_state.dirty() {
+        write_handler.sync_data()?;
+        sync_state.synced();
+    }
+    Ok(())
+}
 
-            let file = file.clone();
-            let current_dir = current_dir.clone();
-            let path = current_dir.join(file.clone());
+/// append encoded records to the active log, return their offset.
+/// A failed write is cut off, so that the next record follows the last
+/// complete one.
+fn append(write_handler: &mut File, records: &[u8]) -> Result<u64> {
+    let offset = write_handler.metadata()?.len();
+    match write_handler.write_all(records) {
+        Ok(()) => Ok(offset),
+        Err(err) => {
+            let _ = write_handler.set_len(offset);
+            Err(err.into())
+        }
+    }
+}
 
-            let mut read_handler = fs::OpenOptions::new().read(true).open(path).unwrap();
+/// whether enough records are stale to compact the logs
+fn garbage_reached(options: &KvStoreOptions, garbage: &Garbage) -> bool {
+    options.compaction_trigger.reached(
+        garbage.records.load(),
+        garbage.bytes.load(),
+        garbage.total.load(),
+    )
+}
 
-            let mut offset = 0;
-            loop {
-                let mut meta_buffer: [u8; 4] = [0; 4];
-                read_handler.read(&mut meta_buffer).unwrap();
-                let key_len = u32::from_be_bytes(meta_buffer);
-                if key_len == 0 {
-                    break;
+/// remove the output of compactions interrupted by a crash, the logs they
+/// were compacting are still there
+fn remove_unfinished_compactions(names: &LogNames) -> Result<()> {
+    for idx in names.compacting_indexes()? {
+        fs::remove_file(names.compacting_path(idx))?;
+    }
+    for idx in names.hint_indexes()? {
+        if !names.log_path(idx).exists() {
+            fs::remove_file(names.hint_path(idx))?;
+        }
+    }
+    Ok(())
+}
+
+/// replay all logs into the index and the file set, return the bytes
+/// discarded from the tail of the active log
+fn read_all_index(
+    names: &LogNames,
+    read_buffer_size: usize,
+    index: &Index,
+    files: &mut BTreeMap<u64, Arc<LogFile>>,
+    garbage: &Garbage,
+) -> Result<u64> {
+    let log_indexes = names.log_indexes()?;
+    let mut discarded = 0;
+    for (i, &file_idx) in log_indexes.iter().enumerate() {
+        let is_active = i + 1 == log_indexes.len();
+
+        let path = names.log_path(file_idx);
+        let read_handler = fs::OpenOptions::new().read(true).open(&path)?;
+        let file_len = read_handler.metadata()?.len();
+        let mut reader = BufReader::with_capacity(read_buffer_size, read_handler);
+
+        let format = match read_log_format(&mut reader).map_err(|err| read_error(&path, 0, err))? {
+            Some(format) => format,
+            None => continue,
+        };
+        files.insert(
+            file_idx,
+            Arc::new(LogFile::new(names, file_idx, format, read_buffer_size)),
+        );
+
+        // a sealed log with a valid hint does not need to be replayed
+        if !is_active {
+            if let Some(hints) = read_hint(&names.hint_path(file_idx), file_len) {
+                // the records of a log follow each other
+                let ends: Vec<u64> = hints
+                    .iter()
+                    .skip(1)
+                    .map(|hint| hint.offset)
+                    .chain(Some(file_len))
+                    .collect();
+                for (hint, end) in hints.into_iter().zip(ends) {
+                    let len = end - hint.offset;
+                    let old = index_insert(
+                        index,
+                        hint.key,
+                        FileOffset {
+                            file: file_idx,
+                            offset: hint.offset,
+                            len,
+                            version: hint.version,
+                            expires_at: hint.expires_at,
+                            seq: 0,
+                        },
+                    );
+                    garbage.written(len, old);
+                }
+                continue;
+            }
+        }
+
+        let mut offset = reader.stream_position()?;
+        // the write batch being read, indexed once its commit record is read
+        let mut batch: Option<PendingBatch> = None;
+        let damage = loop {
+            let (record, len) = match read_record(&mut reader, format) {
+                Ok(Some(read)) => read,
+                Ok(None) if batch.is_some() => {
+                    break Some(invalid_batch("uncommitted write batch"))
                 }
-                let data = read_n(&mut read_handler, key_len as u64);
-                let kv: KV = serde_json::from_slice(&data).unwrap();
-                self.index.insert(
-                    kv.key,
-                    FileOffset {
+                Ok(None) => break None,
+                Err(err) => break Some(err),
+            };
+            match record {
+                Record::Kv(kv) => {
+                    let fo = FileOffset {
                         file: file_idx,
-                        offset: offset,
-                    },
-                );
-                offset += 4 + key_len as u64;
+                        offset,
+                        len,
+                        version: kv.version,
+                        expires_at: kv.expires_at,
+                        seq: 0,
+                    };
+                    match batch {
+                        Some(ref mut batch) => batch.records.push((kv.key, fo)),
+                        None => {
+                            let old = index_insert(index, kv.key, fo);
+                            garbage.written(len, old);
+                        }
+                    }
+                }
+                Record::BatchBegin(_) if batch.is_some() => {
+                    break Some(invalid_batch("write batch inside a write batch"))
+                }
+                Record::BatchBegin(count) => {
+                    batch = Some(PendingBatch {
+                        offset,
+                        count,
+                        markers: len,
+                        records: Vec::new(),
+                    })
+                }
+                Record::BatchCommit => match batch.take() {
+                    Some(committed) if committed.records.len() == committed.count as usize => {
+                        for (key, fo) in committed.records {
+                            let old = index_insert(index, key, fo);
+                            garbage.written(fo.len, old);
+                        }
+                        garbage.stale(committed.markers + len);
+                    }
+                    uncommitted => {
+                        batch = uncommitted;
+                        break Some(invalid_batch("write batch commit without its records"));
+                    }
+                },
+            }
+            offset += len;
+        };
+
+        if let Some(err) = damage {
+            // an uncommitted batch is dropped as a whole
+            let offset = batch.map_or(offset, |batch| batch.offset);
+            // only the active log can be torn by a crash, a damaged sealed log
+            // is not something we can repair here
+            if !is_active {
+                return Err(read_error(&path, offset, err));
             }
+            discarded = file_len - offset;
+            warn!(
+                "{} is damaged at offset {}: {}, discard the last {} bytes",
+                path.display(),
+                offset,
+                err,
+                discarded
+            );
+            let file = fs::OpenOptions::new().write(true).open(&path)?;
+            file.set_len(offset)?;
+            file.sync_all()?;
         }
     }
+    Ok(discarded)
 }
 
-fn get_write_file_handler(path: PathBuf) -> File {
-    fs::OpenOptions::new()
+/// a write batch read from a log, up to its commit record
+struct PendingBatch {
+    offset: u64,
+    count: u32,
+    markers: u64, // the size of its begin and commit records
+    records: Vec<(Vec<u8>, FileOffset)>,
+}
+
+fn invalid_batch(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// sync the writes left behind by a periodic policy once they are overdue,
+/// until the store is dropped
+fn spawn_flusher(
+    write_handler: Weak<RwLock<File>>,
+    sync_state: Weak<Mutex<SyncState>>,
+    interval: Duration,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let (write_handler, sync_state) = match (write_handler.upgrade(), sync_state.upgrade()) {
+            (Some(write_handler), Some(sync_state)) => (write_handler, sync_state),
+            _ => break,
+        };
+        let write_handler = write_handler.read().unwrap();
+        let mut sync_state = sync_state.lock().unwrap();
+        if sync_state.overdue() {
+            match write_handler.sync_data() {
+                Ok(()) => sync_state.synced(),
+                // keep the writes overdue, the next round tries again
+                Err(err) => error!("can not sync the active log: {}", err),
+            }
+        }
+    });
+}
+
+/// open the log at `file_idx` for appending, and add it to the file set
+fn new_active_log(
+    names: &LogNames,
+    files: &LogFiles,
+    file_idx: u64,
+    read_buffer_size: usize,
+) -> Result<File> {
+    let write_handler = get_write_file_handler(names.log_path(file_idx))?;
+    files.write().unwrap().insert(
+        file_idx,
+        Arc::new(LogFile::new(
+            names,
+            file_idx,
+            LogFormat::Binary(LOG_FORMAT_VERSION),
+            read_buffer_size,
+        )),
+    );
+    Ok(write_handler)
+}
+
+/// the format of an existing log file, `None` if it is empty
+fn log_format(path: &Path) -> Result<Option<LogFormat>> {
+    let file = match fs::OpenOptions::new().read(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    read_log_format(&mut BufReader::new(file)).map_err(|err| read_error(path, 0, err))
+}
+
+fn get_write_file_handler(path: PathBuf) -> Result<File> {
+    let mut file = fs::OpenOptions::new()
         .read(true)
-        .write(true)
         .create(true)
         .append(true)
-        .open(path)
-        .unwrap_or_else(|err| {
-            panic!("can not open the path : {}", err);
-        })
+        .open(path)?;
+    // a new log file starts with the binary format header
+    if file.metadata()?.len() == 0 {
+        write_log_header(&mut file)?;
+    }
+    Ok(file)
 }
@@ -0,0 +1,179 @@
+use log::error;
+use std::fs::{self, read_dir};
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::record::{read_kv, LogFormat};
+use super::util::KV;
+use crate::{KvsError, Result};
+
+/// A log file of the `KvStore`, shared with the readers using it.
+///
+/// Compaction retires the logs it has rewritten by removing them from the
+/// store's file set. A retired log is deleted from the disk as soon as the
+/// last reader holding it is done, so a reader never finds its file gone.
+pub struct LogFile {
+    path: PathBuf,
+    hint_path: PathBuf,
+    format: LogFormat,
+    read_buffer_size: usize,
+    retired: AtomicBool,
+}
+
+impl LogFile {
+    /// the log `file_idx`, with records in the given format
+    pub fn new(
+        names: &LogNames,
+        file_idx: u64,
+        format: LogFormat,
+        read_buffer_size: usize,
+    ) -> LogFile {
+        LogFile {
+            path: names.log_path(file_idx),
+            hint_path: names.hint_path(file_idx),
+            format,
+            read_buffer_size,
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    /// read the kv at `offset`
+    pub fn read_kv(&self, offset: u64) -> Result<KV> {
+        let file = fs::OpenOptions::new().read(true).open(&self.path)?;
+        let mut reader = BufReader::with_capacity(self.read_buffer_size, file);
+        reader.seek(SeekFrom::Start(offset))?;
+        match read_kv(&mut reader, self.format) {
+            Ok(Some((kv, _))) => Ok(kv),
+            // the index only points at records
+            Ok(None) => Err(KvsError::ErrCorrupted {
+                path: self.path.display().to_string(),
+                offset,
+                reason: "no record at an indexed offset".to_owned(),
+            }),
+            Err(err) => Err(read_error(&self.path, offset, err)),
+        }
+    }
+
+    /// delete the file, and its hint, once nobody uses it any more
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        if self.retired.load(Ordering::SeqCst) {
+            if let Err(err) = fs::remove_file(&self.path) {
+                error!("can not remove {}: {}", self.path.display(), err);
+            }
+            if self.hint_path.exists() {
+                if let Err(err) = fs::remove_file(&self.hint_path) {
+                    error!("can not remove {}: {}", self.hint_path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+/// The names of the files of a `KvStore` in its directory.
+///
+/// The log `N` is `<prefix>_N`, its hint is `<prefix>_N.hint`, and while
+/// it is written by a compaction it is `<prefix>_N.compact`.
+#[derive(Clone)]
+pub struct LogNames {
+    dir: PathBuf,
+    prefix: String,
+}
+
+impl LogNames {
+    pub fn new(dir: &Path, prefix: &str) -> LogNames {
+        LogNames {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_owned(),
+        }
+    }
+
+    /// the path of the log `file_idx`
+    pub fn log_path(&self, file_idx: u64) -> PathBuf {
+        self.dir.join(format!("{}_{}", self.prefix, file_idx))
+    }
+
+    /// the path of the hint of the log `file_idx`
+    pub fn hint_path(&self, file_idx: u64) -> PathBuf {
+        self.dir.join(format!("{}_{}.hint", self.prefix, file_idx))
+    }
+
+    /// the path of the log `file_idx` while a compaction writes it
+    pub fn compacting_path(&self, file_idx: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}.compact", self.prefix, file_idx))
+    }
+
+    /// the indexes of the logs in the directory, in order
+    pub fn log_indexes(&self) -> Result<Vec<u64>> {
+        self.indexes_with_suffix("")
+    }
+
+    /// the indexes of the hints in the directory, in order
+    pub fn hint_indexes(&self) -> Result<Vec<u64>> {
+        self.indexes_with_suffix(".hint")
+    }
+
+    /// the indexes of the compaction outputs in the directory, in order
+    pub fn compacting_indexes(&self) -> Result<Vec<u64>> {
+        self.indexes_with_suffix(".compact")
+    }
+
+    /// the indexes `N` of the files named `<prefix>_N<suffix>`, an error if
+    /// one is not named the way the store names it, like `log_01`: it may be
+    /// a second file for the same index, `FsckReport` tells
+    fn indexes_with_suffix(&self, suffix: &str) -> Result<Vec<u64>> {
+        let mut indexes = Vec::new();
+        for (idx, path) in self.files_with_suffix(suffix)? {
+            if path != self.dir.join(format!("{}_{}{}", self.prefix, idx, suffix)) {
+                return Err(KvsError::ErrIo(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a name of the log {}", path.display(), idx),
+                )));
+            }
+            indexes.push(idx);
+        }
+        Ok(indexes)
+    }
+
+    /// the files named `<prefix>_N<suffix>` with their index `N`, in order,
+    /// `log_1` and `log_01` are both the log 1
+    pub fn files_with_suffix(&self, suffix: &str) -> Result<Vec<(u64, PathBuf)>> {
+        let prefix = format!("{}_", self.prefix);
+        let mut files = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let name = match entry?.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let idx = name
+                .strip_prefix(&prefix)
+                .and_then(|name| name.strip_suffix(suffix))
+                .and_then(|idx| idx.parse::<u64>().ok());
+            if let Some(idx) = idx {
+                files.push((idx, self.dir.join(name)));
+            }
+        }
+        files.sort_unstable();
+        Ok(files)
+    }
+}
+
+/// turn an error reading the record at `offset` of `path` into a `KvsError`,
+/// invalid or truncated data means the file is corrupted
+pub fn read_error(path: &Path, offset: u64, err: io::Error) -> KvsError {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => KvsError::ErrCorrupted {
+            path: path.display().to_string(),
+            offset,
+            reason: err.to_string(),
+        },
+        _ => KvsError::ErrIo(err),
+    }
+}
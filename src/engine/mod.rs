@@ -1,19 +1,113 @@
 pub use self::sled::SledStore;
+pub use batch::{BatchOp, WriteBatch};
+pub use fsck::{CorruptRange, FsckReport, LogReport};
+pub use inspect::{KeyHistory, LogEntry, LogInspector, LogRecords, LogSummary};
 pub use kvstore::KvStore;
-pub use util::KV;
+pub use options::{CompactionTrigger, EngineOptions, KvStoreOptions, SyncPolicy};
+pub use record::{LogFormat, Record};
+pub use snapshot::Snapshot;
+pub use transaction::{KvStoreTransaction, SledTransaction, Transaction};
+pub use util::{now_millis, KV};
 
 use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// key/value pairs in key order, as returned by a scan
+pub type KvPairs<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// What a key must be for `KvsEngine::set_if` to set it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// the key does not exist
+    Absent,
+    /// the key holds this value
+    Equals(Vec<u8>),
+    /// the key is at this version, 0 if it does not exist
+    Version(u32),
+}
 
 /// KvsEngine
+///
+/// Keys and values are arbitrary bytes, anything turning into a `Vec<u8>`
+/// can be passed, like a `String` or a `&[u8]`.
+///
+/// A key set with a time-to-live is gone once it expires, as if it had been
+/// removed.
+///
+/// Every set of a key bumps its version, the first one sets it to 1 and a key
+/// that does not exist is at version 0.
 pub trait KvsEngine: Clone + Send + 'static {
-    /// set kv pair
-    fn set(&self, key: String, value: String) -> Result<()>;
+    /// the transactions of the engine
+    type Transaction: Transaction;
+
+    /// set kv pair, it never expires
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    /// set kv pair, it expires once `ttl` has passed
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// set kv pair only if `condition` holds, `ErrConditionFailed` otherwise,
+    /// it expires once `ttl` has passed if given
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+        ttl: Option<Duration>,
+    ) -> Result<()>;
     /// get kv pair
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+    /// get kv pair together with its version
+    fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u32)>>;
     /// remove kv pair
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+    /// apply all the writes of `batch` or none of them
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// the time left before `key` expires, `None` if it never does,
+    /// `ErrKeyNotFound` if the key does not exist
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>>;
+    /// make `key` never expire, `ErrKeyNotFound` if the key does not exist
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+    /// the pairs with a key in `range`, in key order, at most `limit` of them
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvPairs<'_>>;
+    /// the pairs with a key starting with `prefix`, in key order, at most `limit` of them
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>, limit: Option<usize>) -> Result<KvPairs<'_>> {
+        let prefix = prefix.into();
+        let pairs = self.scan(prefix.clone().., None)?;
+        Ok(with_prefix(pairs, prefix, limit))
+    }
+    /// start an optimistic transaction, its writes are applied when it
+    /// commits unless a key it read was written since
+    fn begin_transaction(&self) -> Result<Self::Transaction>;
+    /// write a consistent copy of the store to `dest`, which must be missing
+    /// or empty, the copy opens as a store of the same engine
+    fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()>;
+}
+
+/// the pairs of a scan from `prefix` on, up to the first key without it
+fn with_prefix(pairs: KvPairs<'_>, prefix: Vec<u8>, limit: Option<usize>) -> KvPairs<'_> {
+    let pairs = pairs.take_while(move |pair| match pair {
+        Ok((key, _)) => key.starts_with(&prefix),
+        Err(_) => true,
+    });
+    Box::new(pairs.take(limit.unwrap_or(usize::MAX)))
 }
 
+mod batch;
+mod fsck;
+mod hint;
+mod inspect;
 mod kvstore;
+mod log_file;
+mod options;
+mod record;
 mod sled;
+mod snapshot;
+mod transaction;
 mod util;
@@ -0,0 +1,201 @@
+use std::time::{Duration, Instant};
+
+/// When an engine forces its writes down to the disk.
+///
+/// The same policy gives the same guarantee whichever engine is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncPolicy {
+    /// never sync explicitly, writes reach the disk whenever the OS or the
+    /// engine decides to flush them
+    #[default]
+    Never,
+    /// sync after every write, nothing acknowledged is lost on power failure
+    Always,
+    /// group commit: sync once `interval` has passed or `bytes` have been
+    /// written since the last sync, whichever comes first
+    Periodic {
+        /// max time between two syncs
+        interval: Duration,
+        /// max bytes written between two syncs
+        bytes: u64,
+    },
+}
+
+/// Options accepted by `KvStore::open_with` and `SledStore::open_with`
+///
+/// ```
+/// use kvs::{EngineOptions, KvStore, KvsEngine, SyncPolicy};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let options = EngineOptions {
+///     sync_policy: SyncPolicy::Always,
+/// };
+/// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EngineOptions {
+    /// durability of writes
+    pub sync_policy: SyncPolicy,
+}
+
+/// tracks the writes not synced yet, to apply a `SyncPolicy`
+pub(crate) struct SyncState {
+    policy: SyncPolicy,
+    unsynced_bytes: u64,
+    last_sync: Instant,
+}
+
+impl SyncState {
+    pub fn new(policy: SyncPolicy) -> SyncState {
+        SyncState {
+            policy,
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// record a write of `bytes`, return whether it should be synced now
+    pub fn written(&mut self, bytes: u64) -> bool {
+        self.unsynced_bytes += bytes;
+        match self.policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Periodic { interval, bytes } => {
+                self.unsynced_bytes >= bytes || self.last_sync.elapsed() >= interval
+            }
+        }
+    }
+
+    /// whether some writes are not synced yet, and the policy cares about it
+    pub fn dirty(&self) -> bool {
+        self.policy != SyncPolicy::Never && self.unsynced_bytes > 0
+    }
+
+    /// whether the periodic flusher has anything overdue to sync
+    pub fn overdue(&self) -> bool {
+        match self.policy {
+            SyncPolicy::Periodic { interval, .. } => {
+                self.unsynced_bytes > 0 && self.last_sync.elapsed() >= interval
+            }
+            _ => false,
+        }
+    }
+
+    /// everything written so far is on the disk
+    pub fn synced(&mut self) {
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+    }
+}
+
+/// When the `KvStore` compacts its logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionTrigger {
+    /// compact once this many records have been overwritten or removed
+    StaleRecords(u64),
+    /// compact once the overwritten and removed records take at least
+    /// `ratio` of the logs, and at least `min_bytes`
+    StaleRatio {
+        /// share of the log bytes that are stale, between 0 and 1
+        ratio: f64,
+        /// stale bytes below which compaction is not worth it
+        min_bytes: u64,
+    },
+}
+
+impl CompactionTrigger {
+    /// whether the stale records are enough to compact the logs
+    pub(crate) fn reached(&self, stale_records: u64, stale_bytes: u64, total_bytes: u64) -> bool {
+        match *self {
+            CompactionTrigger::StaleRecords(records) => stale_records > records,
+            CompactionTrigger::StaleRatio { ratio, min_bytes } => {
+                stale_bytes >= min_bytes && stale_bytes as f64 >= ratio * total_bytes as f64
+            }
+        }
+    }
+}
+
+/// Options accepted by `KvStore::open_with_options`
+///
+/// ```
+/// use kvs::{CompactionTrigger, KvStore, KvStoreOptions, KvsEngine, SyncPolicy};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let options = KvStoreOptions::new()
+///     .max_file_size(4 << 20)
+///     .compaction_trigger(CompactionTrigger::StaleRecords(1000))
+///     .file_prefix("data")
+///     .sync_policy(SyncPolicy::Always);
+/// let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// assert!(temp_dir.path().join("data_1").exists());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct KvStoreOptions {
+    pub(crate) max_file_size: u64,
+    pub(crate) compaction_trigger: CompactionTrigger,
+    pub(crate) file_prefix: String,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) sync_policy: SyncPolicy,
+}
+
+impl KvStoreOptions {
+    /// the default options: 64 MiB logs, compacted once half of them is stale
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions {
+            max_file_size: 64 << 20,
+            compaction_trigger: CompactionTrigger::StaleRatio {
+                ratio: 0.5,
+                min_bytes: 1 << 20,
+            },
+            file_prefix: "log".to_owned(),
+            read_buffer_size: 8 << 10,
+            sync_policy: SyncPolicy::default(),
+        }
+    }
+
+    /// the size in bytes after which the active log is sealed and a new one started
+    pub fn max_file_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// when the logs are compacted
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> KvStoreOptions {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    /// the logs are named `<prefix>_<n>`
+    pub fn file_prefix(mut self, prefix: impl Into<String>) -> KvStoreOptions {
+        self.file_prefix = prefix.into();
+        self
+    }
+
+    /// the buffer size used to read the logs
+    pub fn read_buffer_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// durability of writes
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = policy;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions::new()
+    }
+}
+
+impl From<EngineOptions> for KvStoreOptions {
+    fn from(options: EngineOptions) -> KvStoreOptions {
+        KvStoreOptions::new().sync_policy(options.sync_policy)
+    }
+}
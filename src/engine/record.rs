@@ -0,0 +1,351 @@
+use crc32fast::Hasher;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::util::KV;
+use crate::io::read_n;
+
+/// magic bytes at the beginning of every binary log file
+const LOG_MAGIC: [u8; 4] = *b"KVSL";
+/// current on-disk format version of binary log files
+pub const LOG_FORMAT_VERSION: u32 = 5;
+/// magic + format version
+pub const LOG_HEADER_LEN: u64 = 8;
+/// record type + key len + value len
+const RECORD_HEADER_LEN: usize = 9;
+/// crc32 of the rest of the record, since format version 2
+const RECORD_CRC_LEN: usize = 4;
+/// version of a put, since format version 4
+const RECORD_VERSION_LEN: usize = 4;
+/// expiry time of an expiring put, since format version 3
+const RECORD_EXPIRY_LEN: usize = 8;
+
+const RECORD_PUT: u8 = 1;
+const RECORD_DELETE: u8 = 2;
+const RECORD_PUT_EXPIRING: u8 = 3;
+const RECORD_BATCH_BEGIN: u8 = 4;
+const RECORD_BATCH_COMMIT: u8 = 5;
+
+/// The layout of a log file.
+///
+/// Old stores wrote every record as a `u32` length prefix followed by the
+/// json encoding of `KV`, without any file header. Binary logs start with
+/// `LOG_MAGIC` and a format version, and every record is laid out as
+///
+/// ```text
+/// | crc: u32 | type: u8 | key len: u32 | value len: u32 | key | value |
+/// ```
+///
+/// where `crc` covers everything after it. Version 1 records have no crc.
+/// Since version 3, a put with a time-to-live has its own type, and its
+/// expiry time in milliseconds since the unix epoch comes before the key:
+///
+/// ```text
+/// | crc: u32 | type: u8 | key len: u32 | value len: u32 | expires at: u64 | key | value |
+/// ```
+///
+/// Since version 4, every put carries the version of the key it sets, before
+/// the expiry time if any. Puts of older logs are at version 1.
+///
+/// ```text
+/// | crc: u32 | type: u8 | key len: u32 | value len: u32 | version: u32 | key | value |
+/// ```
+///
+/// Since version 5, the records of a write batch are framed by a begin record,
+/// holding their count as a `u32` value, and a commit record. Both have no key.
+/// The records of a batch without its commit record are not applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// legacy `serde_json` records
+    Json,
+    /// versioned binary records
+    Binary(u32),
+}
+
+/// write the header of a new binary log file
+pub fn write_log_header(file: &mut File) -> io::Result<()> {
+    let mut header = Vec::with_capacity(LOG_HEADER_LEN as usize);
+    header.extend_from_slice(&LOG_MAGIC);
+    header.extend_from_slice(&LOG_FORMAT_VERSION.to_be_bytes());
+    file.write_all(&header)
+}
+
+/// detect the format of a log file, and seek to its first record.
+/// Return `None` if the file is empty.
+pub fn read_log_format<R: Read + Seek>(reader: &mut R) -> io::Result<Option<LogFormat>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0; LOG_HEADER_LEN as usize];
+    let n = read_full(reader, &mut header)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n == header.len() && header[..4] == LOG_MAGIC {
+        let mut version = [0; 4];
+        version.copy_from_slice(&header[4..]);
+        let version = u32::from_be_bytes(version);
+        if version > LOG_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported log format version {}", version),
+            ));
+        }
+        return Ok(Some(LogFormat::Binary(version)));
+    }
+    // json logs have no header, the first record starts at 0
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(Some(LogFormat::Json))
+}
+
+/// A record of a binary log
+pub enum Record {
+    /// a put or a tombstone
+    Kv(KV),
+    /// the start of a write batch of this many records
+    BatchBegin(u32),
+    /// the end of a write batch, its records are applied
+    BatchCommit,
+}
+
+/// encode a kv into a binary record
+pub fn encode_kv(kv: &KV) -> Vec<u8> {
+    let (record_type, expires_at) = match (kv.version, kv.expires_at) {
+        (0, _) => (RECORD_DELETE, None),
+        (_, None) => (RECORD_PUT, None),
+        (_, Some(expires_at)) => (RECORD_PUT_EXPIRING, Some(expires_at)),
+    };
+    let mut meta = Vec::with_capacity(RECORD_VERSION_LEN + RECORD_EXPIRY_LEN);
+    if record_type != RECORD_DELETE {
+        meta.extend_from_slice(&kv.version.to_be_bytes());
+    }
+    if let Some(expires_at) = expires_at {
+        meta.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    encode_record(record_type, &meta, &kv.key, &kv.value)
+}
+
+/// encode the record starting a write batch of `len` records
+pub fn encode_batch_begin(len: u32) -> Vec<u8> {
+    encode_record(RECORD_BATCH_BEGIN, &[], &[], &len.to_be_bytes())
+}
+
+/// encode the record committing a write batch
+pub fn encode_batch_commit() -> Vec<u8> {
+    encode_record(RECORD_BATCH_COMMIT, &[], &[], &[])
+}
+
+fn encode_record(record_type: u8, meta: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(
+        RECORD_CRC_LEN + RECORD_HEADER_LEN + meta.len() + key.len() + value.len(),
+    );
+    buf.extend_from_slice(&[0; RECORD_CRC_LEN]);
+    buf.push(record_type);
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(meta);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = checksum(&[&buf[RECORD_CRC_LEN..]]);
+    buf[..RECORD_CRC_LEN].copy_from_slice(&crc.to_be_bytes());
+    buf
+}
+
+/// append a kv to the log file, return the written length
+pub fn write_kv<W: Write>(writer: &mut W, kv: &KV) -> io::Result<u64> {
+    let buf = encode_kv(kv);
+    writer.write_all(&buf)?;
+    Ok(buf.len() as u64)
+}
+
+/// read the next kv of a log file, return it with its length on disk.
+/// Return `None` at the end of the file.
+///
+/// A record cut off by the end of the file is reported as
+/// `ErrorKind::UnexpectedEof`, a damaged one as `ErrorKind::InvalidData`.
+pub fn read_kv<R: Read>(reader: &mut R, format: LogFormat) -> io::Result<Option<(KV, u64)>> {
+    match read_record(reader, format)? {
+        Some((Record::Kv(kv), len)) => Ok(Some((kv, len))),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "batch record where a kv was expected",
+        )),
+        None => Ok(None),
+    }
+}
+
+/// read the next record of a log file, like `read_kv`
+pub fn read_record<R: Read>(
+    reader: &mut R,
+    format: LogFormat,
+) -> io::Result<Option<(Record, u64)>> {
+    match format {
+        LogFormat::Json => Ok(read_json_kv(reader)?.map(|(kv, len)| (Record::Kv(kv), len))),
+        LogFormat::Binary(version) => read_binary_record(reader, version),
+    }
+}
+
+/// read the record at the start of `data`, like `read_record`, without
+/// trusting the lengths it declares: a record said to be longer than `data`
+/// is reported as cut off before anything is allocated for it
+pub fn parse_record(data: &[u8], format: LogFormat) -> io::Result<Option<(Record, u64)>> {
+    let declared = |at: usize| {
+        data.get(at..at + 4).map_or(0, |len| {
+            u32::from_be_bytes(len.try_into().unwrap()) as usize
+        })
+    };
+    let min_len = match format {
+        LogFormat::Json => 4 + declared(0),
+        LogFormat::Binary(version) => {
+            let header = crc_len(version >= 2);
+            header + RECORD_HEADER_LEN + declared(header + 1) + declared(header + 5)
+        }
+    };
+    if !data.is_empty() && min_len > data.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    read_record(&mut &data[..], format)
+}
+
+/// a record of a legacy json log, only string keys and values were stored
+#[derive(Deserialize)]
+struct JsonKV {
+    version: u32,
+    key: String,
+    value: String,
+}
+
+fn read_json_kv<R: Read>(reader: &mut R) -> io::Result<Option<(KV, u64)>> {
+    let mut len_buffer = [0; 4];
+    if !read_header(reader, &mut len_buffer)? {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(len_buffer);
+    if len == 0 {
+        return Ok(None);
+    }
+    // grown as it is read, a torn length must not allocate what is not there
+    let data = read_n(&mut *reader, len as u64)?;
+    let kv: JsonKV = serde_json::from_slice(&data)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(Some((
+        KV::new(kv.key.into_bytes(), kv.value.into_bytes(), kv.version),
+        4 + len as u64,
+    )))
+}
+
+fn read_binary_record<R: Read>(
+    reader: &mut R,
+    format_version: u32,
+) -> io::Result<Option<(Record, u64)>> {
+    let with_crc = format_version >= 2;
+    let mut crc = [0; RECORD_CRC_LEN];
+    if with_crc && !read_header(reader, &mut crc)? {
+        return Ok(None);
+    }
+    let mut header = [0; RECORD_HEADER_LEN];
+    if !read_header(reader, &mut header)? {
+        if with_crc {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(None);
+    }
+    let (put, expiring) = match header[0] {
+        RECORD_PUT => (true, false),
+        RECORD_DELETE | RECORD_BATCH_BEGIN | RECORD_BATCH_COMMIT => (false, false),
+        RECORD_PUT_EXPIRING => (true, true),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record type {}", other),
+            ))
+        }
+    };
+    let key_len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let value_len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+
+    // the version and the expiry time, when the record has them
+    let version_len = if put && format_version >= 4 {
+        RECORD_VERSION_LEN
+    } else {
+        0
+    };
+    let expiry_len = if expiring { RECORD_EXPIRY_LEN } else { 0 };
+    let mut meta = [0; RECORD_VERSION_LEN + RECORD_EXPIRY_LEN];
+    let meta = &mut meta[..version_len + expiry_len];
+    reader.read_exact(meta)?;
+    // the lengths are not checked yet, they are only trusted as far as
+    // there is data to read
+    let mut data = read_n(&mut *reader, (key_len + value_len) as u64)?;
+    if with_crc && u32::from_be_bytes(crc) != checksum(&[&header, meta, &data]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record checksum mismatch",
+        ));
+    }
+    let len = (crc_len(with_crc) + RECORD_HEADER_LEN + meta.len() + key_len + value_len) as u64;
+    match header[0] {
+        RECORD_BATCH_BEGIN => {
+            let count = data.as_slice().try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid batch begin record")
+            })?;
+            return Ok(Some((Record::BatchBegin(u32::from_be_bytes(count)), len)));
+        }
+        RECORD_BATCH_COMMIT => return Ok(Some((Record::BatchCommit, len))),
+        _ => {}
+    }
+    let (version, expiry) = meta.split_at(version_len);
+    // puts of older logs have no version
+    let version = match (put, version.try_into()) {
+        (true, Ok(version)) => u32::from_be_bytes(version),
+        (true, Err(_)) => 1,
+        (false, _) => 0,
+    };
+    let expires_at = expiry.try_into().ok().map(u64::from_be_bytes);
+    let value = data.split_off(key_len);
+    let key = data;
+    Ok(Some((
+        Record::Kv(KV::new(key, value, version).expiring(expires_at)),
+        len,
+    )))
+}
+
+fn crc_len(with_crc: bool) -> usize {
+    if with_crc {
+        RECORD_CRC_LEN
+    } else {
+        0
+    }
+}
+
+fn checksum(parts: &[&[u8]]) -> u32 {
+    let mut hasher = Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// fill `buf`, return `false` if the reader is already exhausted.
+/// A partially read `buf` means the record was torn.
+fn read_header<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match read_full(reader, buf)? {
+        0 => Ok(false),
+        n if n == buf.len() => Ok(true),
+        _ => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// read until `buf` is full or the reader is exhausted, return the read length
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
@@ -1,12 +1,42 @@
-use std::ops::Deref;
+use log::error;
+use sled::transaction::{
+    ConflictableTransactionError::Abort, ConflictableTransactionResult, TransactionalTree,
+};
+use sled::Transactional;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::ops::{Bound, Deref, RangeBounds};
 use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::io::own_dir_or_not;
-use crate::KvsEngine;
+use super::options::{EngineOptions, SyncPolicy, SyncState};
+use super::transaction::{SledTransaction, Versioned};
+use super::util::{deadline, now_millis, time_left};
+use crate::io::{create_empty_dir, lock_dir, DirLock};
 use crate::KvsError;
 use crate::Result;
+use crate::{BatchOp, Condition, KvPairs, KvsEngine, WriteBatch};
+
+/// on-disk format version of the sled store directory,
+/// version 2 keeps the expiry times in `EXPIRY_TREE`, version 3 the versions
+/// in `VERSION_TREE`
+const SLED_FORMAT_VERSION: u32 = 3;
+/// the tree mapping the keys with a ttl to their expiry time, as a big endian
+/// `u64` of milliseconds since the unix epoch
+const EXPIRY_TREE: &[u8] = b"expiry";
+/// the tree mapping the keys to their version, as a big endian `u32`
+const VERSION_TREE: &[u8] = b"versions";
+/// how often the expired keys are removed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// how long to wait for the background threads of a dropped store to release
+/// the sled lock
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Sled store
 /// A kv store based on seld
@@ -20,7 +50,7 @@ use crate::Result;
 /// // set a kv
 /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
 /// // get a kv
-/// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// assert_eq!(store.get("key1").unwrap(), Some(b"value1".to_vec()));
 /// // rm a kv
 /// assert!(store.remove("key1".to_owned()).is_ok());
 /// // check it!
@@ -28,58 +58,194 @@ use crate::Result;
 /// ```
 pub struct SledStore {
     db: Arc<Mutex<sled::Db>>,
+    expiry: sled::Tree,
+    versions: sled::Tree,
+    copying: Arc<Mutex<Copying>>,
+    sync_state: Arc<Mutex<SyncState>>,
+    sweeper: Arc<Sweeper>,
+    // released once the sweeper above is stopped
+    _lock: Arc<DirLock>,
 }
 
 impl Clone for SledStore {
     fn clone(&self) -> Self {
         SledStore {
             db: self.db.clone(),
+            expiry: self.expiry.clone(),
+            versions: self.versions.clone(),
+            copying: self.copying.clone(),
+            sync_state: self.sync_state.clone(),
+            sweeper: self.sweeper.clone(),
+            _lock: self._lock.clone(),
         }
     }
 
     fn clone_from(&mut self, source: &Self) {
         self.db = source.db.clone();
+        self.expiry = source.expiry.clone();
+        self.versions = source.versions.clone();
+        self.copying = source.copying.clone();
+        self.sync_state = source.sync_state.clone();
+        self.sweeper = source.sweeper.clone();
+        self._lock = source._lock.clone();
     }
 }
 
 impl KvsEngine for SledStore {
-    /// set kv pair
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let _ = self
-            .db
-            .lock()
-            .unwrap()
-            .insert(key.as_bytes(), value.as_bytes());
-        self.db.lock().unwrap().flush().unwrap();
-        Ok(())
+    type Transaction = SledTransaction;
+
+    /// set kv pair, dropping its expiry time
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.put(key.into(), value.into(), None, None)
+    }
+    /// set kv pair together with its expiry time
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.put(key.into(), value.into(), Some(deadline(ttl)), None)
+    }
+    /// set kv pair, the condition is checked in the same transaction
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.put(key.into(), value.into(), ttl.map(deadline), Some(condition))
     }
     /// get kv pair
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let result = self.db.lock().unwrap().get(key);
-        match result {
-            Ok(result) => {
-                if let Some(value) = result {
-                    let value = String::from(std::str::from_utf8(&value.deref()).unwrap());
-                    return Ok(Some(value));
-                } else {
-                    return Ok(None);
-                }
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+    /// get kv pair with its version from the version tree
+    fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u32)>> {
+        let key = key.into();
+        self.transaction(|tx| tx.get_versioned(&key))
+    }
+    /// remove kv pair
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.transaction(|tx| {
+            if tx.live_version(&key)? == 0 {
+                return Err(Abort(KvsError::ErrKeyNotFound));
             }
-            Err(_) => {
-                return Ok(None);
+            tx.remove(&key)
+        })?;
+        self.sync_written(key.len() as u64)
+    }
+    /// apply the writes as one `sled::Batch` per tree, in one transaction
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.transaction(|tx| tx.write_batch(&batch))?;
+        self.sync_written(batch_size(&batch))
+    }
+    /// the time left from the expiry tree
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let key = key.into();
+        self.transaction(|tx| {
+            if tx.live_version(&key)? == 0 {
+                return Err(Abort(KvsError::ErrKeyNotFound));
+            }
+            let expires_at = tx.expiry.get(&key[..])?;
+            Ok(expires_at.map(|at| time_left(expiry_time(&at))))
+        })
+    }
+    /// remove the key from the expiry tree
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.transaction(|tx| {
+            if tx.live_version(&key)? == 0 {
+                return Err(Abort(KvsError::ErrKeyNotFound));
+            }
+            tx.expiry.remove(&key[..])?;
+            tx.wrote(&key);
+            Ok(())
+        })?;
+        self.sync_written(key.len() as u64)
+    }
+    /// scan kv pairs in key order with `sled::Tree::range`
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvPairs<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        // sled does not accept a range ending before it starts
+        if let (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) = (&range.0, &range.1)
+        {
+            if start > end {
+                return Ok(Box::new(std::iter::empty()));
             }
         }
+        let db = self.db.lock().unwrap().clone();
+        let expiry = self.expiry.clone();
+        let pairs = db.range(range).filter_map(move |pair| {
+            let (key, value) = match pair {
+                Ok(pair) => pair,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match expiry.get(&key) {
+                Ok(Some(expires_at)) if expired(expiry_time(&expires_at)) => None,
+                Ok(_) => Some(Ok((key.to_vec(), value.to_vec()))),
+                Err(err) => Some(Err(err.into())),
+            }
+        });
+        Ok(Box::new(pairs.take(limit.unwrap_or(usize::MAX))))
     }
-    /// remove kv pair
-    fn remove(&self, key: String) -> Result<()> {
-        match self.db.lock().unwrap().get(key.clone()).unwrap() {
-            Some(_) => {}
-            None => {
-                return Err(KvsError::ErrKeyNotFound);
+    /// a transaction reading the latest values
+    fn begin_transaction(&self) -> Result<SledTransaction> {
+        Ok(SledTransaction::new(self.clone()))
+    }
+    /// copy the trees into a new sled db while the writes go on, then copy
+    /// again the keys written meanwhile, holding the db only for those: the
+    /// copy is the store as it is at the end of the checkpoint
+    fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = dest.into();
+        create_empty_dir(&dest)?;
+        let _lock = lock_dir(&dest, "sled", SLED_FORMAT_VERSION)?;
+        let copy = sled::Config::new().path(dest).open()?;
+        let copies = [
+            sled::Tree::clone(&copy),
+            copy.open_tree(EXPIRY_TREE)?,
+            copy.open_tree(VERSION_TREE)?,
+        ];
+        let db = {
+            // the writes hold the db, the ones from now on record their keys
+            let db = self.db.lock().unwrap();
+            self.copying.lock().unwrap().checkpoints += 1;
+            db.clone()
+        };
+        let trees = [
+            sled::Tree::clone(&db),
+            self.expiry.clone(),
+            self.versions.clone(),
+        ];
+        let copied = trees
+            .iter()
+            .zip(&copies)
+            .try_for_each(|(tree, copy)| copy_tree(tree, copy));
+        let _db = self.db.lock().unwrap();
+        let written = {
+            let mut copying = self.copying.lock().unwrap();
+            copying.checkpoints -= 1;
+            if copying.checkpoints == 0 {
+                mem::take(&mut copying.keys)
+            } else {
+                copying.keys.clone()
+            }
+        };
+        copied?;
+        for key in written {
+            for (tree, copy) in trees.iter().zip(&copies) {
+                match tree.get(&key)? {
+                    Some(value) => copy.insert(&key[..], value)?,
+                    None => copy.remove(&key[..])?,
+                };
             }
         }
-        self.db.lock().unwrap().remove(key).unwrap();
-        self.db.lock().unwrap().flush().unwrap();
+        copy.flush()?;
         Ok(())
     }
 }
@@ -87,11 +253,346 @@ impl KvsEngine for SledStore {
 impl SledStore {
     /// open
     pub fn open(path: impl Into<PathBuf>) -> Result<SledStore> {
+        SledStore::open_with(path, EngineOptions::default())
+    }
+
+    /// open with the given options
+    pub fn open_with(path: impl Into<PathBuf>, options: EngineOptions) -> Result<SledStore> {
         let path = path.into();
-        own_dir_or_not(path.clone(), "sled");
-        let tree = sled::open(path).unwrap();
+        let lock = lock_dir(&path, "sled", SLED_FORMAT_VERSION)?;
+        let mut config = sled::Config::new().path(path);
+        // sled flushes in the background by itself, only its period is tuned here
+        if let SyncPolicy::Periodic { interval, .. } = options.sync_policy {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        let tree = open_db(&config)?;
+        let expiry = tree.open_tree(EXPIRY_TREE)?;
+        let versions = tree.open_tree(VERSION_TREE)?;
+        let db = Arc::new(Mutex::new(tree));
+        let copying = Arc::new(Mutex::new(Copying::default()));
         Ok(SledStore {
-            db: Arc::new(Mutex::new(tree)),
+            sweeper: Arc::new(Sweeper::spawn(db.clone(), copying.clone())),
+            db,
+            expiry,
+            versions,
+            copying,
+            sync_state: Arc::new(Mutex::new(SyncState::new(options.sync_policy))),
+            _lock: Arc::new(lock),
+        })
+    }
+
+    /// set `key` at its next version, if `condition` holds
+    fn put(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        condition: Option<Condition>,
+    ) -> Result<()> {
+        let written = (key.len() + value.len()) as u64;
+        self.transaction(|tx| {
+            let version = tx.live_version(&key)?;
+            let holds = match &condition {
+                None => true,
+                Some(Condition::Absent) => version == 0,
+                Some(Condition::Version(expected)) => version == *expected,
+                Some(Condition::Equals(expected)) => {
+                    version != 0 && tx.db.get(&key[..])?.as_deref() == Some(&expected[..])
+                }
+            };
+            if !holds {
+                return Err(Abort(KvsError::ErrConditionFailed));
+            }
+            let version = version.checked_add(1).unwrap_or(1);
+            tx.wrote(&key);
+            tx.db.insert(&key[..], &value[..])?;
+            tx.versions.insert(&key[..], &version.to_be_bytes()[..])?;
+            match expires_at {
+                Some(expires_at) => tx.expiry.insert(&key[..], &expires_at.to_be_bytes()[..])?,
+                None => tx.expiry.remove(&key[..])?,
+            };
+            Ok(())
+        })?;
+        self.sync_written(written)
+    }
+
+    /// apply the writes of a transaction in a sled transaction, unless a key
+    /// it read does not hold what it read any more
+    pub(super) fn commit(
+        &self,
+        reads: &HashMap<Vec<u8>, Versioned>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        self.transaction(|tx| {
+            for (key, read) in reads {
+                if tx.get_versioned(key)? != *read {
+                    return Err(Abort(KvsError::ErrConflict));
+                }
+            }
+            tx.write_batch(&batch)
+        })?;
+        self.sync_written(batch_size(&batch))
+    }
+
+    /// run `f` in a transaction over the trees of the store
+    fn transaction<A>(
+        &self,
+        f: impl Fn(&Transaction<'_>) -> ConflictableTransactionResult<A, KvsError>,
+    ) -> Result<A> {
+        let db = self.db.lock().unwrap();
+        transaction(&db, &self.expiry, &self.versions, &self.copying, f)
+    }
+
+    /// apply the sync policy after a write of `bytes`
+    fn sync_written(&self, bytes: u64) -> Result<()> {
+        let mut sync_state = self.sync_state.lock().unwrap();
+        if sync_state.written(bytes) {
+            self.db.lock().unwrap().flush()?;
+            sync_state.synced();
+        }
+        Ok(())
+    }
+}
+
+/// The keys written while checkpoints copy the trees, see
+/// `SledStore::checkpoint`
+#[derive(Default)]
+struct Copying {
+    checkpoints: usize, // the checkpoints running
+    keys: HashSet<Vec<u8>>,
+}
+
+/// the trees of a store, inside a transaction
+struct Transaction<'a> {
+    db: &'a TransactionalTree,
+    expiry: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+    written: &'a RefCell<Vec<Vec<u8>>>, // the keys written, for `Copying`
+}
+
+impl Transaction<'_> {
+    /// record that `key` is written
+    fn wrote(&self, key: &[u8]) {
+        self.written.borrow_mut().push(key.to_vec());
+    }
+
+    /// the version of `key`, 0 if it does not exist or is expired
+    fn live_version(&self, key: &[u8]) -> ConflictableTransactionResult<u32, KvsError> {
+        if self.db.get(key)?.is_none() {
+            return Ok(0);
+        }
+        if let Some(expires_at) = self.expiry.get(key)? {
+            if expired(expiry_time(&expires_at)) {
+                return Ok(0);
+            }
+        }
+        // keys set before the versions were kept are at version 1
+        Ok(self.versions.get(key)?.map_or(1, |version| {
+            let mut buf = [0; 4];
+            buf.copy_from_slice(&version[..4]);
+            u32::from_be_bytes(buf)
+        }))
+    }
+
+    /// the value of `key` with its version, `None` if it does not exist or is expired
+    fn get_versioned(
+        &self,
+        key: &[u8],
+    ) -> ConflictableTransactionResult<Option<(Vec<u8>, u32)>, KvsError> {
+        // expired keys stay until the next sweep
+        let version = self.live_version(key)?;
+        if version == 0 {
+            return Ok(None);
+        }
+        let value = self.db.get(key)?;
+        Ok(value.map(|value| (value.deref().to_vec(), version)))
+    }
+
+    /// remove `key` with its expiry time and version
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<(), KvsError> {
+        self.wrote(key);
+        self.db.remove(key)?;
+        self.expiry.remove(key)?;
+        self.versions.remove(key)?;
+        Ok(())
+    }
+
+    /// apply the writes of `batch`, as one `sled::Batch` per tree
+    fn write_batch(&self, batch: &WriteBatch) -> ConflictableTransactionResult<(), KvsError> {
+        let mut data = sled::Batch::default();
+        let mut versions = sled::Batch::default();
+        let mut expiry = sled::Batch::default();
+        // the versions left by the writes so far, for the later writes of a key
+        let mut written = HashMap::new();
+        for op in batch.ops() {
+            let key = match op {
+                BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+            };
+            self.wrote(key);
+            let version = match written.get(key) {
+                Some(&version) => version,
+                None => self.live_version(key)?,
+            };
+            let version = match op {
+                BatchOp::Set { value, .. } => {
+                    let version = version.checked_add(1).unwrap_or(1);
+                    data.insert(&key[..], &value[..]);
+                    versions.insert(&key[..], &version.to_be_bytes()[..]);
+                    version
+                }
+                // removed and expired keys are already gone
+                BatchOp::Remove { .. } if version == 0 => continue,
+                BatchOp::Remove { .. } => {
+                    data.remove(&key[..]);
+                    versions.remove(&key[..]);
+                    0
+                }
+            };
+            expiry.remove(&key[..]);
+            written.insert(key, version);
+        }
+        self.db.apply_batch(&data)?;
+        self.versions.apply_batch(&versions)?;
+        self.expiry.apply_batch(&expiry)?;
+        Ok(())
+    }
+}
+
+/// the bytes written by `batch`, for the sync policy
+fn batch_size(batch: &WriteBatch) -> u64 {
+    batch
+        .ops()
+        .iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => key.len() + value.len(),
+            BatchOp::Remove { key } => key.len(),
+        })
+        .sum::<usize>() as u64
+}
+
+/// run `f` over the trees of a store in a single transaction, the keys it
+/// writes are recorded while checkpoints run
+fn transaction<A>(
+    db: &sled::Db,
+    expiry: &sled::Tree,
+    versions: &sled::Tree,
+    copying: &Mutex<Copying>,
+    f: impl Fn(&Transaction<'_>) -> ConflictableTransactionResult<A, KvsError>,
+) -> Result<A> {
+    let written = RefCell::new(Vec::new());
+    let result = (&**db, expiry, versions).transaction(|(db, expiry, versions)| {
+        // sled may run it again on a conflict
+        written.borrow_mut().clear();
+        f(&Transaction {
+            db,
+            expiry,
+            versions,
+            written: &written,
         })
+    })?;
+    let written = written.into_inner();
+    if !written.is_empty() {
+        let mut copying = copying.lock().unwrap();
+        if copying.checkpoints > 0 {
+            copying.keys.extend(written);
+        }
+    }
+    Ok(result)
+}
+
+/// open the sled db, the directory lock is held so the sled lock can only
+/// be held by the background threads of a store of this process just dropped
+fn open_db(config: &sled::Config) -> Result<sled::Db> {
+    let start = Instant::now();
+    loop {
+        match config.open() {
+            Err(sled::Error::Io(err))
+                if err.kind() == io::ErrorKind::Other && start.elapsed() < RELEASE_TIMEOUT =>
+            {
+                thread::sleep(Duration::from_millis(10))
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// copy all the pairs of `from` into `to`
+fn copy_tree(from: &sled::Tree, to: &sled::Tree) -> Result<()> {
+    for pair in from.iter() {
+        let (key, value) = pair?;
+        to.insert(key, value)?;
+    }
+    Ok(())
+}
+
+/// decode an expiry time of `EXPIRY_TREE`
+fn expiry_time(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    // only `u64`s are written there
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+/// whether a key expiring at `expires_at` is expired now
+fn expired(expires_at: u64) -> bool {
+    expires_at <= now_millis()
+}
+
+/// The thread removing the expired keys every `SWEEP_INTERVAL`, it is
+/// stopped and joined when the last clone of the store is dropped.
+struct Sweeper {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    fn spawn(db: Arc<Mutex<sled::Db>>, copying: Arc<Mutex<Copying>>) -> Sweeper {
+        // nothing is ever sent, the channel is only closed
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(SWEEP_INTERVAL) {
+                let result = sweep(&db, &copying);
+                if let Err(err) = result {
+                    error!("can not remove the expired keys: {}", err);
+                }
+            }
+        });
+        Sweeper {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// remove the keys expired now, unless they were set again meanwhile.
+/// The expiry tree is walked without holding the db, which is only held to
+/// remove a key, the writes go on between two removals.
+fn sweep(db: &Mutex<sled::Db>, copying: &Mutex<Copying>) -> Result<()> {
+    let (expiry, versions) = {
+        let db = db.lock().unwrap();
+        (db.open_tree(EXPIRY_TREE)?, db.open_tree(VERSION_TREE)?)
+    };
+    for entry in expiry.iter() {
+        let (key, expires_at) = entry?;
+        if !expired(expiry_time(&expires_at)) {
+            continue;
+        }
+        // checked again in the transaction, the key may have been set since
+        transaction(&db.lock().unwrap(), &expiry, &versions, copying, |tx| {
+            if tx.expiry.get(&key)?.as_ref() == Some(&expires_at) {
+                tx.remove(&key)?;
+            }
+            Ok(())
+        })?;
     }
+    Ok(())
 }
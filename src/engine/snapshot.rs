@@ -0,0 +1,76 @@
+use std::ops::RangeBounds;
+
+use super::kvstore::{KvStore, View};
+use super::{with_prefix, KvPairs};
+use crate::Result;
+
+/// A point-in-time view of a `KvStore`, taken by `KvStore::snapshot`
+///
+/// Its reads and scans see the store as it was when it was taken, whatever
+/// is written after, and a key expiring later is still there. The records it
+/// sees are kept until it is dropped.
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("key1", "value1").unwrap();
+/// let snapshot = store.snapshot();
+/// store.set("key1", "value2").unwrap();
+/// store.set("key2", "value2").unwrap();
+/// assert_eq!(snapshot.get("key1").unwrap(), Some(b"value1".to_vec()));
+/// assert_eq!(snapshot.get("key2").unwrap(), None);
+/// ```
+pub struct Snapshot {
+    store: KvStore,
+    view: View,
+}
+
+impl Snapshot {
+    pub(super) fn new(store: KvStore, view: View) -> Snapshot {
+        Snapshot { store, view }
+    }
+
+    /// the store it was taken from, and what it sees of it
+    pub(super) fn source(&self) -> (&KvStore, View) {
+        (&self.store, self.view)
+    }
+
+    /// get kv pair
+    pub fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// get kv pair together with its version
+    pub fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u32)>> {
+        self.store.get_at(&key.into(), self.view)
+    }
+
+    /// the pairs with a key in `range`, in key order, at most `limit` of them
+    pub fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<KvPairs<'_>> {
+        self.store.scan_at(range, limit, self.view)
+    }
+
+    /// the pairs with a key starting with `prefix`, in key order, at most `limit` of them
+    pub fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<KvPairs<'_>> {
+        let prefix = prefix.into();
+        let pairs = self.scan(prefix.clone().., None)?;
+        Ok(with_prefix(pairs, prefix, limit))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.store.release(self.view);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::{SledStore, Snapshot, WriteBatch};
+use crate::{KvsEngine, KvsError, Result};
+
+/// a value read with its version, `None` if the key did not exist
+pub(super) type Versioned = Option<(Vec<u8>, u32)>;
+
+/// An optimistic transaction, started by `KvsEngine::begin_transaction`
+///
+/// Its reads see its own writes, which are only applied by `commit`.
+/// Dropping it without committing rolls it back.
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine, KvsError, Transaction};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("stock", "3").unwrap();
+///
+/// let mut tx = store.begin_transaction().unwrap();
+/// assert_eq!(tx.get("stock").unwrap(), Some(b"3".to_vec()));
+/// tx.set("stock", "2");
+/// tx.set("order", "1");
+/// // another writer got there first
+/// store.set("stock", "1").unwrap();
+/// assert!(matches!(tx.commit(), Err(KvsError::ErrConflict)));
+/// assert_eq!(store.get("order").unwrap(), None);
+/// ```
+pub trait Transaction: Send + 'static {
+    /// get kv pair
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+    /// set kv pair once the transaction commits
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>);
+    /// remove kv pair once the transaction commits, `ErrKeyNotFound` if the
+    /// key does not exist
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()>;
+    /// apply the writes all together, `ErrConflict` if a key read by the
+    /// transaction was written since
+    fn commit(self) -> Result<()>;
+}
+
+/// the last write of every key of a transaction, `None` for a remove
+#[derive(Default)]
+struct Writes(BTreeMap<Vec<u8>, Option<Vec<u8>>>);
+
+impl Writes {
+    /// what the transaction left `key` with, `None` if it did not write it
+    fn get(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.0.get(key).cloned()
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.0.insert(key, Some(value));
+    }
+
+    fn remove(&mut self, key: Vec<u8>) {
+        self.0.insert(key, None);
+    }
+
+    fn into_batch(self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.0 {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        batch
+    }
+}
+
+/// A transaction of a `KvStore`
+///
+/// It reads from a snapshot taken when it started, and conflicts with any
+/// write since then to a key it read.
+pub struct KvStoreTransaction {
+    snapshot: Snapshot,
+    reads: HashSet<Vec<u8>>,
+    writes: Writes,
+}
+
+impl KvStoreTransaction {
+    pub(super) fn new(snapshot: Snapshot) -> KvStoreTransaction {
+        KvStoreTransaction {
+            snapshot,
+            reads: HashSet::new(),
+            writes: Writes::default(),
+        }
+    }
+}
+
+impl Transaction for KvStoreTransaction {
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value);
+        }
+        let value = self.snapshot.get(key.clone())?;
+        self.reads.insert(key);
+        Ok(value)
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.set(key.into(), value.into());
+    }
+
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::ErrKeyNotFound);
+        }
+        self.writes.remove(key);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        // the snapshot keeps the records read until the check is done
+        let (store, view) = self.snapshot.source();
+        store.commit(view, &self.reads, self.writes.into_batch())
+    }
+}
+
+/// A transaction of a `SledStore`
+///
+/// It reads the latest values, and conflicts with any write to a key it read
+/// changing its value or version before it commits.
+pub struct SledTransaction {
+    store: SledStore,
+    reads: HashMap<Vec<u8>, Versioned>,
+    writes: Writes,
+}
+
+impl SledTransaction {
+    pub(super) fn new(store: SledStore) -> SledTransaction {
+        SledTransaction {
+            store,
+            reads: HashMap::new(),
+            writes: Writes::default(),
+        }
+    }
+}
+
+impl Transaction for SledTransaction {
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value);
+        }
+        // a key is read once, later reads see the same value
+        let read = match self.reads.get(&key) {
+            Some(read) => read.clone(),
+            None => {
+                let read = self.store.get_versioned(key.clone())?;
+                self.reads.insert(key, read.clone());
+                read
+            }
+        };
+        Ok(read.map(|(value, _)| value))
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.set(key.into(), value.into());
+    }
+
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::ErrKeyNotFound);
+        }
+        self.writes.remove(key);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        self.store.commit(&self.reads, self.writes.into_batch())
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// KV for kvstore
 #[derive(Serialize, Deserialize)]
@@ -6,18 +7,52 @@ pub struct KV {
     /// version
     pub version: u32,
     /// key
-    pub key: String,
+    pub key: Vec<u8>,
     /// value
-    pub value: String,
+    pub value: Vec<u8>,
+    /// when the pair expires, in milliseconds since the unix epoch,
+    /// `None` if it never does
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
 impl KV {
     /// new KV
-    pub fn new(key: String, value: String, version: u32) -> KV {
+    pub fn new(key: Vec<u8>, value: Vec<u8>, version: u32) -> KV {
         KV {
-            version: version,
-            key: key,
-            value: value,
+            version,
+            key,
+            value,
+            expires_at: None,
         }
     }
+
+    /// the same KV, expiring at `expires_at`
+    pub fn expiring(mut self, expires_at: Option<u64>) -> KV {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// whether the pair is expired at `now`, in milliseconds since the unix epoch
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// the current time in milliseconds since the unix epoch, the clock of
+/// `KV::expires_at` and of the dumps
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// the expiry time of a pair living for `ttl` from now
+pub(crate) fn deadline(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// the time left until `expires_at`
+pub(crate) fn time_left(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
 }
@@ -1,16 +1,135 @@
 extern crate failure;
 use failure::Fail;
-use serde::{Deserialize, Serialize};
+use sled::transaction::TransactionError;
+use std::io;
+use std::string::FromUtf8Error;
 
 /// Error in Kvs Store
-#[derive(Serialize, Deserialize, Debug, Fail)]
+#[derive(Debug, Fail)]
 pub enum KvsError {
     /// Key not found
     #[fail(display = "Key not found")]
     ErrKeyNotFound,
-    /// OK
-    #[fail(display = "OK")]
-    ErrOk,
+    /// the condition of a conditional write does not hold
+    #[fail(display = "Condition failed")]
+    ErrConditionFailed,
+    /// a key read by a transaction was written before it committed
+    #[fail(display = "Transaction conflict")]
+    ErrConflict,
+    /// IO error on the files of a store
+    #[fail(display = "IO error: {}", _0)]
+    ErrIo(#[cause] io::Error),
+    /// error while encoding or decoding json
+    #[fail(display = "serialization error: {}", _0)]
+    ErrSerde(#[cause] serde_json::Error),
+    /// a file of a store does not hold what it should
+    #[fail(display = "{} is corrupted at offset {}: {}", path, offset, reason)]
+    ErrCorrupted {
+        /// the damaged file
+        path: String,
+        /// where the damage starts
+        offset: u64,
+        /// what is wrong
+        reason: String,
+    },
+    /// the directory belongs to another engine
+    #[fail(display = "{} is used by the {} engine", path, engine)]
+    ErrEngineMismatch {
+        /// the store directory
+        path: String,
+        /// the engine owning it
+        engine: String,
+    },
+    /// the directory is already opened by another store
+    #[fail(display = "{} is locked by another store", path)]
+    ErrLocked {
+        /// the store directory
+        path: String,
+    },
+    /// the directory was written by a newer version
+    #[fail(
+        display = "{} has format version {}, only up to {} is supported",
+        path, version, supported
+    )]
+    ErrUnsupportedFormat {
+        /// the store directory
+        path: String,
+        /// the format version of the directory
+        version: u32,
+        /// the newest format version this build understands
+        supported: u32,
+    },
+    /// error from the sled engine
+    #[fail(display = "sled error: {}", _0)]
+    ErrSled(#[cause] sled::Error),
+    /// a key or value is not valid utf-8
+    #[fail(display = "invalid utf-8: {}", _0)]
+    ErrUtf8(#[cause] FromUtf8Error),
+    /// the thread pool can not be built
+    #[fail(display = "thread pool error: {}", _0)]
+    ErrThreadPool(#[cause] rayon::ThreadPoolBuildError),
+    /// a connection to or from `addr` failed
+    #[fail(display = "network error with {}: {}", addr, cause)]
+    ErrNetwork {
+        /// the peer or listen address
+        addr: String,
+        /// the underlying error
+        #[cause]
+        cause: io::Error,
+    },
+    /// the peer sent something that is not a valid message
+    #[fail(display = "protocol error: {}", _0)]
+    ErrProtocol(String),
+    /// the server failed to serve a request
+    #[fail(display = "server error: {}", _0)]
+    ErrServer(String),
+    /// a dump does not hold what `DumpWriter` writes
+    #[fail(display = "invalid dump at offset {}: {}", offset, reason)]
+    ErrInvalidDump {
+        /// where the dump stops making sense
+        offset: u64,
+        /// what is wrong
+        reason: String,
+    },
+}
+
+impl From<io::Error> for KvsError {
+    fn from(err: io::Error) -> KvsError {
+        KvsError::ErrIo(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::ErrSerde(err)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::ErrSled(err)
+    }
+}
+
+impl From<TransactionError<KvsError>> for KvsError {
+    fn from(err: TransactionError<KvsError>) -> KvsError {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => KvsError::ErrSled(err),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::ErrUtf8(err)
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for KvsError {
+    fn from(err: rayon::ThreadPoolBuildError) -> KvsError {
+        KvsError::ErrThreadPool(err)
+    }
 }
 
 /// The Result for kvs store
@@ -0,0 +1,457 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::Duration;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::io::read_n;
+use crate::server::run_on;
+use crate::{thread_pool::ThreadPool, KvsEngine, KvsError, Result, WriteBatch};
+
+/// the longest request line or header
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// the most headers a request can have
+const MAX_HEADERS: usize = 100;
+/// the largest body of a request
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+/// the pairs `GET /keys` answers without a `limit`
+const PAGE_SIZE: usize = 1000;
+
+/// An HTTP request, the headers the gateway does not use are dropped
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+/// An HTTP response
+struct HttpResponse {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json(status: u16, body: serde_json::Value) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn no_content() -> HttpResponse {
+        HttpResponse {
+            status: 204,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, json!({ "error": message }))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if self.status != 204 {
+            write!(writer, "Content-Type: {}\r\n", self.content_type)?;
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+/// A request that can not be read, answered before the connection is closed
+struct BadRequest(u16, String);
+
+/// serve the HTTP requests of a connection until the client closes it, asks
+/// to close it, or it goes `idle_timeout` without one. The requests are run
+/// by `pool`.
+pub(crate) fn handle_connection<E: KvsEngine, P: ThreadPool>(
+    store: E,
+    pool: &P,
+    stream: TcpStream,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(BadRequest(status, message)) => {
+                // where the next request starts is unknown
+                HttpResponse::error(status, &message).write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Err(KvsError::ErrProtocol(message));
+            }
+        };
+        info!("HTTP Request : {} {}", request.method, request.path);
+        let keep_alive = request.keep_alive;
+        let store = store.clone();
+        let response = run_on(pool, move || handle_request(&store, &request))?;
+        response.write_to(&mut writer, keep_alive)?;
+        if !keep_alive {
+            writer.flush()?;
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    info!("HTTP Connection closed : {}", peer);
+    Ok(())
+}
+
+/// the next request, `None` if the connection was closed, or its read
+/// timeout passed, before the request began
+fn read_request<R: BufRead>(
+    reader: &mut R,
+) -> std::result::Result<Option<HttpRequest>, BadRequest> {
+    let line = match read_line(reader) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Ok(None),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(err) => return Err(BadRequest(400, err.to_string())),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target, version)
+        }
+        _ => return Err(BadRequest(400, format!("invalid request line: {}", line))),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    };
+    let mut keep_alive = version != "HTTP/1.0";
+
+    let mut len = None;
+    for count in 0.. {
+        let header = read_line(reader)
+            .map_err(|err| BadRequest(400, err.to_string()))?
+            .ok_or_else(|| BadRequest(400, "unexpected end of headers".to_owned()))?;
+        if header.is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(BadRequest(431, "too many headers".to_owned()));
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| BadRequest(400, format!("invalid header: {}", header)))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let value = value
+                    .parse::<u64>()
+                    .map_err(|_| BadRequest(400, format!("invalid content length: {}", value)))?;
+                len = Some(value);
+            }
+            "transfer-encoding" => {
+                return Err(BadRequest(
+                    501,
+                    "transfer encodings are not supported".to_owned(),
+                ))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+
+    let body = match len {
+        Some(len) if len > MAX_BODY_LEN => {
+            return Err(BadRequest(
+                413,
+                format!("bodies are limited to {} bytes", MAX_BODY_LEN),
+            ))
+        }
+        Some(len) => read_n(&mut *reader, len).map_err(|err| BadRequest(400, err.to_string()))?,
+        None if method == "PUT" || method == "POST" => {
+            return Err(BadRequest(411, "a body needs a Content-Length".to_owned()))
+        }
+        None => Vec::new(),
+    };
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        query,
+        body,
+        keep_alive,
+    }))
+}
+
+/// a line without its CRLF, `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line too long or cut short",
+        ));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not UTF-8"))
+}
+
+/// A write of `POST /batch`
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum JsonOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// A pair of `GET /keys`
+#[derive(Serialize)]
+struct JsonPair {
+    key: String,
+    value: String,
+}
+
+fn handle_request<E: KvsEngine>(store: &E, request: &HttpRequest) -> HttpResponse {
+    let method = request.method.as_str();
+    let response = match request.path.as_str() {
+        "/health" => match method {
+            "GET" => Ok(HttpResponse::json(200, json!({ "status": "ok" }))),
+            _ => Ok(not_allowed("GET")),
+        },
+        "/keys" => match method {
+            "GET" => list(store, request.query.as_deref()),
+            _ => Ok(not_allowed("GET")),
+        },
+        "/batch" => match method {
+            "POST" => batch(store, &request.body),
+            _ => Ok(not_allowed("POST")),
+        },
+        path => match path.strip_prefix("/keys/").map(percent_decode) {
+            Some(Some(key)) if !key.is_empty() => match method {
+                "GET" => get(store, key),
+                "PUT" => put(store, key, request),
+                "DELETE" => match store.remove(key) {
+                    Ok(()) => Ok(HttpResponse::no_content()),
+                    Err(err) => Err(err),
+                },
+                _ => Ok(not_allowed("GET, PUT, DELETE")),
+            },
+            Some(_) => Ok(HttpResponse::error(400, "invalid key")),
+            None => Ok(HttpResponse::error(404, "no such endpoint")),
+        },
+    };
+    response.unwrap_or_else(|err| match err {
+        KvsError::ErrKeyNotFound => HttpResponse::error(404, &err.to_string()),
+        err => {
+            error!("Error happened when serve an HTTP request: {}", err);
+            HttpResponse::error(500, &err.to_string())
+        }
+    })
+}
+
+/// `GET /keys/{key}`, the value as is, its version in `X-Kvs-Version`
+fn get<E: KvsEngine>(store: &E, key: Vec<u8>) -> Result<HttpResponse> {
+    let (value, version) = store.get_versioned(key)?.ok_or(KvsError::ErrKeyNotFound)?;
+    Ok(HttpResponse {
+        status: 200,
+        content_type: "application/octet-stream",
+        headers: vec![("X-Kvs-Version", version.to_string())],
+        body: value,
+    })
+}
+
+/// `PUT /keys/{key}[?ttl=seconds]`, the body is the value
+fn put<E: KvsEngine>(store: &E, key: Vec<u8>, request: &HttpRequest) -> Result<HttpResponse> {
+    let mut ttl = None;
+    for (name, value) in query_pairs(request.query.as_deref()) {
+        match (name.as_str(), value.parse()) {
+            ("ttl", Ok(seconds)) => ttl = Some(Duration::from_secs(seconds)),
+            _ => return Ok(bad_parameter(&name)),
+        }
+    }
+    let value = request.body.clone();
+    match ttl {
+        Some(ttl) => store.set_with_ttl(key, value, ttl)?,
+        None => store.set(key, value)?,
+    }
+    Ok(HttpResponse::no_content())
+}
+
+/// `GET /keys[?prefix=..][&after=..][&limit=..]`, the pairs in key order,
+/// their keys and values base64-encoded, `PAGE_SIZE` of them by default. The
+/// next page starts after the last key of a page.
+fn list<E: KvsEngine>(store: &E, query: Option<&str>) -> Result<HttpResponse> {
+    let mut prefix = Vec::new();
+    let mut after = None;
+    let mut limit = PAGE_SIZE;
+    for (name, value) in query_pairs(query) {
+        match name.as_str() {
+            "prefix" => match query_decode(&value) {
+                Some(value) => prefix = value,
+                None => return Ok(bad_parameter(&name)),
+            },
+            "after" => match query_decode(&value) {
+                Some(value) => after = Some(value),
+                None => return Ok(bad_parameter(&name)),
+            },
+            "limit" => match value.parse() {
+                Ok(value) => limit = value,
+                Err(_) => return Ok(bad_parameter(&name)),
+            },
+            _ => return Ok(bad_parameter(&name)),
+        }
+    }
+    let from = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix.clone()),
+    };
+    let pairs = store
+        .scan((from, Bound::Unbounded), None)?
+        .take_while(|pair| {
+            pair.as_ref()
+                .map_or(true, |(key, _)| key.starts_with(&prefix))
+        })
+        .take(limit)
+        .map(|pair| {
+            pair.map(|(key, value)| JsonPair {
+                key: base64::encode(key),
+                value: base64::encode(value),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(HttpResponse::json(200, json!(pairs)))
+}
+
+/// `POST /batch`, a JSON array of `{"op": "set", "key": .., "value": ..}`
+/// and `{"op": "remove", "key": ..}`, base64-encoded, applied all together
+fn batch<E: KvsEngine>(store: &E, body: &[u8]) -> Result<HttpResponse> {
+    let ops: Vec<JsonOp> = match serde_json::from_slice(body) {
+        Ok(ops) => ops,
+        Err(err) => return Ok(HttpResponse::error(400, &format!("invalid batch: {}", err))),
+    };
+    let decode =
+        |field: &str| base64::decode(field).map_err(|err| format!("invalid base64: {}", err));
+    let mut batch = WriteBatch::new();
+    for op in ops {
+        let added = match op {
+            JsonOp::Set { key, value } => decode(&key)
+                .and_then(|key| decode(&value).map(|value| (key, value)))
+                .map(|(key, value)| batch.set(key, value)),
+            JsonOp::Remove { key } => decode(&key).map(|key| batch.remove(key)),
+        };
+        if let Err(message) = added {
+            return Ok(HttpResponse::error(400, &message));
+        }
+    }
+    store.write_batch(batch)?;
+    Ok(HttpResponse::no_content())
+}
+
+fn not_allowed(allowed: &str) -> HttpResponse {
+    HttpResponse {
+        headers: vec![("Allow", allowed.to_owned())],
+        ..HttpResponse::error(405, "method not allowed")
+    }
+}
+
+fn bad_parameter(name: &str) -> HttpResponse {
+    HttpResponse::error(400, &format!("invalid query parameter {}", name))
+}
+
+/// the `name=value` pairs of a query, the names decoded, the values as they are
+fn query_pairs(query: Option<&str>) -> Vec<(String, String)> {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = query_decode(name).map(|name| String::from_utf8_lossy(&name).into_owned());
+            (name.unwrap_or_default(), value.to_owned())
+        })
+        .collect()
+}
+
+/// the bytes of a component of a query, where `+` also stands for a space
+fn query_decode(encoded: &str) -> Option<Vec<u8>> {
+    percent_decode(&encoded.replace('+', " "))
+}
+
+/// the bytes of a percent-encoded URL component, `None` if it is malformed
+fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                // `from_str_radix` would take a sign, as in `%+1`
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    Some(decoded)
+}
@@ -1,73 +1,130 @@
-use engine::KV;
-use std::fs::{self, read_dir, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::process::exit;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
 
-use crate::engine;
+use crate::{KvsError, Result};
 
 /// read n bytes
-pub fn read_n<R>(reader: R, bytes_to_read: u64) -> Vec<u8>
+pub fn read_n<R>(reader: R, bytes_to_read: u64) -> io::Result<Vec<u8>>
 where
     R: Read,
 {
     let mut buf = vec![];
     let mut chunk = reader.take(bytes_to_read);
-    let n = chunk.read_to_end(&mut buf).expect("Didn't read enough");
-    assert_eq!(bytes_to_read as usize, n);
-    buf
+    let n = chunk.read_to_end(&mut buf)?;
+    if n as u64 != bytes_to_read {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes, read {}", bytes_to_read, n),
+        ));
+    }
+    Ok(buf)
 }
 
-/// write a kv to the file
-pub fn write_kv(file: &mut File, kv: KV) {
-    let serialized = serde_json::to_string(&kv).unwrap();
-    let key_len = serialized.len() as u32;
-    file.write(&key_len.to_be_bytes()).unwrap();
-    file.write(serialized.as_bytes()).unwrap();
+/// held by a store for as long as it is open
+const LOCK_FILE: &str = "LOCK";
+/// the engine and format of the store in the directory
+const META_FILE: &str = "META";
+
+/// The content of the `META` file of a store directory.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    engine: String,
+    format_version: u32,
 }
 
-/// get files from dir by prefix
-pub fn get_sst_from_dir_with_prefix(dir: impl Into<PathBuf>, prefix: String) -> Vec<String> {
-    let paths = read_dir(dir.into()).unwrap();
-    let mut files: Vec<String> = paths
-        .map(|path| path.unwrap().file_name().into_string().unwrap())
-        .filter(|path| path.starts_with(&prefix))
-        .collect();
-    let get_version = |filename: &String| -> u32 {
-        let pos1 = filename.find("_").unwrap();
-        let v1 = filename[(pos1 + 1)..].parse::<u32>().unwrap();
-        v1
-    };
-    files.sort_by(|a, b| {
-        let v1 = get_version(a);
-        let v2 = get_version(b);
-        v1.cmp(&v2)
-    });
-    files
+/// An exclusive lock on a store directory, released when dropped.
+pub struct DirLock {
+    _file: File,
 }
 
-pub fn own_dir_or_not(dir: PathBuf, db_type: &str) {
-    let paths = read_dir(dir.clone()).unwrap();
+/// Lock `dir` for the `engine` store opening it, and check that the
+/// directory belongs to that engine, in a format no newer than `format_version`.
+///
+/// The metadata is then updated to `format_version`, opening the directory
+/// upgrades it. Directories of older versions have no metadata, only a
+/// `kvs` or `sled` marker file telling the engine.
+pub fn lock_dir(dir: &Path, engine: &str, format_version: u32) -> Result<DirLock> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    if let Err(err) = file.try_lock_exclusive() {
+        return Err(match err.kind() {
+            io::ErrorKind::WouldBlock => KvsError::ErrLocked {
+                path: dir.display().to_string(),
+            },
+            _ => KvsError::ErrIo(err),
+        });
+    }
 
-    // 如果没有任何前缀文件，那么认为都没创建过，可以继续做
-    for file in paths {
-        let filename = file.unwrap().file_name().into_string().unwrap();
-        if db_type == "kvs" && filename.starts_with("sled") {
-            exit(1);
+    let meta_path = dir.join(META_FILE);
+    let found =
+        match fs::read(&meta_path) {
+            Ok(data) => Some(serde_json::from_slice::<Meta>(&data).map_err(|err| {
+                KvsError::ErrCorrupted {
+                    path: meta_path.display().to_string(),
+                    offset: 0,
+                    reason: err.to_string(),
+                }
+            })?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+    let found_engine = match &found {
+        Some(meta) => Some(meta.engine.clone()),
+        // 如果没有任何前缀文件，那么认为都没创建过，可以继续做
+        None => ["kvs", "sled"]
+            .iter()
+            .find(|marker| dir.join(marker).exists())
+            .map(|marker| marker.to_string()),
+    };
+    if let Some(found_engine) = found_engine {
+        if found_engine != engine {
+            return Err(KvsError::ErrEngineMismatch {
+                path: dir.display().to_string(),
+                engine: found_engine,
+            });
         }
-        if db_type == "sled" && filename.starts_with("kvs") {
-            exit(1);
+    }
+    if let Some(meta) = &found {
+        if meta.format_version > format_version {
+            return Err(KvsError::ErrUnsupportedFormat {
+                path: dir.display().to_string(),
+                version: meta.format_version,
+                supported: format_version,
+            });
+        }
+        if meta.format_version == format_version {
+            return Ok(DirLock { _file: file });
         }
     }
 
-    let filepath = dir.join(db_type);
+    // replace the metadata at once, a crash leaves either the old or the new one
+    let meta = Meta {
+        engine: engine.to_owned(),
+        format_version,
+    };
+    let tmp_path = dir.join(format!("{}.tmp", META_FILE));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&serde_json::to_vec(&meta)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, &meta_path)?;
+    Ok(DirLock { _file: file })
+}
 
-    fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(filepath)
-        .unwrap_or_else(|err| {
-            panic!("can not open the path : {}", err);
-        });
+/// Create `dir` for a copy of a store, it may already exist if it is empty.
+pub fn create_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::ErrIo(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", dir.display()),
+        )));
+    }
+    Ok(())
 }
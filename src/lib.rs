@@ -1,17 +1,32 @@
 #![deny(missing_docs)]
+// `failure_derive` expands `Fail` into non-local impls
+#![allow(non_local_definitions)]
 //! A simple kv store
 
-pub use client::KvsClient;
-pub use engine::{KvStore, KvsEngine, SledStore, KV};
+pub use client::{KvsClient, RemoteTransaction};
+pub use dump::{DumpEntry, DumpFormat, DumpReader, DumpWriter};
+pub use engine::{
+    now_millis, BatchOp, CompactionTrigger, Condition, CorruptRange, EngineOptions, FsckReport,
+    KeyHistory, KvPairs, KvStore, KvStoreOptions, KvStoreTransaction, KvsEngine, LogEntry,
+    LogFormat, LogInspector, LogRecords, LogReport, LogSummary, Record, SledStore, SledTransaction,
+    Snapshot, SyncPolicy, Transaction, WriteBatch, KV,
+};
 pub use error::{KvsError, Result};
-pub use proto::{Request, Response};
+pub use proto::{
+    Handshake, HandshakeReply, Request, RequestFrame, Response, Status, FEATURES,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use resp::RespServer;
 pub use server::KvServer;
 
 mod client;
+mod dump;
 mod engine;
 mod error;
+mod http;
 mod io;
 mod proto;
+mod resp;
 mod server;
 /// thread pool
 pub mod thread_pool;
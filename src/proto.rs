@@ -1,5 +1,76 @@
-use crate::error::KvsError;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+use crate::io::read_n;
+use crate::{Condition, KvsError, Result, WriteBatch};
+
+/// the newest protocol version, the one of the messages below
+pub const PROTOCOL_VERSION: u32 = 1;
+/// the oldest protocol version still spoken
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// the optional parts of the protocol, agreed on by the handshake
+pub const FEATURES: &[&str] = &[
+    "ttl",
+    "conditional",
+    "batch",
+    "scan",
+    "transactions",
+    "backup",
+];
+/// the first bytes of a `Handshake`, which tell it apart from a request
+const HANDSHAKE_MAGIC: [u8; 4] = *b"kvs!";
+
+/// The first message of a connection, from the client. The server answers
+/// it with a `HandshakeReply` before any request is sent, and then refuses
+/// with an error the requests using a feature not agreed on.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    magic: [u8; 4],
+    /// the oldest protocol version the client speaks
+    pub min_version: u32,
+    /// the newest protocol version the client speaks
+    pub max_version: u32,
+    /// the features the client wants to use
+    pub features: Vec<String>,
+}
+
+impl Handshake {
+    /// the handshake of a client speaking the versions from `min_version`
+    /// to `max_version`
+    pub fn new(min_version: u32, max_version: u32, features: Vec<String>) -> Handshake {
+        Handshake {
+            magic: HANDSHAKE_MAGIC,
+            min_version,
+            max_version,
+            features,
+        }
+    }
+
+    /// whether this is a handshake, and not the first request of a client
+    /// which does not send one
+    pub fn is_valid(&self) -> bool {
+        self.magic == HANDSHAKE_MAGIC
+    }
+}
+
+/// The answer of the server to a `Handshake`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum HandshakeReply {
+    /// the rest of the connection uses `version`, and the `features` both
+    /// sides have
+    Accepted {
+        /// the newest version both sides speak
+        version: u32,
+        /// the features of the client the server has
+        features: Vec<String>,
+    },
+    /// no version is spoken by both sides, the server closes the connection
+    Rejected {
+        /// why, for the client to report
+        reason: String,
+    },
+}
 
 /// Operation Type
 #[derive(Serialize, Deserialize, Debug)]
@@ -7,27 +78,237 @@ pub enum Request {
     /// get
     GET {
         /// get key
-        key: String,
+        key: Vec<u8>,
     },
     /// put
     SET {
         /// set key
-        key: String,
+        key: Vec<u8>,
         /// set value
-        value: String,
+        value: Vec<u8>,
+        /// time-to-live in milliseconds, the key never expires if `None`
+        ttl: Option<u64>,
+        /// only set the key if this holds
+        condition: Option<Condition>,
     },
     /// remove
     RM {
         /// remove key
-        key: String,
+        key: Vec<u8>,
+    },
+    /// writes applied all together or not at all
+    BATCH {
+        /// the writes
+        batch: WriteBatch,
+    },
+    /// time left before a key expires
+    TTL {
+        /// key
+        key: Vec<u8>,
+    },
+    /// make a key never expire
+    PERSIST {
+        /// key
+        key: Vec<u8>,
+    },
+    /// pairs in key order, from `from` included to `to` excluded
+    SCAN {
+        /// first key, unbounded if `None`
+        from: Option<Vec<u8>>,
+        /// end key, unbounded if `None`
+        to: Option<Vec<u8>>,
+        /// max number of pairs
+        limit: Option<usize>,
+    },
+    /// pairs in key order with a key starting with `prefix`
+    PREFIX {
+        /// key prefix
+        prefix: Vec<u8>,
+        /// max number of pairs
+        limit: Option<usize>,
+    },
+    /// start a transaction, the response holds its id
+    BEGIN,
+    /// get within a transaction
+    TGET {
+        /// transaction id
+        txn: u64,
+        /// get key
+        key: Vec<u8>,
+    },
+    /// set within a transaction
+    TSET {
+        /// transaction id
+        txn: u64,
+        /// set key
+        key: Vec<u8>,
+        /// set value
+        value: Vec<u8>,
+    },
+    /// remove within a transaction
+    TRM {
+        /// transaction id
+        txn: u64,
+        /// remove key
+        key: Vec<u8>,
+    },
+    /// commit a transaction
+    COMMIT {
+        /// transaction id
+        txn: u64,
+    },
+    /// drop a transaction and its writes
+    ROLLBACK {
+        /// transaction id
+        txn: u64,
     },
+    /// write a consistent copy of the store to a directory of the server
+    BACKUP {
+        /// the directory, missing or empty, relative to the backup directory
+        /// of the server
+        path: String,
+    },
+}
+
+/// A request with the id its client gave it, which the server echoes in
+/// the response. The server answers the requests of a connection in the
+/// order they come, a client can send several before reading the responses.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestFrame {
+    /// request id
+    pub id: u64,
+    /// the request
+    pub request: Request,
+}
+
+/// The outcome of a request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Status {
+    /// the request succeeded
+    Ok,
+    /// the key does not exist
+    KeyNotFound,
+    /// the condition of a conditional `SET` does not hold
+    ConditionFailed,
+    /// a key read by the transaction of a `COMMIT` was written since
+    Conflict,
+    /// the server failed to serve the request
+    Error(String),
 }
 
 /// Response
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
-    /// KvsError
-    pub status: KvsError,
+    /// the id of the request, see `RequestFrame`
+    pub id: u64,
     /// status
-    pub value: String,
+    pub status: Status,
+    /// value
+    pub value: Vec<u8>,
+    /// version of the value of a `GET`
+    pub version: u32,
+    /// key/value pairs of a scan
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// milliseconds left before the key of a `TTL` expires, `None` if it never does
+    pub ttl: Option<u64>,
+    /// id of the transaction started by a `BEGIN`
+    pub txn: u64,
+}
+
+impl Response {
+    /// the response if the request succeeded, or the error it reports
+    pub fn into_result(self) -> Result<Response> {
+        match self.status {
+            Status::Ok => Ok(self),
+            Status::KeyNotFound => Err(KvsError::ErrKeyNotFound),
+            Status::ConditionFailed => Err(KvsError::ErrConditionFailed),
+            Status::Conflict => Err(KvsError::ErrConflict),
+            Status::Error(message) => Err(KvsError::ErrServer(message)),
+        }
+    }
+}
+
+/// Write a message to `peer`, as its length in a big endian `u32`
+/// followed by its bincode encoding, which keeps keys and values as raw bytes.
+/// It is not flushed, several messages can go out together.
+pub(crate) fn write_message<W: Write, T: Serialize>(
+    stream: &mut W,
+    message: &T,
+    peer: &str,
+) -> Result<()> {
+    let data = bincode::serialize(message)
+        .map_err(|err| KvsError::ErrProtocol(format!("can not encode a message: {}", err)))?;
+    let network = |cause| KvsError::ErrNetwork {
+        addr: peer.to_owned(),
+        cause,
+    };
+    // in a single write, a connection serving several requests must not
+    // wait for the ack of the length before sending the rest
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&data);
+    stream.write_all(&frame).map_err(network)
+}
+
+/// Read a message written by `write_message` from `peer`.
+pub(crate) fn read_message<R: Read, T: DeserializeOwned>(stream: &mut R, peer: &str) -> Result<T> {
+    let mut buffer = [0; 4]; // message len
+    stream
+        .read_exact(&mut buffer)
+        .map_err(|cause| KvsError::ErrNetwork {
+            addr: peer.to_owned(),
+            cause,
+        })?;
+    read_body(stream, buffer, peer).and_then(|data| decode(&data))
+}
+
+/// Read the next message written by `write_message` from `peer`, `None` if
+/// the connection was closed, or its read timeout passed, before the message began.
+pub(crate) fn read_next_message<R: Read, T: DeserializeOwned>(
+    stream: &mut R,
+    peer: &str,
+) -> Result<Option<T>> {
+    match read_next_frame(stream, peer)? {
+        Some(data) => decode(&data).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Like `read_next_message`, the message left encoded.
+pub(crate) fn read_next_frame<R: Read>(stream: &mut R, peer: &str) -> Result<Option<Vec<u8>>> {
+    let network = |cause| KvsError::ErrNetwork {
+        addr: peer.to_owned(),
+        cause,
+    };
+    let mut buffer = [0; 4]; // message len
+    loop {
+        match stream.read(&mut buffer[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(err) => match err.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionReset => return Ok(None),
+                _ => return Err(network(err)),
+            },
+        }
+    }
+    stream.read_exact(&mut buffer[1..]).map_err(network)?;
+    read_body(stream, buffer, peer).map(Some)
+}
+
+/// decode a message read by `read_next_frame`
+pub(crate) fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    bincode::deserialize(data)
+        .map_err(|err| KvsError::ErrProtocol(format!("invalid message: {}", err)))
+}
+
+/// read the message whose length is `len`
+fn read_body<R: Read>(stream: &mut R, len: [u8; 4], peer: &str) -> Result<Vec<u8>> {
+    let len = u32::from_be_bytes(len);
+    read_n(stream, len as u64).map_err(|cause| KvsError::ErrNetwork {
+        addr: peer.to_owned(),
+        cause,
+    })
 }
@@ -0,0 +1,659 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use crate::io::read_n;
+use crate::server::{run_on, spawn_connection, CONNECTION_IDLE_TIMEOUT};
+use crate::{thread_pool::ThreadPool, Condition, KvsEngine, KvsError, Result, WriteBatch};
+
+/// the longest line of a command, as Redis has it
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// the longest bulk string of a command, as Redis has it
+const MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+/// the keys a `SCAN` goes through without a `COUNT`
+const SCAN_COUNT: usize = 10;
+/// the `SCAN` cursors a connection keeps, the oldest are forgotten
+const MAX_SCAN_CURSORS: usize = 1024;
+
+/// A server speaking the protocol of Redis (RESP), for its clients and
+/// tools to use a store.
+///
+/// It serves `GET`, `SET` (with `EX`, `PX`, `NX` and `XX`), `DEL`,
+/// `EXISTS`, `MGET`, `MSET`, `SCAN` (with `MATCH` and `COUNT`), `EXPIRE`,
+/// `PING`, `ECHO`, `INFO` and `QUIT`. The commands of several keys apply
+/// key by key, but `MSET`, which is a write batch. A `SCAN` cursor is a
+/// number standing for the last key gone through, on the connection which
+/// got it: a pass goes through the keys present all along once, whatever is
+/// written meanwhile. A connection keeps its last 1024 cursors.
+///
+/// As with `KvServer`, a connection has a thread of its own and its commands
+/// are run by the thread pool.
+///
+/// ```
+/// use std::io::{BufRead, BufReader, Write};
+/// use std::net::TcpStream;
+/// use std::sync::mpsc;
+/// use kvs::{RespServer, KvStore, thread_pool::*};
+/// use tempfile::TempDir;
+///
+/// const RESP_SOCKET_ADDR: &str = "127.0.0.1:4010";
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// let pool = SharedQueueThreadPool::new(2).unwrap();
+/// let (server_stop_tx, server_stop_rx) = mpsc::channel();
+/// let server = RespServer::new(store, pool, RESP_SOCKET_ADDR, server_stop_rx).unwrap();
+/// let handle = std::thread::spawn(move || {
+///     server.start().unwrap();
+/// });
+///
+/// let mut stream = TcpStream::connect(RESP_SOCKET_ADDR).unwrap();
+/// stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n").unwrap();
+/// stream.write_all(b"GET key\r\n").unwrap();
+/// let mut reader = BufReader::new(stream);
+/// let mut reply = String::new();
+/// reader.read_line(&mut reply).unwrap();
+/// assert_eq!(reply, "+OK\r\n");
+/// reply.clear();
+/// reader.read_line(&mut reply).unwrap();
+/// reader.read_line(&mut reply).unwrap();
+/// assert_eq!(reply, "$5\r\nvalue\r\n");
+/// drop(reader);
+///
+/// server_stop_tx.send(0).unwrap();
+/// TcpStream::connect(RESP_SOCKET_ADDR).unwrap();
+/// handle.join().unwrap();
+/// ```
+pub struct RespServer<E: KvsEngine, P> {
+    engine: E,
+    pool: Arc<P>,
+    listener: TcpListener,
+    stop_rx: Receiver<i32>,
+    idle_timeout: Duration,
+    started: Instant,
+}
+
+impl<E: KvsEngine, P: ThreadPool> RespServer<E, P> {
+    /// new server
+    pub fn new(engine: E, pool: P, addr: &str, stop_rx: Receiver<i32>) -> Result<RespServer<E, P>> {
+        let listener = TcpListener::bind(addr).map_err(|cause| KvsError::ErrNetwork {
+            addr: addr.to_owned(),
+            cause,
+        })?;
+        info!("Now RESP Server is listening on: {}", addr);
+        Ok(RespServer {
+            engine,
+            pool: Arc::new(pool),
+            listener,
+            stop_rx,
+            idle_timeout: CONNECTION_IDLE_TIMEOUT,
+            started: Instant::now(),
+        })
+    }
+
+    /// close the connections which go `timeout` without a command, a minute by default
+    pub fn idle_timeout(mut self, timeout: Duration) -> RespServer<E, P> {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// server start
+    pub fn start(&self) -> Result<()>
+    where
+        P: Send + Sync + 'static,
+    {
+        for stream in self.listener.incoming() {
+            if self.stop_rx.try_recv().is_ok() {
+                info!("RESP Server stop");
+                break;
+            }
+
+            // a failed accept only concerns that connection
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Error happened when accept: {}", err);
+                    continue;
+                }
+            };
+            let store = self.engine.clone();
+            let pool = self.pool.clone();
+            let idle_timeout = self.idle_timeout;
+            let started = self.started;
+            spawn_connection("a RESP connection", move || {
+                handle_connection(store, &*pool, stream, idle_timeout, started)
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A reply of RESP
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>), // `None` is the null reply
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status),
+            Reply::Error(message) => write!(writer, "-{}\r\n", message),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                write!(writer, "${}\r\n", data.len())?;
+                writer.write_all(data)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(writer))
+            }
+        }
+    }
+}
+
+/// The keys the `SCAN`s of a connection resume after, by cursor
+struct ScanCursors {
+    next: u64,
+    keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Default for ScanCursors {
+    fn default() -> Self {
+        ScanCursors {
+            next: 1,
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl ScanCursors {
+    /// a new cursor resuming after `key`, the oldest one is forgotten past
+    /// `MAX_SCAN_CURSORS`
+    fn insert(&mut self, key: Vec<u8>) -> u64 {
+        let cursor = self.next;
+        self.next += 1;
+        self.keys.insert(cursor, key);
+        if self.keys.len() > MAX_SCAN_CURSORS {
+            self.keys.pop_first();
+        }
+        cursor
+    }
+
+    /// the key `cursor` resumes after, `None` for the first page, or
+    /// nothing if the cursor is not one of the connection
+    fn resume(&self, cursor: &[u8]) -> Option<Option<Vec<u8>>> {
+        if cursor.is_empty() || !cursor.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        match std::str::from_utf8(cursor).ok()?.parse().ok()? {
+            0 => Some(None),
+            cursor => self.keys.get(&cursor).cloned().map(Some),
+        }
+    }
+}
+
+/// serve the commands of a connection until the client closes it, or it
+/// goes `idle_timeout` without one. The replies are sent once there is no
+/// command left to read, pipelined commands are answered together.
+fn handle_connection<E: KvsEngine, P: ThreadPool>(
+    store: E,
+    pool: &P,
+    stream: TcpStream,
+    idle_timeout: Duration,
+    started: Instant,
+) -> Result<()> {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut cursors = ScanCursors::default();
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(err) => {
+                // nothing that follows can be trusted
+                let reply = Reply::Error(format!("ERR Protocol error: {}", err));
+                reply.write_to(&mut writer)?;
+                writer.flush()?;
+                return Err(err);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        info!("RESP Command : {}", String::from_utf8_lossy(&args[0]));
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit {
+            Reply::Status("OK")
+        } else {
+            let store = store.clone();
+            let (left, reply) = run_on(pool, move || {
+                let reply = execute(&store, started, &mut cursors, &args);
+                (cursors, reply)
+            })?;
+            cursors = left;
+            reply
+        };
+        reply.write_to(&mut writer)?;
+        if quit {
+            writer.flush()?;
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    info!("RESP Connection closed : {}", peer);
+    Ok(())
+}
+
+/// the arguments of the next command, an array of bulk strings or an inline
+/// command, `None` if the connection was closed, or its read timeout passed,
+/// before the command began
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Ok(None),
+        Err(KvsError::ErrIo(err))
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(Vec::from)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..], "multibulk length")?;
+    let mut args = Vec::with_capacity(count.clamp(0, 1024) as usize);
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol("unexpected end of command"))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol(&format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..line.len().min(1)])
+            )));
+        }
+        let len = parse_len(&line[1..], "bulk length")?;
+        if len < 0 || len as u64 > MAX_BULK_LEN {
+            return Err(protocol("invalid bulk length"));
+        }
+        let mut arg = read_n(&mut *reader, len as u64 + 2)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol("bulk string without its CRLF"));
+        }
+        arg.truncate(len as usize);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// a line without its CRLF, `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(protocol("line too long or cut short"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(data: &[u8], what: &str) -> Result<i64> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| protocol(&format!("invalid {}", what)))
+}
+
+fn protocol(reason: &str) -> KvsError {
+    KvsError::ErrProtocol(reason.to_owned())
+}
+
+/// run a command, its errors are replies
+fn execute<E: KvsEngine>(
+    store: &E,
+    started: Instant,
+    cursors: &mut ScanCursors,
+    args: &[Vec<u8>],
+) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let result = match (name.as_str(), &args[1..]) {
+        ("PING", []) => Ok(Reply::Status("PONG")),
+        ("PING", [message]) | ("ECHO", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        ("GET", [key]) => store.get(key.clone()).map(Reply::Bulk),
+        ("SET", [key, value, options @ ..]) => set(store, key, value, options),
+        ("DEL", keys) if !keys.is_empty() => del(store, keys),
+        ("EXISTS", keys) if !keys.is_empty() => keys
+            .iter()
+            .map(|key| store.get_versioned(key.clone()))
+            .filter(|found| !matches!(found, Ok(None)))
+            .try_fold(0, |count, found| found.map(|_| count + 1))
+            .map(Reply::Integer),
+        ("MGET", keys) if !keys.is_empty() => keys
+            .iter()
+            .map(|key| store.get(key.clone()).map(Reply::Bulk))
+            .collect::<Result<_>>()
+            .map(Reply::Array),
+        ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let mut batch = WriteBatch::new();
+            for pair in pairs.chunks(2) {
+                batch.set(pair[0].clone(), pair[1].clone());
+            }
+            store.write_batch(batch).map(|_| Reply::Status("OK"))
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(store, cursors, cursor, options),
+        ("EXPIRE", [key, seconds]) => expire(store, key, seconds),
+        ("INFO", []) | ("INFO", [_]) => Ok(info(started)),
+        // asked by redis-cli to complete the commands, there is nothing to tell
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        (
+            "PING" | "ECHO" | "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "SCAN"
+            | "EXPIRE" | "INFO",
+            _,
+        ) => Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ))),
+        _ => Ok(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&args[0])
+        ))),
+    };
+    result.unwrap_or_else(|err| {
+        error!("Error happened when serve a RESP command: {}", err);
+        Reply::Error(format!("ERR {}", err))
+    })
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+fn set<E: KvsEngine>(store: &E, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" | b"XX" if condition.is_none() => condition = Some(option.to_ascii_uppercase()),
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let amount = match options.next().map(|amount| parse_int(amount)) {
+                    Some(Some(amount)) if amount > 0 => amount as u64,
+                    Some(Some(_)) => {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                    Some(None) => return Ok(not_an_integer()),
+                    None => return Ok(syntax_error()),
+                };
+                ttl = Some(if unit == b"EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                });
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let result = match condition.as_deref() {
+        Some(b"NX") => store.set_if(key, value, Condition::Absent, ttl),
+        // the key must exist, at the version it is when the value is written
+        Some(_) => loop {
+            let version = match store.get_versioned(key)? {
+                Some((_, version)) => version,
+                None => return Ok(Reply::Bulk(None)),
+            };
+            match store.set_if(key, value, Condition::Version(version), ttl) {
+                Err(KvsError::ErrConditionFailed) => continue,
+                result => break result,
+            }
+        },
+        None => match ttl {
+            Some(ttl) => store.set_with_ttl(key, value, ttl),
+            None => store.set(key, value),
+        },
+    };
+    match result {
+        Ok(()) => Ok(Reply::Status("OK")),
+        Err(KvsError::ErrConditionFailed) => Ok(Reply::Bulk(None)),
+        Err(err) => Err(err),
+    }
+}
+
+/// `DEL key [key ...]`, the number of keys removed
+fn del<E: KvsEngine>(store: &E, keys: &[Vec<u8>]) -> Result<Reply> {
+    let mut removed = 0;
+    for key in keys {
+        match store.remove(key.clone()) {
+            Ok(()) => removed += 1,
+            Err(KvsError::ErrKeyNotFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Reply::Integer(removed))
+}
+
+/// `EXPIRE key seconds`, 1 if the key exists, a key expiring now is removed
+fn expire<E: KvsEngine>(store: &E, key: &[u8], seconds: &[u8]) -> Result<Reply> {
+    let seconds = match parse_int(seconds) {
+        Some(seconds) => seconds,
+        None => return Ok(not_an_integer()),
+    };
+    if seconds <= 0 {
+        return match store.remove(key) {
+            Ok(()) => Ok(Reply::Integer(1)),
+            Err(KvsError::ErrKeyNotFound) => Ok(Reply::Integer(0)),
+            Err(err) => Err(err),
+        };
+    }
+    // the value is written again with the expiry time, unless it changed meanwhile
+    loop {
+        let (value, version) = match store.get_versioned(key)? {
+            Some(found) => found,
+            None => return Ok(Reply::Integer(0)),
+        };
+        let ttl = Some(Duration::from_secs(seconds as u64));
+        match store.set_if(key, value, Condition::Version(version), ttl) {
+            Ok(()) => return Ok(Reply::Integer(1)),
+            Err(KvsError::ErrConditionFailed) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`, the page starts after the
+/// key of the cursor, the keys of a page are matched after they are read
+fn scan<E: KvsEngine>(
+    store: &E,
+    cursors: &mut ScanCursors,
+    cursor: &[u8],
+    options: &[Vec<u8>],
+) -> Result<Reply> {
+    let after = match cursors.resume(cursor) {
+        Some(after) => after,
+        None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = None;
+    let mut count = SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+            (b"MATCH", Some(matching)) => pattern = Some(matching.as_slice()),
+            (b"COUNT", Some(n)) => match parse_int(n) {
+                Some(n) if n >= 1 => count = n as usize,
+                Some(_) => return Ok(syntax_error()),
+                None => return Ok(not_an_integer()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    // only the keys with the literal start of the pattern can match
+    let prefix = pattern.map_or(&b""[..], |pattern| {
+        let end = pattern
+            .iter()
+            .position(|c| b"*?[\\".contains(c))
+            .unwrap_or(pattern.len());
+        &pattern[..end]
+    });
+    let from = match after {
+        Some(after) if after.as_slice() >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix.to_vec()),
+    };
+    let pairs = store
+        .scan((from, Bound::Unbounded), None)?
+        .take_while(|pair| {
+            pair.as_ref()
+                .map_or(true, |(key, _)| key.starts_with(prefix))
+        });
+    let mut keys = Vec::new();
+    let mut last = Vec::new();
+    let mut next = 0;
+    for (i, pair) in pairs.enumerate() {
+        let (key, _) = pair?;
+        // `count` is 1 at least, `last` is set
+        if i == count {
+            next = cursors.insert(last);
+            break;
+        }
+        if pattern.is_none_or(|pattern| glob_match(pattern, &key)) {
+            keys.push(Reply::Bulk(Some(key.clone())));
+        }
+        last = key;
+    }
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+/// `INFO`, the server section, whatever the section asked for
+fn info(started: Instant) -> Reply {
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\nredis_mode:standalone\r\nprocess_id:{}\r\nuptime_in_seconds:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        std::process::id(),
+        started.elapsed().as_secs()
+    );
+    Reply::Bulk(Some(info.into_bytes()))
+}
+
+fn parse_int(data: &[u8]) -> Option<i64> {
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+/// whether `text` matches the glob-style `pattern` of Redis: `*`, `?`,
+/// `[abc]`, `[^a-z]` and `\` escaping the next character
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*`: the pattern after it, the text it covers up to
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, t));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// the length of the element starting `pattern` if it matches `c`
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] | [b'*', ..] => None,
+        [b'?', ..] => Some(1),
+        [b'\\', escaped, ..] => Some(2).filter(|_| *escaped == c),
+        [b'[', class @ ..] => match class.iter().position(|&end| end == b']') {
+            Some(end) => Some(end + 2).filter(|_| class_matches(&class[..end], c)),
+            // an unclosed class is a plain `[`
+            None => Some(1).filter(|_| c == b'['),
+        },
+        [literal, ..] => Some(1).filter(|_| *literal == c),
+    }
+}
+
+/// whether `c` is in the class `[class]`
+fn class_matches(class: &[u8], c: u8) -> bool {
+    let (negate, mut class) = match class {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    while !class.is_empty() {
+        match class {
+            [b'\\', escaped, rest @ ..] => {
+                found |= *escaped == c;
+                class = rest;
+            }
+            [start, b'-', end, rest @ ..] => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                found |= (low..=high).contains(&c);
+                class = rest;
+            }
+            [single, rest @ ..] => {
+                found |= *single == c;
+                class = rest;
+            }
+            [] => unreachable!(),
+        }
+    }
+    found != negate
+}
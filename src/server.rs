@@ -1,17 +1,41 @@
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
 use std::net::{TcpListener, TcpStream};
-use std::process::exit;
-use std::sync::mpsc::Receiver;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log::{error, info};
 
-use crate::io::read_n;
-use crate::{thread_pool::ThreadPool, KvsEngine};
-use crate::{KvsError, Request, Response};
+use crate::http;
+use crate::proto::{decode, read_next_frame, read_next_message, write_message};
+use crate::{thread_pool::ThreadPool, KvsEngine, Transaction};
+use crate::{Handshake, HandshakeReply, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::{KvPairs, KvsError, Request, RequestFrame, Response, Result, Status};
+
+/// how long a transaction can go without a request before it is rolled back
+const TRANSACTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often an idle connection rolls back its transactions gone idle
+const TRANSACTION_REAP_INTERVAL: Duration = Duration::from_secs(1);
+/// how long a connection can go without a request before it is closed
+pub(crate) const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 /// kvserver
 /// it can specify store engine and thread pool
 /// it will serving network requests
+///
+/// A connection has a thread of its own, which reads its requests and writes
+/// the responses for as long as the client keeps it open, up to
+/// `idle_timeout` between two requests. The requests are run by the thread
+/// pool: the pool bounds the requests run at once, not the connections open,
+/// and an idle connection holds none of its threads.
+///
+/// With [`http_addr`](KvServer::http_addr) the server also answers an
+/// HTTP/JSON API, served by the same engine and thread pool.
 /// ```
 /// use std::net::TcpStream;
 /// use std::sync::mpsc::{self, Receiver, Sender};
@@ -27,117 +51,621 @@ use crate::{KvsError, Request, Response};
 /// // server stop signal
 /// let (server_stop_tx, server_stop_rx): (Sender<i32>, Receiver<i32>) =
 ///     mpsc::channel();
-/// let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx);
+/// let server = KvServer::new(store, pool, SERVER_SOCKET_ADDR, server_stop_rx).unwrap();
 /// let handle = std::thread::spawn(move || {
-///     server.start();
+///     server.start().unwrap();
 /// });
 /// server_stop_tx.send(0).unwrap();
 /// TcpStream::connect(SERVER_SOCKET_ADDR).unwrap();
 /// handle.join().unwrap();
 /// ```
-pub struct KvServer<E, P> {
+pub struct KvServer<E: KvsEngine, P> {
     engine: E,
-    pool: P,
+    pool: Arc<P>,
     listener: TcpListener,
     stop_rx: Receiver<i32>,
+    idle_timeout: Duration,
+    http_listener: Option<TcpListener>,
+    backup_dir: Option<Arc<Path>>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
     /// new server
-    pub fn new(engine: E, pool: P, addr: &str, stop_rx: Receiver<i32>) -> KvServer<E, P> {
-        let listener = TcpListener::bind(addr).unwrap_or_else(|err| {
-            error!("Error happened when tcp listen: {}", err);
-            exit(1);
-        });
+    pub fn new(engine: E, pool: P, addr: &str, stop_rx: Receiver<i32>) -> Result<KvServer<E, P>> {
+        let listener = TcpListener::bind(addr).map_err(|cause| KvsError::ErrNetwork {
+            addr: addr.to_owned(),
+            cause,
+        })?;
         info!("Now Server is listening on: {}", addr);
-        KvServer {
-            engine: engine,
-            pool: pool,
-            listener: listener,
-            stop_rx: stop_rx,
-        }
+        Ok(KvServer {
+            engine,
+            pool: Arc::new(pool),
+            listener,
+            stop_rx,
+            idle_timeout: CONNECTION_IDLE_TIMEOUT,
+            http_listener: None,
+            backup_dir: None,
+        })
+    }
+
+    /// close the connections which go `timeout` without a request, a minute by default
+    pub fn idle_timeout(mut self, timeout: Duration) -> KvServer<E, P> {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// accept the backups, written under `dir`. A `BACKUP` request names a
+    /// path relative to it, and can not lead out of it with `..`. Without a
+    /// backup directory, every backup is refused.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> KvServer<E, P> {
+        self.backup_dir = Some(dir.into().into());
+        self
+    }
+
+    /// also serve the HTTP API at `addr`:
+    ///
+    /// - `GET /health`: `{"status": "ok"}`
+    /// - `GET /keys/{key}`: the value, its version in `X-Kvs-Version`
+    /// - `PUT /keys/{key}[?ttl=seconds]`: set the key to the body
+    /// - `DELETE /keys/{key}`: remove the key
+    /// - `GET /keys[?prefix=..][&after=..][&limit=..]`: the pairs in key order,
+    ///   as a JSON array of `{"key": .., "value": ..}`, base64-encoded, a
+    ///   thousand by default. The next page is asked with `after` the last key.
+    /// - `POST /batch`: apply a JSON array of `{"op": "set", "key": .., "value": ..}`
+    ///   and `{"op": "remove", "key": ..}`, base64-encoded, all together
+    ///
+    /// Keys in paths are percent-encoded. A missing key is answered with 404,
+    /// an invalid request with 4xx, an engine failure with 500, and the body
+    /// of an error is `{"error": message}`.
+    ///
+    /// ```
+    /// use std::io::{Read, Write};
+    /// use std::net::TcpStream;
+    /// use std::sync::mpsc;
+    /// use kvs::{KvServer, KvStore, thread_pool::*};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// let pool = SharedQueueThreadPool::new(2).unwrap();
+    /// let (stop_tx, stop_rx) = mpsc::channel();
+    /// let server = KvServer::new(store, pool, "127.0.0.1:4015", stop_rx)
+    ///     .unwrap()
+    ///     .http_addr("127.0.0.1:4016")
+    ///     .unwrap();
+    /// let handle = std::thread::spawn(move || server.start().unwrap());
+    ///
+    /// let mut stream = TcpStream::connect("127.0.0.1:4016").unwrap();
+    /// stream
+    ///     .write_all(b"PUT /keys/k1 HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nv1")
+    ///     .unwrap();
+    /// let mut response = String::new();
+    /// stream.read_to_string(&mut response).unwrap();
+    /// assert!(response.starts_with("HTTP/1.1 204"));
+    ///
+    /// stop_tx.send(0).unwrap();
+    /// TcpStream::connect("127.0.0.1:4015").unwrap();
+    /// handle.join().unwrap();
+    /// ```
+    pub fn http_addr(mut self, addr: &str) -> Result<KvServer<E, P>> {
+        let listener = TcpListener::bind(addr).map_err(|cause| KvsError::ErrNetwork {
+            addr: addr.to_owned(),
+            cause,
+        })?;
+        info!("Now Server is listening for HTTP on: {}", addr);
+        self.http_listener = Some(listener);
+        Ok(self)
     }
 
     /// server start
-    pub fn start(&self) {
-        for stream in self.listener.incoming() {
-            let stop_singal = self.stop_rx.try_recv();
-            match stop_singal {
-                Ok(_) => {
-                    info!("Server stop");
-                    break;
+    pub fn start(&self) -> Result<()>
+    where
+        P: Send + Sync + 'static,
+    {
+        let http_stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            if let Some(listener) = &self.http_listener {
+                let store = self.engine.clone();
+                let (pool, http_stop) = (&self.pool, &http_stop);
+                let idle_timeout = self.idle_timeout;
+                scope.spawn(move || serve_http(store, pool, listener, http_stop, idle_timeout));
+            }
+            let result = self.serve();
+            if let Some(listener) = &self.http_listener {
+                // wake the HTTP listener up so that it sees the stop
+                http_stop.store(true, Ordering::SeqCst);
+                if let Err(err) = listener.local_addr().and_then(TcpStream::connect) {
+                    error!("Error happened when stop the HTTP listener: {}", err);
                 }
-                Err(_) => {}
+            }
+            result
+        })
+    }
+
+    fn serve(&self) -> Result<()>
+    where
+        P: Send + Sync + 'static,
+    {
+        for stream in self.listener.incoming() {
+            if self.stop_rx.try_recv().is_ok() {
+                info!("Server stop");
+                break;
             }
 
+            // a failed accept only concerns that connection
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Error happened when accept: {}", err);
+                    continue;
+                }
+            };
             let store = self.engine.clone();
-            self.pool.spawn(move || {
-                handle_connection(store, stream.unwrap());
+            let pool = self.pool.clone();
+            let idle_timeout = self.idle_timeout;
+            let backup_dir = self.backup_dir.clone();
+            spawn_connection("a connection", move || {
+                handle_connection(store, &*pool, stream, idle_timeout, backup_dir)
             });
         }
+        Ok(())
     }
 }
 
-fn handle_connection<E: KvsEngine>(store: E, mut stream: TcpStream) {
-    let mut buffer = [0; 4]; // request len
-    stream.read(&mut buffer).unwrap();
-    let request_len = u32::from_be_bytes(buffer);
-    let data = read_n(&mut stream, request_len as u64);
-    let request: Request = serde_json::from_slice(&data).unwrap();
+/// serve a connection on a thread of its own, the error it ends with is
+/// logged as the one of `what`
+pub(crate) fn spawn_connection<F>(what: &'static str, serve: F)
+where
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
+    let spawned = thread::Builder::new().spawn(move || {
+        if let Err(err) = serve() {
+            error!("Error happened when serve {}: {}", what, err);
+        }
+    });
+    if let Err(err) = spawned {
+        error!("Error happened when start a thread for {}: {}", what, err);
+    }
+}
 
-    info!("Request : {:?}", request);
+/// run `job` on a thread of `pool` and wait for its result
+pub(crate) fn run_on<P, R, F>(pool: &P, job: F) -> Result<R>
+where
+    P: ThreadPool,
+    R: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+{
+    let (result_tx, result_rx) = mpsc::channel();
+    pool.spawn(move || {
+        // the connection may be gone, the result with it
+        let _ = result_tx.send(job());
+    });
+    result_rx
+        .recv()
+        .map_err(|_| KvsError::ErrServer("the request panicked".to_owned()))
+}
 
-    let mut write_reponse = |response: &mut Response| {
-        let response = serde_json::to_string(&response).unwrap();
-        let response_len = response.len() as u32;
-        stream.write(&response_len.to_be_bytes()).unwrap();
-        stream.write(response.as_bytes()).unwrap();
-    };
-    match request {
-        Request::GET { key } => {
-            let result = store.get(key).unwrap();
-            if let Some(value) = result {
-                let mut response = Response {
-                    status: KvsError::ErrOk,
-                    value: value,
-                };
-                write_reponse(&mut response);
-            } else {
-                let mut response = Response {
-                    status: KvsError::ErrKeyNotFound,
-                    value: "".to_owned(),
-                };
-                write_reponse(&mut response);
+/// accept the HTTP connections until `stop` is set
+fn serve_http<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    store: E,
+    pool: &Arc<P>,
+    listener: &TcpListener,
+    stop: &AtomicBool,
+    idle_timeout: Duration,
+) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Error happened when accept: {}", err);
+                continue;
             }
+        };
+        let store = store.clone();
+        let pool = pool.clone();
+        spawn_connection("an HTTP connection", move || {
+            http::handle_connection(store, &*pool, stream, idle_timeout)
+        });
+    }
+}
+
+/// The transactions begun on a connection, by id. An id only names a
+/// transaction on the connection which began it, and the transactions left
+/// are rolled back when the connection closes.
+struct Transactions<T> {
+    next_id: u64,
+    open: HashMap<u64, (T, Instant)>, // with the time they were last used
+}
+
+impl<T> Default for Transactions<T> {
+    fn default() -> Self {
+        Transactions {
+            next_id: 1,
+            open: HashMap::new(),
         }
-        Request::SET { key, value } => {
-            store.set(key, value).unwrap();
-            let mut response = Response {
-                status: KvsError::ErrOk,
-                value: "".to_owned(),
+    }
+}
+
+impl<T: Transaction> Transactions<T> {
+    /// keep `tx` for the next requests, return its id
+    fn begin(&mut self, tx: T) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(id, (tx, Instant::now()));
+        id
+    }
+
+    /// take the transaction `id` out while a request uses it
+    fn take(&mut self, id: u64) -> Result<T> {
+        match self.open.remove(&id) {
+            Some((tx, _)) => Ok(tx),
+            None => Err(KvsError::ErrServer(format!("unknown transaction {}", id))),
+        }
+    }
+
+    /// run `f` on the transaction `id`, and keep it for the next requests
+    fn with<R>(&mut self, id: u64, f: impl FnOnce(&mut T) -> R) -> Result<R> {
+        let mut tx = self.take(id)?;
+        let result = f(&mut tx);
+        self.open.insert(id, (tx, Instant::now()));
+        Ok(result)
+    }
+
+    /// roll back the transactions unused for `TRANSACTION_IDLE_TIMEOUT`
+    fn reap(&mut self) {
+        self.open
+            .retain(|_, (_, used)| used.elapsed() < TRANSACTION_IDLE_TIMEOUT);
+    }
+}
+
+/// serve the requests of a connection until the client closes it, or it
+/// goes `idle_timeout` without one. The responses are sent once there is no
+/// request left to read, requests sent together are answered together.
+/// A connection starts with a handshake, see `Handshake`.
+fn handle_connection<E: KvsEngine, P: ThreadPool>(
+    store: E,
+    pool: &P,
+    stream: TcpStream,
+    idle_timeout: Duration,
+    backup_dir: Option<Arc<Path>>,
+) -> Result<()> {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let agreement = match read_next_frame(&mut reader, &peer)? {
+        Some(data) => handshake(&mut writer, &data, &peer)?,
+        None => return Ok(()),
+    };
+    // dropped with the connection, which rolls back the transactions left
+    let mut transactions = Transactions::default();
+    let mut last_request = Instant::now();
+    loop {
+        if reader.buffer().is_empty()
+            && !wait_request(
+                reader.get_ref(),
+                &mut transactions,
+                last_request,
+                idle_timeout,
+            )?
+        {
+            break;
+        }
+        let frame = match read_next_message::<_, RequestFrame>(&mut reader, &peer)? {
+            Some(frame) => frame,
+            None => break,
+        };
+        last_request = Instant::now();
+        info!("Request {} : {:?}", frame.id, frame.request);
+        if let Err(reason) = agreement.allows(&frame.request) {
+            let response = Response {
+                id: frame.id,
+                ..response(Status::Error(reason), Vec::new())
             };
-            write_reponse(&mut response);
+            write_message(&mut writer, &response, &peer)?;
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            continue;
         }
-        Request::RM { key } => {
-            let result = store.remove(key);
-            match result {
-                Ok(()) => {
-                    let mut response = Response {
-                        status: KvsError::ErrOk,
-                        value: "".to_owned(),
-                    };
-                    write_reponse(&mut response);
-                }
-                Err(_) => {
-                    let mut response = Response {
-                        status: KvsError::ErrKeyNotFound,
-                        value: "".to_owned(),
+        let (store, backup_dir, request) = (store.clone(), backup_dir.clone(), frame.request);
+        let (left, response) = run_on(pool, move || {
+            let backup_dir = backup_dir.as_deref();
+            let response = handle_request(&store, &mut transactions, backup_dir, request);
+            (transactions, response)
+        })?;
+        transactions = left;
+        let response = Response {
+            id: frame.id,
+            ..response
+        };
+        write_message(&mut writer, &response, &peer)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    info!("Connection closed : {}", peer);
+    Ok(())
+}
+
+/// wait for the next request, rolling back the transactions which go idle
+/// meanwhile. false if the client closed the connection, or it went
+/// `idle_timeout` since `last_request`.
+fn wait_request<T: Transaction>(
+    stream: &TcpStream,
+    transactions: &mut Transactions<T>,
+    last_request: Instant,
+    idle_timeout: Duration,
+) -> Result<bool> {
+    let ready = loop {
+        transactions.reap();
+        let left = match idle_timeout.checked_sub(last_request.elapsed()) {
+            Some(left) if !left.is_zero() => left,
+            _ => break false,
+        };
+        stream.set_read_timeout(Some(left.min(TRANSACTION_REAP_INTERVAL)))?;
+        match stream.peek(&mut [0]) {
+            Ok(read) => break read > 0,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => break false,
+            Err(err) => return Err(err.into()),
+        }
+    };
+    // a request arriving in pieces gets the whole idle timeout
+    stream.set_read_timeout(Some(idle_timeout))?;
+    Ok(ready)
+}
+
+/// What a connection agreed on in its handshake
+struct Agreement {
+    version: u32,
+    features: Vec<String>,
+}
+
+impl Agreement {
+    /// the reason `request` is refused, if it needs a feature not agreed on
+    fn allows(&self, request: &Request) -> std::result::Result<(), String> {
+        let needed: &[&str] = match request {
+            Request::GET { .. } | Request::RM { .. } => &[],
+            Request::SET { ttl, condition, .. } => match (ttl, condition) {
+                (None, None) => &[],
+                (Some(_), None) => &["ttl"],
+                (None, Some(_)) => &["conditional"],
+                (Some(_), Some(_)) => &["ttl", "conditional"],
+            },
+            Request::TTL { .. } | Request::PERSIST { .. } => &["ttl"],
+            Request::BATCH { .. } => &["batch"],
+            Request::SCAN { .. } | Request::PREFIX { .. } => &["scan"],
+            Request::BEGIN
+            | Request::TGET { .. }
+            | Request::TSET { .. }
+            | Request::TRM { .. }
+            | Request::COMMIT { .. }
+            | Request::ROLLBACK { .. } => &["transactions"],
+            Request::BACKUP { .. } => &["backup"],
+        };
+        match needed
+            .iter()
+            .find(|feature| !self.features.iter().any(|agreed| agreed == *feature))
+        {
+            Some(feature) => Err(format!(
+                "protocol error: {} was not agreed on in the handshake of protocol version {}",
+                feature, self.version
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// answer the first message of a connection, an error if the client is
+/// rejected
+fn handshake<W: Write>(writer: &mut W, data: &[u8], peer: &str) -> Result<Agreement> {
+    let reply = match decode::<Handshake>(data) {
+        Ok(handshake) if handshake.is_valid() => negotiate(handshake),
+        _ => {
+            let reason = format!(
+                "a handshake is expected, the server speaks protocol versions {} to {}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            );
+            // a client older than the handshake starts with a request, and
+            // only understands a response
+            match decode::<RequestFrame>(data) {
+                Ok(frame) => {
+                    let response = Response {
+                        id: frame.id,
+                        ..response(Status::Error(reason.clone()), Vec::new())
                     };
-                    write_reponse(&mut response);
+                    write_message(writer, &response, peer)?;
+                    writer.flush()?;
+                    return Err(KvsError::ErrProtocol(reason));
                 }
+                Err(_) => HandshakeReply::Rejected { reason },
             }
         }
+    };
+    write_message(writer, &reply, peer)?;
+    writer.flush()?;
+    match reply {
+        HandshakeReply::Accepted { version, features } => {
+            info!(
+                "Handshake : {} speaks version {} with {:?}",
+                peer, version, features
+            );
+            Ok(Agreement { version, features })
+        }
+        HandshakeReply::Rejected { reason } => Err(KvsError::ErrProtocol(reason)),
     }
+}
+
+/// the newest version both sides speak, and the features both have
+fn negotiate(handshake: Handshake) -> HandshakeReply {
+    let version = handshake.max_version.min(PROTOCOL_VERSION);
+    if version < handshake.min_version.max(MIN_PROTOCOL_VERSION) {
+        let reason = format!(
+            "the client speaks protocol versions {} to {}, the server {} to {}",
+            handshake.min_version, handshake.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        return HandshakeReply::Rejected { reason };
+    }
+    let features = handshake
+        .features
+        .into_iter()
+        .filter(|feature| FEATURES.contains(&feature.as_str()))
+        .collect();
+    HandshakeReply::Accepted { version, features }
+}
+
+fn handle_request<E: KvsEngine>(
+    store: &E,
+    transactions: &mut Transactions<E::Transaction>,
+    backup_dir: Option<&Path>,
+    request: Request,
+) -> Response {
+    match request {
+        Request::GET { key } => match store.get_versioned(key) {
+            Ok(Some((value, version))) => Response {
+                version,
+                ..response(Status::Ok, value)
+            },
+            Ok(None) => response(Status::KeyNotFound, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::SET {
+            key,
+            value,
+            ttl,
+            condition,
+        } => {
+            let ttl = ttl.map(Duration::from_millis);
+            let result = match (condition, ttl) {
+                (Some(condition), ttl) => store.set_if(key, value, condition, ttl),
+                (None, Some(ttl)) => store.set_with_ttl(key, value, ttl),
+                (None, None) => store.set(key, value),
+            };
+            match result {
+                Ok(()) => response(Status::Ok, Vec::new()),
+                Err(KvsError::ErrConditionFailed) => response(Status::ConditionFailed, Vec::new()),
+                Err(err) => failed(err),
+            }
+        }
+        Request::RM { key } => match store.remove(key) {
+            Ok(()) => response(Status::Ok, Vec::new()),
+            Err(KvsError::ErrKeyNotFound) => response(Status::KeyNotFound, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::BATCH { batch } => match store.write_batch(batch) {
+            Ok(()) => response(Status::Ok, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::TTL { key } => match store.ttl(key) {
+            Ok(ttl) => Response {
+                ttl: ttl.map(|ttl| ttl.as_millis() as u64),
+                ..response(Status::Ok, Vec::new())
+            },
+            Err(KvsError::ErrKeyNotFound) => response(Status::KeyNotFound, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::PERSIST { key } => match store.persist(key) {
+            Ok(()) => response(Status::Ok, Vec::new()),
+            Err(KvsError::ErrKeyNotFound) => response(Status::KeyNotFound, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::SCAN { from, to, limit } => {
+            let from = from.map_or(Bound::Unbounded, Bound::Included);
+            let to = to.map_or(Bound::Unbounded, Bound::Excluded);
+            scanned(store.scan((from, to), limit))
+        }
+        Request::PREFIX { prefix, limit } => scanned(store.scan_prefix(prefix, limit)),
+        Request::BEGIN => match store.begin_transaction() {
+            Ok(tx) => Response {
+                txn: transactions.begin(tx),
+                ..response(Status::Ok, Vec::new())
+            },
+            Err(err) => failed(err),
+        },
+        Request::TGET { txn, key } => match transactions.with(txn, |tx| tx.get(key)) {
+            Ok(Ok(Some(value))) => response(Status::Ok, value),
+            Ok(Ok(None)) => response(Status::KeyNotFound, Vec::new()),
+            Ok(Err(err)) | Err(err) => failed(err),
+        },
+        Request::TSET { txn, key, value } => {
+            match transactions.with(txn, |tx| tx.set(key, value)) {
+                Ok(()) => response(Status::Ok, Vec::new()),
+                Err(err) => failed(err),
+            }
+        }
+        Request::TRM { txn, key } => match transactions.with(txn, |tx| tx.remove(key)) {
+            Ok(Ok(())) => response(Status::Ok, Vec::new()),
+            Ok(Err(KvsError::ErrKeyNotFound)) => response(Status::KeyNotFound, Vec::new()),
+            Ok(Err(err)) | Err(err) => failed(err),
+        },
+        Request::COMMIT { txn } => match transactions.take(txn).and_then(Transaction::commit) {
+            Ok(()) => response(Status::Ok, Vec::new()),
+            Err(KvsError::ErrConflict) => response(Status::Conflict, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::ROLLBACK { txn } => match transactions.take(txn) {
+            Ok(_) => response(Status::Ok, Vec::new()),
+            Err(err) => failed(err),
+        },
+        Request::BACKUP { path } => match backup_path(backup_dir, &path) {
+            Ok(dest) => match store.checkpoint(dest) {
+                Ok(()) => response(Status::Ok, Vec::new()),
+                Err(err) => failed(err),
+            },
+            Err(reason) => response(Status::Error(reason), Vec::new()),
+        },
+    }
+}
+
+/// where a backup to `path` is written, in `backup_dir`
+fn backup_path(backup_dir: Option<&Path>, path: &str) -> std::result::Result<PathBuf, String> {
+    let backup_dir = backup_dir.ok_or("backups are disabled on this server")?;
+    let path = Path::new(path);
+    let inside = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if path.as_os_str().is_empty() || !inside {
+        return Err(format!(
+            "invalid backup path {}: a relative path without `..` is expected",
+            path.display()
+        ));
+    }
+    Ok(backup_dir.join(path))
+}
+
+fn response(status: Status, value: Vec<u8>) -> Response {
+    Response {
+        id: 0,
+        status,
+        value,
+        version: 0,
+        pairs: Vec::new(),
+        ttl: None,
+        txn: 0,
+    }
+}
+
+/// collect the pairs of a scan into a response
+fn scanned(pairs: Result<KvPairs<'_>>) -> Response {
+    match pairs.and_then(|pairs| pairs.collect::<Result<Vec<_>>>()) {
+        Ok(pairs) => Response {
+            pairs,
+            ..response(Status::Ok, Vec::new())
+        },
+        Err(err) => failed(err),
+    }
+}
 
-    stream.flush().unwrap();
+/// the engine failed, report it to the client and keep serving
+fn failed(err: KvsError) -> Response {
+    error!("Error happened when serve a request: {}", err);
+    response(Status::Error(err.to_string()), Vec::new())
 }
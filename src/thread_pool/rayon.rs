@@ -25,8 +25,8 @@ impl ThreadPoolTrait for RayonThreadPool {
         Self: Sized,
     {
         let pool_builder = ThreadPoolBuilder::new().num_threads(threads as usize);
-        let pool = pool_builder.build().unwrap();
-        Ok(RayonThreadPool { pool: pool })
+        let pool = pool_builder.build()?;
+        Ok(RayonThreadPool { pool })
     }
 
     /// spawn
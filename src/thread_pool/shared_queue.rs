@@ -72,9 +72,10 @@ struct Worker {
 }
 
 impl Worker {
+    #[allow(clippy::new_ret_no_self)]
     fn new(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) {
         let worker = Worker {
-            id: id,
+            id,
             receiver: receiver.clone(),
         };
         take_job(worker);
@@ -1,6 +1,15 @@
 use assert_cmd::prelude::*;
+use kvs::{
+    Handshake, HandshakeReply, KvStore, KvsClient, KvsEngine, Request, RequestFrame, Response,
+    SledStore, Status, PROTOCOL_VERSION,
+};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +29,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +61,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +100,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +132,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +143,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +154,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +166,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +188,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +208,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +229,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +258,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +266,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +274,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +282,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +290,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +298,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,32 +311,258 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
+        .args(["--backup-dir", "backups"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\nkey3\tvalue4\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--from", "key3", "--limit", "5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3\tvalue4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "ttl1", "value", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "ttl1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["persist", "ttl1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "ttl1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "ttl2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["persist", "ttl2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value5", "--if-absent", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key3",
+            "value5",
+            "--if-equals",
+            "value4",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key3", "--with-version", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("2\tvalue5\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value6", "--if-version", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value6", "--if-version", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // binary values, from a file, stdin or base64
+    let blob: &'static [u8] = b"\x00\xff\n\x01";
+    fs::write(temp_dir.path().join("blob"), blob).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "blob1", "--file", "blob", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "blob1", "--raw", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(blob);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "blob1", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("AP8KAQ==\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "blob2", "--file", "-", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(blob.to_vec())
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "blob3", "AP8KAQ==", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "blob", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("YmxvYjE=\tAP8KAQ==\nYmxvYjI=\tAP8KAQ==\nYmxvYjM=\tAP8KAQ==\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "blob4", "not base64!", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid base64"));
+
+    // a copy of the store, written by the server in its backup directory
+    let backup = temp_dir.path().join("backups").join("copy");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "copy", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "copy", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+    let outside = temp_dir.path().join("outside");
+    for dir in ["../outside", outside.to_str().unwrap()] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", dir, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("invalid backup path"));
+    }
+    assert!(!outside.exists());
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--addr", addr, "--format", "binary", "--output", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("6 pairs dumped"));
     sender.send(()).unwrap();
     handle.join().unwrap();
+
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(["--dir", "restored", "--engine", engine, "--input", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("6 pairs restored"));
+    let restored = temp_dir.path().join("restored");
+    let (value, restored_blob) = match engine {
+        "kvs" => {
+            let store = KvStore::open(&restored).unwrap();
+            (store.get("key3").unwrap(), store.get("blob2").unwrap())
+        }
+        _ => {
+            let store = SledStore::open(&restored).unwrap();
+            (store.get("key3").unwrap(), store.get("blob2").unwrap())
+        }
+    };
+    assert_eq!(value, Some(b"value6".to_vec()));
+    assert_eq!(restored_blob, Some(blob.to_vec()));
+
+    let value = match engine {
+        "kvs" => KvStore::open(&backup).unwrap().get("key3").unwrap(),
+        _ => SledStore::open(&backup).unwrap().get("key3").unwrap(),
+    };
+    assert_eq!(value, Some(b"value6".to_vec()));
 }
 
 #[test]
@@ -335,3 +574,732 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+/// write `message` to `stream` the way `KvsClient` does: its length, then its
+/// bincode encoding
+fn frame<T: Serialize>(message: &T) -> Vec<u8> {
+    let data = bincode::serialize(message).unwrap();
+    [&(data.len() as u32).to_be_bytes()[..], &data].concat()
+}
+
+/// read a message written by the server
+fn read_frame<T: DeserializeOwned>(stream: &mut TcpStream) -> T {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut data = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut data).unwrap();
+    bincode::deserialize(&data).unwrap()
+}
+
+// `kvs-server` should serve several requests on a connection, and close it
+// once it goes idle
+#[test]
+fn server_keeps_connections_open() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--idle-timeout-ms", "500"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    assert_eq!(client.get("key99").unwrap(), Some(b"value99".to_vec()));
+    // closed by the server once idle, the client connects again
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(client.get("key1").unwrap(), Some(b"value1".to_vec()));
+    drop(client);
+
+    // two requests written at once, on the same stream
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let handshake = Handshake::new(PROTOCOL_VERSION, PROTOCOL_VERSION, Vec::new());
+    stream.write_all(&frame(&handshake)).unwrap();
+    let reply: HandshakeReply = read_frame(&mut stream);
+    assert!(matches!(reply, HandshakeReply::Accepted { .. }));
+    let mut frames = Vec::new();
+    for (id, key) in [(7, "key1"), (3, "key2")] {
+        let request = Request::GET { key: key.into() };
+        frames.extend(frame(&RequestFrame { id, request }));
+    }
+    stream.write_all(&frames).unwrap();
+    for (id, value) in [(7, "value1"), (3, "value2")] {
+        let response: Response = read_frame(&mut stream);
+        assert_eq!(response.id, id);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.value, value.as_bytes());
+    }
+
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-server` should serve more open connections than it has threads, an
+// idle connection holds none of them
+#[test]
+fn server_serves_more_connections_than_threads() {
+    let (addr, http_addr) = ("127.0.0.1:4023", "127.0.0.1:4024");
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--http-addr", http_addr, "--threads", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // a failure rather than a hang if a connection waits for a thread
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut clients: Vec<_> = (0..4).map(|_| KvsClient::connect(addr).unwrap()).collect();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.set(format!("key{}", i), "value").unwrap();
+        }
+        // a transaction opens a connection of its own
+        let mut tx = clients[0].begin_transaction().unwrap();
+        tx.set("key0", "tx").unwrap();
+        tx.commit().unwrap();
+        let mut stream = TcpStream::connect(http_addr).unwrap();
+        let (status, _, body) = http_request(&mut stream, "GET /keys/key0 HTTP/1.1", b"");
+        assert_eq!((status, body), (200, b"tx".to_vec()));
+        for (i, client) in clients.iter_mut().enumerate().rev() {
+            assert!(client.get(format!("key{}", i)).unwrap().is_some());
+        }
+        done_tx.send(()).unwrap();
+    });
+    done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// a transaction should only be seen by the connection which began it, and
+// be rolled back when that connection closes
+#[test]
+fn server_scopes_transactions() {
+    let addr = "127.0.0.1:4022";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let connect = || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let features = vec!["transactions".to_owned()];
+        let handshake = Handshake::new(PROTOCOL_VERSION, PROTOCOL_VERSION, features);
+        stream.write_all(&frame(&handshake)).unwrap();
+        let reply: HandshakeReply = read_frame(&mut stream);
+        assert!(matches!(reply, HandshakeReply::Accepted { .. }));
+        stream
+    };
+    let call = |stream: &mut TcpStream, request| -> Response {
+        let data = frame(&RequestFrame { id: 1, request });
+        stream.write_all(&data).unwrap();
+        read_frame(stream)
+    };
+
+    let mut stream = connect();
+    let response = call(&mut stream, Request::BEGIN);
+    assert_eq!((response.status, response.txn), (Status::Ok, 1));
+    let request = Request::TSET {
+        txn: 1,
+        key: b"key".to_vec(),
+        value: b"value".to_vec(),
+    };
+    assert_eq!(call(&mut stream, request).status, Status::Ok);
+
+    // the id means nothing on another connection
+    let mut other = connect();
+    let request = Request::COMMIT { txn: 1 };
+    let response = call(&mut other, request);
+    assert!(matches!(response.status, Status::Error(reason) if reason.contains("unknown")));
+    drop(other);
+
+    // closing the connection rolls the transaction back
+    drop(stream);
+    let mut stream = connect();
+    let request = Request::COMMIT { txn: 1 };
+    let response = call(&mut stream, request);
+    assert!(matches!(response.status, Status::Error(_)));
+    let request = Request::GET {
+        key: b"key".to_vec(),
+    };
+    let response = call(&mut stream, request);
+    assert_eq!(response.status, Status::KeyNotFound);
+    drop(stream);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-server` should agree with a client on a protocol version and the
+// features both have, and refuse the clients it can not serve
+#[test]
+fn server_shakes_hands() {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // the newest version both speak, the features the server has
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let features = vec!["ttl".to_owned(), "compression".to_owned()];
+    let handshake = Handshake::new(1, PROTOCOL_VERSION + 10, features);
+    stream.write_all(&frame(&handshake)).unwrap();
+    let reply: HandshakeReply = read_frame(&mut stream);
+    let expected = HandshakeReply::Accepted {
+        version: PROTOCOL_VERSION,
+        features: vec!["ttl".to_owned()],
+    };
+    assert_eq!(reply, expected);
+    drop(stream);
+
+    // a client older than some features can not use them
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let handshake = Handshake::new(1, 1, vec!["ttl".to_owned()]);
+    stream.write_all(&frame(&handshake)).unwrap();
+    let reply: HandshakeReply = read_frame(&mut stream);
+    let expected = HandshakeReply::Accepted {
+        version: 1,
+        features: vec!["ttl".to_owned()],
+    };
+    assert_eq!(reply, expected);
+    let request = Request::SET {
+        key: b"key".to_vec(),
+        value: b"value".to_vec(),
+        ttl: Some(60_000),
+        condition: None,
+    };
+    let data = frame(&RequestFrame { id: 1, request });
+    stream.write_all(&data).unwrap();
+    let response: Response = read_frame(&mut stream);
+    assert_eq!(response.status, Status::Ok);
+    let request = Request::SCAN {
+        from: None,
+        to: None,
+        limit: None,
+    };
+    let data = frame(&RequestFrame { id: 2, request });
+    stream.write_all(&data).unwrap();
+    let response: Response = read_frame(&mut stream);
+    assert_eq!(response.id, 2);
+    assert!(matches!(response.status, Status::Error(reason) if reason.contains("scan")));
+    let request = Request::GET {
+        key: b"key".to_vec(),
+    };
+    let data = frame(&RequestFrame { id: 3, request });
+    stream.write_all(&data).unwrap();
+    let response: Response = read_frame(&mut stream);
+    assert_eq!(response.value, b"value");
+    drop(stream);
+
+    // a client too new for the server
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let handshake = Handshake::new(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2, Vec::new());
+    stream.write_all(&frame(&handshake)).unwrap();
+    match read_frame(&mut stream) {
+        HandshakeReply::Rejected { reason } => assert!(reason.contains("protocol versions")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // a client older than the handshake gets an error response
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = Request::GET {
+        key: b"key".to_vec(),
+    };
+    stream
+        .write_all(&frame(&RequestFrame { id: 5, request }))
+        .unwrap();
+    let response: Response = read_frame(&mut stream);
+    assert_eq!(response.id, 5);
+    assert!(matches!(response.status, Status::Error(reason) if reason.contains("handshake")));
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    assert!(client.has_feature("backup"));
+    client.set("key", "value").unwrap();
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+/// send a command in RESP to `stream`, and check the reply
+fn resp_command(stream: &mut TcpStream, args: &[&str], reply: &str) {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(command.as_bytes()).unwrap();
+    let mut data = vec![0; reply.len()];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(String::from_utf8_lossy(&data), reply, "reply of {:?}", args);
+}
+
+/// send a `SCAN` in RESP to `stream`, and read the next cursor and the keys
+fn resp_scan(stream: &mut TcpStream, args: &[&str]) -> (String, Vec<String>) {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(command.as_bytes()).unwrap();
+    let mut read_line = || {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while !line.ends_with(b"\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).unwrap()
+    };
+    assert_eq!(read_line(), "*2");
+    read_line();
+    let cursor = read_line();
+    let count: usize = read_line()[1..].parse().unwrap();
+    let keys = (0..count)
+        .map(|_| {
+            read_line();
+            read_line()
+        })
+        .collect();
+    (cursor, keys)
+}
+
+fn resp_access_server(engine: &str, addr: &str, resp_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr, "--resp-addr", resp_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    resp_command(&mut stream, &["PING"], "+PONG\r\n");
+    resp_command(&mut stream, &["SET", "k1", "v1"], "+OK\r\n");
+    resp_command(&mut stream, &["GET", "k1"], "$2\r\nv1\r\n");
+    resp_command(&mut stream, &["GET", "missing"], "$-1\r\n");
+    resp_command(&mut stream, &["SET", "k1", "v2", "NX"], "$-1\r\n");
+    resp_command(&mut stream, &["SET", "k2", "v2", "XX"], "$-1\r\n");
+    resp_command(&mut stream, &["SET", "k1", "v3", "xx"], "+OK\r\n");
+    resp_command(&mut stream, &["MSET", "a", "1", "b", "2"], "+OK\r\n");
+    let reply = "*3\r\n$1\r\n1\r\n$1\r\n2\r\n$-1\r\n";
+    resp_command(&mut stream, &["MGET", "a", "b", "c"], reply);
+    resp_command(&mut stream, &["EXISTS", "a", "c", "a"], ":2\r\n");
+    resp_command(&mut stream, &["DEL", "a", "c"], ":1\r\n");
+    resp_command(&mut stream, &["EXPIRE", "b", "100"], ":1\r\n");
+    resp_command(&mut stream, &["EXPIRE", "c", "100"], ":0\r\n");
+    let reply = "*2\r\n$1\r\n0\r\n*2\r\n$1\r\nb\r\n$2\r\nk1\r\n";
+    resp_command(&mut stream, &["SCAN", "0", "COUNT", "2"], reply);
+    // the cursor resumes after "b", even once "b" is removed
+    let reply = "*2\r\n$1\r\n1\r\n*1\r\n$1\r\nb\r\n";
+    resp_command(&mut stream, &["SCAN", "0", "COUNT", "1"], reply);
+    resp_command(&mut stream, &["DEL", "b"], ":1\r\n");
+    let reply = "*2\r\n$1\r\n0\r\n*1\r\n$2\r\nk1\r\n";
+    resp_command(&mut stream, &["SCAN", "1", "COUNT", "1"], reply);
+    let reply = "-ERR invalid cursor\r\n";
+    resp_command(&mut stream, &["SCAN", "1999"], reply);
+    resp_command(&mut stream, &["SET", "b", "2", "EX", "100"], "+OK\r\n");
+    let reply = "*2\r\n$1\r\n0\r\n*1\r\n$2\r\nk1\r\n";
+    resp_command(&mut stream, &["SCAN", "0", "MATCH", "k[0-9]"], reply);
+    let reply = "-ERR wrong number of arguments for 'get' command\r\n";
+    resp_command(&mut stream, &["GET"], reply);
+
+    // pages over long keys, with cursors the clients can read as u64
+    let mut mset = vec!["MSET".to_owned()];
+    for i in 0..25 {
+        mset.extend([format!("long-key-{:02}", i), "v".to_owned()]);
+    }
+    let mset: Vec<&str> = mset.iter().map(String::as_str).collect();
+    resp_command(&mut stream, &mset, "+OK\r\n");
+    let (mut cursor, mut keys, mut pages) = ("0".to_owned(), Vec::new(), 0);
+    loop {
+        let args = ["SCAN", &cursor, "MATCH", "long-key-*", "COUNT", "10"];
+        let (next, page) = resp_scan(&mut stream, &args);
+        next.parse::<u64>().expect("the cursor is a u64");
+        keys.extend(page);
+        pages += 1;
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    assert_eq!(pages, 3);
+    let expected: Vec<_> = (0..25).map(|i| format!("long-key-{:02}", i)).collect();
+    assert_eq!(keys, expected);
+    resp_command(&mut stream, &["FOO"], "-ERR unknown command 'FOO'\r\n");
+    let reply = "-ERR value is not an integer or out of range\r\n";
+    resp_command(&mut stream, &["SET", "k1", "v", "EX", "x"], reply);
+
+    // inline and pipelined commands
+    stream.write_all(b"PING\r\nGET k1\r\n").unwrap();
+    let mut data = [0; 15];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(&data, b"+PONG\r\n$2\r\nv3\r\n");
+
+    // the same store through the kvs protocol
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("k1").unwrap(), Some(b"v3".to_vec()));
+    let ttl = client.ttl("b").unwrap().unwrap();
+    assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+    drop(client);
+
+    resp_command(&mut stream, &["QUIT"], "+OK\r\n");
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn resp_access_server_kvs_engine() {
+    resp_access_server("kvs", "127.0.0.1:4011", "127.0.0.1:4012");
+}
+
+#[test]
+fn resp_access_server_sled_engine() {
+    resp_access_server("sled", "127.0.0.1:4013", "127.0.0.1:4014");
+}
+
+/// send an HTTP request to `stream`, and read the status, the headers and
+/// the body of the response
+fn http_request(stream: &mut TcpStream, request: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+    let head = format!("{}\r\nContent-Length: {}\r\n\r\n", request, body.len());
+    stream.write_all(&[head.as_bytes(), body].concat()).unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    let status = head[9..12].parse().unwrap();
+    let len = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .map_or(0, |len| len.parse().unwrap());
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).unwrap();
+    (status, head, body)
+}
+
+fn http_access_server(engine: &str, addr: &str, http_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr, "--http-addr", http_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    let (status, _, body) = http_request(&mut stream, "GET /health HTTP/1.1", b"");
+    assert_eq!((status, &body[..]), (200, &br#"{"status":"ok"}"#[..]));
+    let (status, _, _) = http_request(&mut stream, "PUT /keys/k1 HTTP/1.1", b"v1");
+    assert_eq!(status, 204);
+    let (status, head, body) = http_request(&mut stream, "GET /keys/k1 HTTP/1.1", b"");
+    assert_eq!((status, &body[..]), (200, &b"v1"[..]));
+    assert!(head.contains("X-Kvs-Version: 1\r\n"));
+    let (status, _, _) = http_request(&mut stream, "PUT /keys/a%20b?ttl=100 HTTP/1.1", b"v2");
+    assert_eq!(status, 204);
+    let (status, _, body) = http_request(&mut stream, "GET /keys/missing HTTP/1.1", b"");
+    assert_eq!(
+        (status, &body[..]),
+        (404, &br#"{"error":"Key not found"}"#[..])
+    );
+    let (status, _, _) = http_request(&mut stream, "DELETE /keys/missing HTTP/1.1", b"");
+    assert_eq!(status, 404);
+    let (status, head, _) = http_request(&mut stream, "POST /keys/k1 HTTP/1.1", b"");
+    assert_eq!(status, 405);
+    assert!(head.contains("Allow: GET, PUT, DELETE\r\n"));
+    let (status, _, _) = http_request(&mut stream, "GET /nothing HTTP/1.1", b"");
+    assert_eq!(status, 404);
+    let (status, _, _) = http_request(&mut stream, "GET /keys?limit=x HTTP/1.1", b"");
+    assert_eq!(status, 400);
+
+    // keys and values are base64-encoded in JSON: "k2" is "azI=", "v3" is "djM="
+    let batch = br#"[{"op":"set","key":"azI=","value":"djM="},{"op":"remove","key":"azE="}]"#;
+    let (status, _, _) = http_request(&mut stream, "POST /batch HTTP/1.1", batch);
+    assert_eq!(status, 204);
+    let (status, _, _) = http_request(&mut stream, "POST /batch HTTP/1.1", b"[{}]");
+    assert_eq!(status, 400);
+    let (status, _, body) = http_request(&mut stream, "GET /keys?prefix=k HTTP/1.1", b"");
+    assert_eq!(status, 200);
+    assert_eq!(body, br#"[{"key":"azI=","value":"djM="}]"#);
+    // a `+` is itself in a path, a space in a query: "k+1" is "aysx"
+    let (status, _, _) = http_request(&mut stream, "PUT /keys/k+1 HTTP/1.1", b"v4");
+    assert_eq!(status, 204);
+    let (status, _, body) = http_request(&mut stream, "GET /keys/k%2B1 HTTP/1.1", b"");
+    assert_eq!((status, &body[..]), (200, &b"v4"[..]));
+    let (status, _, _) = http_request(&mut stream, "GET /keys/k%+1 HTTP/1.1", b"");
+    assert_eq!(status, 400);
+    let (_, _, body) = http_request(&mut stream, "GET /keys?prefix=k+ HTTP/1.1", b"");
+    assert_eq!(body, b"[]");
+    let (_, _, body) = http_request(&mut stream, "GET /keys?prefix=k&limit=1 HTTP/1.1", b"");
+    assert_eq!(body, br#"[{"key":"aysx","value":"djQ="}]"#);
+    let request = "GET /keys?prefix=k&after=k%2B1 HTTP/1.1";
+    let (_, _, body) = http_request(&mut stream, request, b"");
+    assert_eq!(body, br#"[{"key":"azI=","value":"djM="}]"#);
+    let (status, _, _) = http_request(&mut stream, "DELETE /keys/k+1 HTTP/1.1", b"");
+    assert_eq!(status, 204);
+    let request = "GET /keys HTTP/1.1\r\nConnection: close";
+    let (status, _, body) = http_request(&mut stream, request, b"");
+    assert_eq!(status, 200);
+    let pairs = r#"[{"key":"YSBi","value":"djI="},{"key":"azI=","value":"djM="}]"#;
+    assert_eq!(String::from_utf8_lossy(&body), pairs);
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // the same store through the kvs protocol
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("k1").unwrap(), None);
+    assert_eq!(client.get("k2").unwrap(), Some(b"v3".to_vec()));
+    let ttl = client.ttl("a b").unwrap().unwrap();
+    assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+    drop(client);
+
+    // a malformed request is answered, then the connection is closed
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    stream.write_all(b"nonsense\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn http_access_server_kvs_engine() {
+    http_access_server("kvs", "127.0.0.1:4017", "127.0.0.1:4018");
+}
+
+#[test]
+fn http_access_server_sled_engine() {
+    http_access_server("sled", "127.0.0.1:4019", "127.0.0.1:4020");
+}
+
+// `kvs-dump` and `kvs-restore` should move the pairs from one engine to the other
+#[test]
+fn cli_dump_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("kvs");
+    fs::create_dir(&source).unwrap();
+    {
+        let store = KvStore::open(&source).unwrap();
+        store.set("key1", "value1").unwrap();
+        store.set(&b"\x00key2"[..], &b"\xffvalue2"[..]).unwrap();
+        store
+            .set_with_ttl("key3", "value3", Duration::from_secs(100))
+            .unwrap();
+        store
+            .set_with_ttl("key4", "value4", Duration::from_millis(1))
+            .unwrap();
+        store.set("key5", "value5").unwrap();
+        store.remove("key5").unwrap();
+    }
+    thread::sleep(Duration::from_millis(10));
+
+    let dump = Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--dir", source.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(dump.status.success());
+    let lines = String::from_utf8(dump.stdout.clone()).unwrap();
+    assert_eq!(lines.lines().count(), 3);
+    assert!(lines
+        .starts_with("{\"key\":\"AGtleTI=\",\"value\":\"/3ZhbHVlMg==\",\"expires_at\":null}\n"));
+
+    // from kvs to sled
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(["--dir", "sled", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(dump.stdout.clone())
+        .assert()
+        .success()
+        .stderr(contains("3 pairs restored"));
+    // only into an empty store
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(["--dir", "sled", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(dump.stdout)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+    {
+        let store = SledStore::open(temp_dir.path().join("sled")).unwrap();
+        assert_eq!(store.get("key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(
+            store.get(&b"\x00key2"[..]).unwrap(),
+            Some(b"\xffvalue2".to_vec())
+        );
+        let ttl = store.ttl("key3").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+        assert_eq!(store.get("key4").unwrap(), None);
+        assert_eq!(store.get("key5").unwrap(), None);
+    }
+
+    // and back, through a binary dump
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--dir", "sled", "--engine", "sled", "--format", "binary"])
+        .args(["--output", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("3 pairs dumped"));
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(["--dir", "back", "--input", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("3 pairs restored"));
+    {
+        let store = KvStore::open(temp_dir.path().join("back")).unwrap();
+        assert_eq!(store.get("key1").unwrap(), Some(b"value1".to_vec()));
+        assert!(store.ttl("key3").unwrap().is_some());
+    }
+
+    // a truncated dump is refused
+    let dump = fs::read(temp_dir.path().join("dump")).unwrap();
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(["--dir", "truncated"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(dump[..dump.len() - 1].to_vec())
+        .assert()
+        .failure()
+        .stderr(contains("invalid dump"));
+}
+
+// `kvs-fsck` should tell a damaged store from a clean one, and repair it
+#[test]
+fn cli_fsck() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1", "value1").unwrap();
+        store.set("key2", "value2").unwrap();
+    }
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("2 records").and(contains("clean")));
+
+    let log = temp_dir.path().join("log_1");
+    let mut data = fs::read(&log).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&log, &data).unwrap();
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .args(["--repair", "repaired"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(
+            contains("corrupt from 35 to 62: record checksum mismatch")
+                .and(contains("1 records salvaged"))
+                .and(contains("damaged")),
+        );
+    let store = KvStore::open(temp_dir.path().join("repaired")).unwrap();
+    assert_eq!(store.get("key1").unwrap(), Some(b"value1".to_vec()));
+}
+
+#[test]
+fn cli_inspect() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1", "value1").unwrap();
+        store.set("key1", "value2").unwrap();
+        store.set("key2", "value3").unwrap();
+        store.remove("key2".to_owned()).unwrap();
+    }
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["records", "--prefix", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("log 1: binary")
+                .and(contains("\tput\tkey2\tv1\n"))
+                .and(contains("\tdel\tkey2\n"))
+                .and(contains("key1").not()),
+        );
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["history", "key1", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("put\tkey1\tv1\tvalue1\n").and(contains("put\tkey1\tv2\tvalue2\n")));
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("summary")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\t4\t1\t"));
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // a torn log is read up to the damage, then reported
+    let log = temp_dir.path().join("log_1");
+    let len = fs::metadata(&log).unwrap().len();
+    let file = fs::OpenOptions::new().write(true).open(&log).unwrap();
+    file.set_len(len - 3).unwrap();
+    drop(file);
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("summary")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("\t3\t2\t"))
+        .stderr(contains("log 1 is damaged at offset"));
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["history", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("put\tkey1\tv2\tvalue2\n"))
+        .stderr(contains("log 1 is damaged"));
+}
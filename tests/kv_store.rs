@@ -1,6 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionTrigger, Condition, EngineOptions, FsckReport, KvStore, KvStoreOptions, KvsEngine,
+    KvsError, LogInspector, Record, Result, SledStore, SyncPolicy, Transaction, WriteBatch,
+};
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -13,14 +18,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -32,16 +37,16 @@ fn overwrite_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value2".to_vec()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value3".to_vec()));
 
     Ok(())
 }
@@ -119,7 +124,7 @@ fn compaction() -> Result<()> {
         let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get(key)?, Some(format!("{}", iter).into_bytes()));
         }
         return Ok(());
     }
@@ -132,27 +137,37 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into_bytes())
+        );
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every clone is dropped
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into_bytes())
+        );
     }
 
     Ok(())
@@ -176,7 +191,7 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    Some(format!("value{}", key_id).into_bytes())
                 );
             }
         });
@@ -197,7 +212,7 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    Some(format!("value{}", key_id).into_bytes())
                 );
             }
         });
@@ -209,3 +224,1316 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should read logs written in the legacy json format, and keep them readable
+// after they are upgraded by compaction
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut log = Vec::new();
+    for record in &[
+        r#"{"version":1,"key":"key1","value":"value1"}"#,
+        r#"{"version":1,"key":"key2","value":"value2"}"#,
+        r#"{"version":0,"key":"key1","value":""}"#,
+    ] {
+        log.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        log.extend_from_slice(record.as_bytes());
+    }
+    std::fs::write(temp_dir.path().join("kvs"), b"").expect("unable to write engine marker");
+    std::fs::write(temp_dir.path().join("log_1"), &log).expect("unable to write legacy log");
+
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    for iter in 0..200 {
+        store.set(format!("key{}", iter % 10 + 10), format!("{}", iter))?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get("key3".to_owned())?, Some(b"value3".to_vec()));
+    assert_eq!(store.get("key19".to_owned())?, Some(b"199".to_vec()));
+    assert!(!temp_dir.path().join("log_1").exists());
+
+    Ok(())
+}
+
+// Should cut off a torn record at the end of the active log instead of
+// failing to open
+#[test]
+fn truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    drop(store);
+
+    // simulate a crash in the middle of writing the last record
+    let log = temp_dir.path().join("log_1");
+    let len = std::fs::metadata(&log).expect("unable to stat log").len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)
+        .expect("unable to open log");
+    file.set_len(len - 3).expect("unable to truncate log");
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // a corrupted record is detected by its checksum
+    let mut data = std::fs::read(&log).expect("unable to read log");
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    std::fs::write(&log, &data).expect("unable to write log");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+
+    // a torn header declaring 4GB key and value is cut off, nothing is
+    // allocated for them
+    let mut data = std::fs::read(&log).expect("unable to read log");
+    data.extend_from_slice(&[0, 0, 0, 0, 1]);
+    data.extend_from_slice(&[0xff; 8]);
+    std::fs::write(&log, &data).expect("unable to write log");
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 13);
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+
+    Ok(())
+}
+
+// Should keep data with every sync policy
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Periodic {
+            interval: Duration::from_millis(10),
+            bytes: 64,
+        },
+    ];
+    for &sync_policy in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = EngineOptions { sync_policy };
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..200 {
+            store.set(format!("key{}", i % 20), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        thread::sleep(Duration::from_millis(20));
+
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 180..200 {
+            if i % 20 != 0 {
+                assert_eq!(
+                    store.get(format!("key{}", i % 20))?,
+                    Some(format!("value{}", i).into_bytes())
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Should keep serving reads and writes while compaction runs in the background
+#[test]
+fn concurrent_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("{}-0", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 1..=100 {
+                for i in (thread_id..100).step_by(4) {
+                    store
+                        .set(format!("key{}", i), format!("{}-{}", i, iter))
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for _ in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..20 {
+                for i in 0..100 {
+                    let value = store.get(format!("key{}", i)).unwrap().unwrap();
+                    assert!(value.starts_with(format!("{}-", i).as_bytes()));
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("{}-100", i).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Should load compacted logs from their hint files, and replay them fully
+// when a hint is damaged
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let hints: Vec<_> = std::fs::read_dir(temp_dir.path())
+        .expect("unable to list directory")
+        .map(|entry| entry.expect("unable to read entry").path())
+        .filter(|path| {
+            path.file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .ends_with(".hint")
+        })
+        .collect();
+    assert!(!hints.is_empty());
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(b"19".to_vec()));
+        }
+        Ok(())
+    };
+    check()?;
+
+    for hint in &hints {
+        let mut data = std::fs::read(hint).expect("unable to read hint");
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(hint, &data).expect("unable to write hint");
+    }
+    check()
+}
+
+// Should keep a log compacted away, with its hint, while a scan still holds
+// it, and delete both once the scan is dropped
+#[test]
+fn retired_log_outlives_its_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    let last_hint = || {
+        std::fs::read_dir(temp_dir.path())
+            .expect("unable to read the store directory")
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_prefix("log_")?
+                    .strip_suffix(".hint")?
+                    .parse()
+                    .ok()
+            })
+            .max()
+    };
+    // overwrite the keys until a compaction leaves a log newer than `after`
+    let compact_after = |after: Option<u64>| -> Result<u64> {
+        for i in 0..10000 {
+            store.set(format!("key{}", i % 50), vec![b'v'; 100])?;
+            match last_hint() {
+                Some(idx) if Some(idx) > after => return Ok(idx),
+                _ => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        panic!("No compaction detected");
+    };
+
+    let compacted = compact_after(None)?;
+    // the compacted log is in use once the logs it replaces are gone
+    while temp_dir.path().join("log_1").exists() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let (log, hint) = (
+        temp_dir.path().join(format!("log_{}", compacted)),
+        temp_dir.path().join(format!("log_{}.hint", compacted)),
+    );
+    let mut pairs = store.scan(.., None)?;
+    assert!(pairs.next().unwrap().is_ok());
+    compact_after(Some(compacted))?;
+    // the compaction is done with the log once the next one has begun
+    compact_after(last_hint())?;
+    assert!(log.exists() && hint.exists());
+    assert_eq!(pairs.collect::<Result<Vec<_>>>()?.len(), 49);
+
+    assert!(!log.exists() && !hint.exists());
+    assert_eq!(store.scan(.., None)?.count(), 50);
+    Ok(())
+}
+
+// small logs compacted often, to exercise compaction in short tests
+fn small_logs() -> KvStoreOptions {
+    KvStoreOptions::new()
+        .max_file_size(1024)
+        .compaction_trigger(CompactionTrigger::StaleRecords(100))
+}
+
+// Should name the logs after the configured prefix, roll them at the
+// configured size and compact once the configured ratio is stale
+#[test]
+fn store_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .file_prefix("data")
+            .max_file_size(4096)
+            .read_buffer_size(64)
+            .compaction_trigger(CompactionTrigger::StaleRatio {
+                ratio: 0.5,
+                min_bytes: 16 * 1024,
+            })
+    };
+    let logs = || {
+        std::fs::read_dir(temp_dir.path())
+            .expect("unable to list directory")
+            .map(|entry| entry.expect("unable to read entry").file_name())
+            .filter(|name| name.to_str().unwrap().starts_with("data_"))
+            .count()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    assert!(logs() > 1);
+    assert!(!temp_dir.path().join("log_1").exists());
+
+    // rewrite everything until the stale half of the logs is compacted away
+    let mut compacted = false;
+    for iter in 1..10 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        thread::sleep(Duration::from_millis(100));
+        if !temp_dir.path().join("data_1").exists() {
+            compacted = true;
+            break;
+        }
+    }
+    assert!(compacted);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let last = store.get("key0".to_owned())?.unwrap();
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(last.clone()));
+    }
+    Ok(())
+}
+
+// Should report a damaged sealed log and a directory of another engine as
+// errors instead of panicking or exiting
+#[test]
+fn open_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().max_file_size(64))?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("log_1");
+    let mut data = std::fs::read(&log).expect("unable to read log");
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    std::fs::write(&log, &data).expect("unable to write log");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ErrCorrupted { .. })
+    ));
+
+    assert!(matches!(
+        SledStore::open(temp_dir.path()),
+        Err(KvsError::ErrEngineMismatch { .. })
+    ));
+    Ok(())
+}
+
+// Should lock the directory while a store has it open, and refuse a
+// directory written by a newer format or another engine
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ErrLocked { .. })
+    ));
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ErrLocked { .. })
+    ));
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    drop(store);
+
+    std::fs::write(
+        temp_dir.path().join("META"),
+        r#"{"engine":"kvs","format_version":99}"#,
+    )
+    .expect("unable to write metadata");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ErrUnsupportedFormat { version: 99, .. })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledStore::open(temp_dir.path())?);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ErrEngineMismatch { .. })
+    ));
+    Ok(())
+}
+
+fn check_scans<E: KvsEngine>(store: E) -> Result<()> {
+    for key in &["b", "a2", "a1", "c", "a3"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("a2".to_owned())?;
+    let keys = |pairs: kvs::KvPairs<'_>| -> Result<Vec<String>> {
+        pairs
+            .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+            .collect()
+    };
+
+    let all: Vec<(Vec<u8>, Vec<u8>)> = store.scan(.., None)?.collect::<Result<_>>()?;
+    assert_eq!(all.len(), 4);
+    assert_eq!(all[0], (b"a1".to_vec(), b"value-a1".to_vec()));
+    assert_eq!(keys(store.scan(.., None)?)?, vec!["a1", "a3", "b", "c"]);
+    assert_eq!(
+        keys(store.scan(b"a3".to_vec()..b"c".to_vec(), None)?)?,
+        vec!["a3", "b"]
+    );
+    assert_eq!(keys(store.scan(b"b".to_vec().., Some(1))?)?, vec!["b"]);
+    assert_eq!(
+        keys(store.scan_prefix("a".to_owned(), None)?)?,
+        vec!["a1", "a3"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("a".to_owned(), Some(1))?)?,
+        vec!["a1"]
+    );
+    assert!(keys(store.scan_prefix("d".to_owned(), None)?)?.is_empty());
+    assert!(keys(store.scan(b"c".to_vec()..b"a".to_vec(), None)?)?.is_empty());
+    Ok(())
+}
+
+// Should scan keys in order, skipping removed ones, on both engines
+#[test]
+fn scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(SledStore::open(temp_dir.path())?)
+}
+
+fn check_binary<E: KvsEngine>(store: &E) -> Result<()> {
+    let value: Vec<u8> = (0..=255).collect();
+    store.set(&b"\x00bin\xff"[..], value.clone())?;
+    store.set(&b"\xff"[..], &b"\n\r\x00"[..])?;
+    assert_eq!(store.get(&b"\x00bin\xff"[..])?, Some(value));
+    assert_eq!(store.get(&b"\xff"[..])?, Some(b"\n\r\x00".to_vec()));
+    assert_eq!(store.get(&b"\x00bin"[..])?, None);
+    let keys: Vec<Vec<u8>> = store
+        .scan(.., None)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"\x00bin\xff".to_vec(), b"\xff".to_vec()]);
+    Ok(())
+}
+
+// Should keep keys and values that are not utf-8 as they are, on both engines
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_binary(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check_binary(&store)?;
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    check_binary(&store)?;
+    drop(store);
+    let store = SledStore::open(temp_dir.path())?;
+    check_binary(&store)
+}
+
+fn check_ttl<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set_with_ttl("short", "value", Duration::from_millis(100))?;
+    store.set_with_ttl("long", "value", Duration::from_secs(3600))?;
+    store.set("plain", "value")?;
+
+    assert_eq!(store.ttl("plain")?, None);
+    let ttl = store.ttl("long")?.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert!(matches!(
+        store.ttl("missing"),
+        Err(KvsError::ErrKeyNotFound)
+    ));
+    assert_eq!(store.get("short")?, Some(b"value".to_vec()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("short")?, None);
+    assert!(matches!(store.ttl("short"), Err(KvsError::ErrKeyNotFound)));
+    assert!(matches!(
+        store.persist("short"),
+        Err(KvsError::ErrKeyNotFound)
+    ));
+    assert!(matches!(
+        store.remove("short"),
+        Err(KvsError::ErrKeyNotFound)
+    ));
+    let keys: Vec<Vec<u8>> = store
+        .scan(.., None)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"long".to_vec(), b"plain".to_vec()]);
+
+    // a plain set drops the ttl, persist too
+    store.set_with_ttl("plain", "value", Duration::from_secs(3600))?;
+    assert!(store.ttl("plain")?.is_some());
+    store.set("plain", "value2")?;
+    assert_eq!(store.ttl("plain")?, None);
+    store.persist("long")?;
+    assert_eq!(store.ttl("long")?, None);
+    assert_eq!(store.get("long")?, Some(b"value".to_vec()));
+    assert!(matches!(
+        store.persist("missing"),
+        Err(KvsError::ErrKeyNotFound)
+    ));
+
+    // an expired key can be set again
+    store.set_with_ttl("short", "value2", Duration::from_secs(3600))?;
+    assert_eq!(store.get("short")?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Should expire keys set with a ttl, on both engines
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_ttl(&store)?;
+    drop(store);
+    // the expiry times are persisted
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("short")?.is_some());
+    assert_eq!(store.ttl("long")?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    check_ttl(&store)?;
+    drop(store);
+    let store = SledStore::open(temp_dir.path())?;
+    assert!(store.ttl("short")?.is_some());
+    assert_eq!(store.ttl("long")?, None);
+    Ok(())
+}
+
+// Should drop expired keys from the logs when compacting
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    // the background compaction may remove a log while it is walked
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+
+    for i in 0..100 {
+        store.set_with_ttl(
+            format!("tmp{}", i),
+            vec![b'x'; 1000],
+            Duration::from_millis(100),
+        )?;
+    }
+    assert!(dir_size() > 100_000);
+    thread::sleep(Duration::from_millis(200));
+    // overwrite a key until a compaction runs
+    for i in 0..1000 {
+        store.set("key", format!("{}", i))?;
+        if dir_size() < 50_000 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(dir_size() < 50_000);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("tmp0")?, None);
+    assert!(matches!(store.ttl("tmp0"), Err(KvsError::ErrKeyNotFound)));
+    assert!(store.get("key")?.is_some());
+    Ok(())
+}
+
+fn check_conditional_writes<E: KvsEngine>(store: &E) -> Result<()> {
+    assert_eq!(store.get_versioned("key1")?, None);
+    store.set_if("key1", "value1", Condition::Absent, None)?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value1".to_vec(), 1)));
+    assert!(matches!(
+        store.set_if("key1", "value2", Condition::Absent, None),
+        Err(KvsError::ErrConditionFailed)
+    ));
+
+    store.set_if(
+        "key1",
+        "value2",
+        Condition::Equals(b"value1".to_vec()),
+        None,
+    )?;
+    assert!(matches!(
+        store.set_if(
+            "key1",
+            "value3",
+            Condition::Equals(b"value1".to_vec()),
+            None
+        ),
+        Err(KvsError::ErrConditionFailed)
+    ));
+    assert_eq!(store.get_versioned("key1")?, Some((b"value2".to_vec(), 2)));
+
+    store.set("key1", "value3")?;
+    assert!(matches!(
+        store.set_if("key1", "value4", Condition::Version(2), None),
+        Err(KvsError::ErrConditionFailed)
+    ));
+    store.set_if("key1", "value4", Condition::Version(3), None)?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value4".to_vec(), 4)));
+
+    // a removed key starts over
+    store.remove("key1")?;
+    assert!(matches!(
+        store.set_if(
+            "key1",
+            "value5",
+            Condition::Equals(b"value4".to_vec()),
+            None
+        ),
+        Err(KvsError::ErrConditionFailed)
+    ));
+    store.set_if("key1", "value5", Condition::Version(0), None)?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value5".to_vec(), 1)));
+
+    // and so does an expired one
+    store.set_if(
+        "key2",
+        "value1",
+        Condition::Absent,
+        Some(Duration::from_millis(100)),
+    )?;
+    assert!(store.ttl("key2")?.is_some());
+    thread::sleep(Duration::from_millis(200));
+    store.set_if("key2", "value2", Condition::Absent, None)?;
+    assert_eq!(store.get_versioned("key2")?, Some((b"value2".to_vec(), 1)));
+    Ok(())
+}
+
+// Should only apply conditional writes whose condition holds, on both engines
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_conditional_writes(&store)?;
+    drop(store);
+    // the versions are persisted
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value5".to_vec(), 1)));
+    store.set("key1", "value6")?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value6".to_vec(), 2)));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    check_conditional_writes(&store)?;
+    drop(store);
+    let store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value5".to_vec(), 1)));
+    Ok(())
+}
+
+fn check_concurrent_increments<E: KvsEngine + Sync>(store: E) -> Result<()> {
+    store.set("counter", "0")?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    // retry until no other thread wrote in between
+                    loop {
+                        let (value, version) = store.get_versioned("counter").unwrap().unwrap();
+                        let counter: u32 = String::from_utf8(value).unwrap().parse().unwrap();
+                        let next = format!("{}", counter + 1);
+                        match store.set_if("counter", next, Condition::Version(version), None) {
+                            Ok(()) => break,
+                            Err(KvsError::ErrConditionFailed) => continue,
+                            Err(err) => panic!("{}", err),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(
+        store.get_versioned("counter")?,
+        Some((b"200".to_vec(), 201))
+    );
+    Ok(())
+}
+
+// Should not lose any increment made with version-checked sets
+#[test]
+fn concurrent_increments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_increments(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_increments(SledStore::open(temp_dir.path())?)
+}
+
+fn check_write_batches<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("key1", "value1")?;
+    store.set("key2", "value1")?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1", "value2")
+        .remove("key2")
+        .set("key3", "value1")
+        .set("key3", "value2")
+        // removing a missing key does nothing
+        .remove("key4");
+    store.write_batch(batch)?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value2".to_vec(), 2)));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get_versioned("key3")?, Some((b"value2".to_vec(), 2)));
+    assert_eq!(store.get("key4")?, None);
+
+    // a key removed and set again in a batch starts over
+    let mut batch = WriteBatch::new();
+    batch.remove("key1").set("key1", "value3");
+    store.write_batch(batch)?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value3".to_vec(), 1)));
+
+    store.write_batch(WriteBatch::new())?;
+    Ok(())
+}
+
+// Should apply the writes of a batch in order, on both engines
+#[test]
+fn write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_write_batches(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get_versioned("key1")?, Some((b"value3".to_vec(), 1)));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get_versioned("key3")?, Some((b"value2".to_vec(), 2)));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    check_write_batches(&store)?;
+    drop(store);
+    let store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value3".to_vec(), 1)));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get_versioned("key3")?, Some((b"value2".to_vec(), 2)));
+    Ok(())
+}
+
+// Should drop a whole batch whose commit record did not make it to the log
+#[test]
+fn uncommitted_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    let log = temp_dir.path().join("log_1");
+    let committed = std::fs::metadata(&log).expect("unable to stat log").len();
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value2").set("key2", "value2");
+    store.write_batch(batch)?;
+    drop(store);
+
+    // simulate a crash right before the commit record, a checksum and a header
+    let len = std::fs::metadata(&log).expect("unable to stat log").len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)
+        .expect("unable to open log");
+    file.set_len(len - 13).expect("unable to truncate log");
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), len - 13 - committed);
+    assert_eq!(store.get("key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2")?, None);
+    store.set("key2", "value3")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get_versioned("key2")?, Some((b"value3".to_vec(), 1)));
+    Ok(())
+}
+
+// Should read the store as it was when the snapshot was taken
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value1")?;
+    store.set_with_ttl("key3", "value1", Duration::from_millis(100))?;
+
+    let snapshot = store.snapshot();
+    store.set("key1", "value2")?;
+    store.remove("key2")?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").set("key4", "value2");
+    store.write_batch(batch)?;
+    let later = store.snapshot();
+    store.set("key1", "value3")?;
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(store.get("key1")?, Some(b"value3".to_vec()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(
+        snapshot.get_versioned("key1")?,
+        Some((b"value1".to_vec(), 1))
+    );
+    assert_eq!(snapshot.get("key2")?, Some(b"value1".to_vec()));
+    // a key expiring after the snapshot is still in it
+    assert_eq!(snapshot.get("key3")?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get("key4")?, None);
+    let pairs = snapshot
+        .scan_prefix("key", None)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value1".to_vec()),
+            (b"key3".to_vec(), b"value1".to_vec()),
+        ]
+    );
+
+    assert_eq!(later.get_versioned("key1")?, Some((b"value2".to_vec(), 2)));
+    assert_eq!(later.get_versioned("key2")?, Some((b"value2".to_vec(), 1)));
+    let pairs = later
+        .scan(b"key2".to_vec().., Some(3))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value1".to_vec()),
+            (b"key4".to_vec(), b"value2".to_vec()),
+        ]
+    );
+
+    // dropping a snapshot leaves the others as they were
+    drop(snapshot);
+    assert_eq!(later.get("key1")?, Some(b"value2".to_vec()));
+    drop(later);
+    assert_eq!(store.get("key1")?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// Should keep the records a snapshot sees across compactions
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_with_ttl("tmp", "value", Duration::from_millis(100))?;
+
+    let snapshot = store.snapshot();
+    for i in 0..50 {
+        store.remove(format!("key{}", i))?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    // overwrite a key until the first log is compacted away
+    let first_log = temp_dir.path().join("log_1");
+    for i in 0..10000 {
+        store.set("key99", format!("{}", i))?;
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(!first_log.exists());
+
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    assert_eq!(snapshot.get("tmp")?, Some(b"value".to_vec()));
+    assert_eq!(snapshot.scan(.., None)?.count(), 101);
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("tmp")?, None);
+    assert_eq!(store.scan(.., None)?.count(), 50);
+    drop(snapshot);
+    let last = store.get("key99")?;
+    drop(store);
+
+    // the kept records do not come back once the store is reopened
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key50")?, Some(b"value50".to_vec()));
+    assert_eq!(store.get("key99")?, last);
+    assert_eq!(store.get("tmp")?, None);
+    assert_eq!(store.scan(.., None)?.count(), 50);
+    Ok(())
+}
+
+// Should keep what a snapshot sees while its keys are overwritten during
+// compactions
+#[test]
+fn snapshot_during_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = small_logs().sync_policy(SyncPolicy::Never);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), "0")?;
+    }
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..=200 {
+                for i in 0..50 {
+                    store.set(format!("key{}", i), format!("{}", round))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let mut snapshots = 0;
+    while !writer.is_finished() || snapshots == 0 {
+        let snapshot = store.snapshot();
+        let read =
+            || -> Result<Vec<_>> { (0..50).map(|i| snapshot.get(format!("key{}", i))).collect() };
+        let seen = read()?;
+        assert!(seen.iter().all(Option::is_some));
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(read()?, seen);
+        snapshots += 1;
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.get("key0")?, Some(b"200".to_vec()));
+    Ok(())
+}
+
+fn check_transactions<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("key1", "value1")?;
+    store.set("key2", "value1")?;
+
+    let mut tx = store.begin_transaction()?;
+    assert_eq!(tx.get("key1")?, Some(b"value1".to_vec()));
+    tx.set("key1", "value2");
+    assert_eq!(tx.get("key1")?, Some(b"value2".to_vec()));
+    tx.remove("key2")?;
+    assert_eq!(tx.get("key2")?, None);
+    assert!(matches!(tx.remove("key3"), Err(KvsError::ErrKeyNotFound)));
+    tx.set("key3", "value1");
+    // nothing is applied before the commit
+    assert_eq!(store.get("key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key3")?, None);
+    tx.commit()?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value2".to_vec(), 2)));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get_versioned("key3")?, Some((b"value1".to_vec(), 1)));
+
+    // a key read and written since conflicts
+    let mut tx = store.begin_transaction()?;
+    assert_eq!(tx.get("key1")?, Some(b"value2".to_vec()));
+    tx.set("key4", "value1");
+    store.set("key1", "value3")?;
+    assert!(matches!(tx.commit(), Err(KvsError::ErrConflict)));
+    assert_eq!(store.get("key4")?, None);
+
+    // and so does a key read as missing and set since
+    let mut tx = store.begin_transaction()?;
+    assert_eq!(tx.get("key2")?, None);
+    tx.set("key4", "value1");
+    store.set("key2", "value2")?;
+    assert!(matches!(tx.commit(), Err(KvsError::ErrConflict)));
+    assert_eq!(store.get("key4")?, None);
+
+    // a key only written by the transaction does not
+    let mut tx = store.begin_transaction()?;
+    tx.set("key3", "value2");
+    store.set("key3", "value3")?;
+    tx.commit()?;
+    assert_eq!(store.get_versioned("key3")?, Some((b"value2".to_vec(), 3)));
+
+    // dropping a transaction rolls it back
+    let mut tx = store.begin_transaction()?;
+    tx.set("key4", "value1");
+    drop(tx);
+    assert_eq!(store.get("key4")?, None);
+    Ok(())
+}
+
+fn check_concurrent_transfers<E: KvsEngine + Sync>(store: E) -> Result<()> {
+    store.set("from", "100")?;
+    store.set("to", "0")?;
+    let read = |tx: &mut E::Transaction, key: &str| -> u32 {
+        let value = tx.get(key).unwrap().unwrap();
+        String::from_utf8(value).unwrap().parse().unwrap()
+    };
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    // retry until no other thread wrote in between
+                    loop {
+                        let mut tx = store.begin_transaction().unwrap();
+                        let from = read(&mut tx, "from");
+                        let to = read(&mut tx, "to");
+                        tx.set("from", format!("{}", from - 1));
+                        tx.set("to", format!("{}", to + 1));
+                        match tx.commit() {
+                            Ok(()) => break,
+                            Err(KvsError::ErrConflict) => continue,
+                            Err(err) => panic!("{}", err),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("from")?, Some(b"0".to_vec()));
+    assert_eq!(store.get("to")?, Some(b"100".to_vec()));
+    Ok(())
+}
+
+// Should only commit the transactions whose reads still hold, on both engines
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_transactions(&store)?;
+    // the transaction reads what was there when it started
+    let mut tx = store.begin_transaction()?;
+    store.set("key1", "value4")?;
+    assert_eq!(tx.get("key1")?, Some(b"value3".to_vec()));
+    drop(tx);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_transfers(KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    check_transactions(&store)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_transfers(SledStore::open(temp_dir.path())?)
+}
+
+fn check_checkpoint<E: KvsEngine>(store: &E, dest: &Path) -> Result<()> {
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set_with_ttl("key2", "value1", Duration::from_secs(100))?;
+    store.set("key3", "value1")?;
+    store.remove("key3")?;
+    store.checkpoint(dest)?;
+    // the copy is not changed by the writes that follow
+    store.set("key1", "value3")?;
+    store.set("key4", "value1")?;
+    assert!(matches!(store.checkpoint(dest), Err(KvsError::ErrIo(_))));
+    Ok(())
+}
+
+fn check_checkpoint_copy<E: KvsEngine>(copy: &E) -> Result<()> {
+    assert_eq!(copy.get_versioned("key1")?, Some((b"value2".to_vec(), 2)));
+    assert_eq!(copy.get("key2")?, Some(b"value1".to_vec()));
+    assert!(copy.ttl("key2")?.is_some());
+    assert_eq!(copy.get("key3")?, None);
+    assert_eq!(copy.get("key4")?, None);
+    Ok(())
+}
+
+// Should copy the store as it was when the checkpoint started, on both engines
+#[test]
+fn checkpoints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("copy");
+    check_checkpoint(&store, &dest)?;
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.discarded_bytes(), 0);
+    check_checkpoint_copy(&copy)?;
+    // the copy is a store of its own
+    copy.set("key1", "value4")?;
+    assert_eq!(store.get("key1")?, Some(b"value3".to_vec()));
+    drop(copy);
+    assert!(matches!(
+        SledStore::open(&dest),
+        Err(KvsError::ErrEngineMismatch { .. })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("copy");
+    check_checkpoint(&store, &dest)?;
+    check_checkpoint_copy(&SledStore::open(&dest)?)
+}
+
+// Should take consistent checkpoints while writes and compactions go on, on
+// both engines
+#[test]
+fn checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = spawn_batch_writer(&store);
+    for n in 0..5 {
+        let dest = backup_dir.path().join(format!("copy{}", n));
+        store.checkpoint(&dest)?;
+        let copy = KvStore::open(&dest)?;
+        assert_eq!(copy.discarded_bytes(), 0);
+        check_batch_copy(&copy)?;
+    }
+    writer.join().unwrap()?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    // pairs between the keys of the batches, for the writes to happen while
+    // they are copied
+    for i in 0..10 {
+        for j in 0..200 {
+            store.set(format!("key{}/{}", i, j), vec![b'p'; 100])?;
+        }
+    }
+    let writer = spawn_batch_writer(&store);
+    for n in 0..5 {
+        let dest = backup_dir.path().join(format!("sled{}", n));
+        store.checkpoint(&dest)?;
+        check_batch_copy(&SledStore::open(&dest)?)?;
+    }
+    writer.join().unwrap()
+}
+
+/// write batches of the same value for 10 keys, from another thread
+fn spawn_batch_writer<E: KvsEngine>(store: &E) -> thread::JoinHandle<Result<()>> {
+    let store = store.clone();
+    thread::spawn(move || {
+        for round in 0..200 {
+            // the pairs of a batch are always copied together
+            let mut batch = WriteBatch::new();
+            for i in 0..10 {
+                batch.set(format!("key{}", i), format!("{}", round));
+            }
+            store.write_batch(batch)?;
+        }
+        Ok(())
+    })
+}
+
+/// check that a copy has whole batches of `spawn_batch_writer`
+fn check_batch_copy<E: KvsEngine>(copy: &E) -> Result<()> {
+    let values = (0..10)
+        .map(|i| copy.get(format!("key{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(values.iter().all(|value| *value == values[0]));
+    Ok(())
+}
+
+// Should find the damaged records of any log, and salvage the others
+#[test]
+fn fsck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), vec![b'v'; 100])?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key20", "value").set("key21", "value");
+    store.write_batch(batch)?;
+    drop(store);
+    let report = FsckReport::check(temp_dir.path(), "log")?;
+    assert!(report.is_clean());
+    assert!(report.logs.len() > 1);
+    assert_eq!(report.logs.iter().map(|log| log.records).sum::<u64>(), 22);
+    assert_eq!(report.logs.iter().map(|log| log.batches).sum::<u64>(), 1);
+
+    // damage the value of the second record of the first, sealed, log
+    // header: 8, record: crc 4 + header 9 + version 4 + key 5 + value 100
+    let log = temp_dir.path().join("log_1");
+    let mut data = std::fs::read(&log).expect("unable to read log");
+    data[130 + 30] ^= 0xff;
+    std::fs::write(&log, &data).expect("unable to write log");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ErrCorrupted { offset: 130, .. })
+    ));
+    let report = FsckReport::check(temp_dir.path(), "log")?;
+    assert!(!report.is_clean());
+    assert_eq!(report.logs[0].corrupt.len(), 1);
+    assert_eq!(report.logs[0].corrupt[0].start, 130);
+    assert_eq!(report.logs[0].corrupt[0].end, 252);
+    assert!(report.logs[0].corrupt[0].reason.contains("checksum"));
+
+    let repaired = TempDir::new().expect("unable to create temporary working directory");
+    let report = FsckReport::repair(temp_dir.path(), "log", repaired.path())?;
+    assert_eq!(report.salvaged, 21);
+    let store = KvStore::open(repaired.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key00")?, Some(vec![b'v'; 100]));
+    assert_eq!(store.get("key01")?, None);
+    assert_eq!(store.get_versioned("key21")?, Some((b"value".to_vec(), 1)));
+    drop(store);
+    assert!(FsckReport::check(repaired.path(), "log")?.is_clean());
+
+    // files that do not belong
+    std::fs::copy(&log, temp_dir.path().join("log_01")).expect("unable to copy log");
+    std::fs::write(temp_dir.path().join("log_9.hint"), b"").expect("unable to write hint");
+    let report = FsckReport::check(temp_dir.path(), "log")?;
+    assert_eq!(report.duplicates.len(), 1);
+    assert_eq!(report.duplicates[0].0, 1);
+    assert_eq!(
+        report.orphaned_hints,
+        vec![temp_dir.path().join("log_9.hint")]
+    );
+    // the store does not replay two files as the same log
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("log_01"), "{}", err);
+    Ok(())
+}
+
+// Should list the records of every log and tell the live ones from the
+// stale ones
+#[test]
+fn inspect_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), small_logs())?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), vec![b'v'; 100])?;
+    }
+    for i in 0..5 {
+        store.set(format!("key{:02}", i), vec![b'w'; 100])?;
+    }
+    store.remove("key05".to_owned())?;
+    drop(store);
+
+    let inspector = LogInspector::new(temp_dir.path(), "log");
+    let summaries = inspector.summary()?;
+    assert!(summaries.len() > 1);
+    assert_eq!(summaries.iter().map(|log| log.records).sum::<u64>(), 26);
+    assert_eq!(
+        summaries.iter().map(|log| log.live_records).sum::<u64>(),
+        19
+    );
+    for log in &summaries {
+        // the header is neither live nor stale
+        assert_eq!(log.live_bytes + log.stale_bytes, log.len - 8);
+    }
+    // the values overwritten or removed are all in the first log
+    assert_eq!(summaries[0].records - summaries[0].live_records, 6);
+
+    let history = inspector.history(b"key00")?;
+    assert_eq!(history.entries.len(), 2);
+    assert!(history.damaged.is_empty());
+    let (first, second) = (&history.entries[0], &history.entries[1]);
+    assert!(first.file < second.file);
+    assert!(matches!(&second.record, Record::Kv(kv) if kv.value == vec![b'w'; 100]));
+    let entries: Vec<_> = inspector.records(first.file)?.collect::<Result<_>>()?;
+    assert_eq!(entries[0].offset, 8);
+    assert_eq!(entries[1].offset, 8 + entries[0].len);
+
+    // a torn tombstone stops its log, what was read before it is kept
+    let tombstone = inspector.history(b"key05")?.entries.pop().unwrap();
+    let log = temp_dir.path().join(format!("log_{}", tombstone.file));
+    let len = std::fs::metadata(&log).expect("unable to stat log").len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)
+        .expect("unable to open log");
+    file.set_len(len - 3).expect("unable to truncate log");
+    drop(file);
+    let summaries = inspector.summary()?;
+    assert_eq!(summaries.iter().map(|log| log.records).sum::<u64>(), 25);
+    assert_eq!(
+        summaries.iter().map(|log| log.live_records).sum::<u64>(),
+        20
+    );
+    let torn = summaries.iter().find(|log| log.file == tombstone.file);
+    let damaged = torn.and_then(|log| log.damaged.as_ref()).unwrap();
+    assert_eq!((damaged.start, damaged.end), (tombstone.offset, len - 3));
+    assert_eq!(
+        summaries.iter().filter(|log| log.damaged.is_some()).count(),
+        1
+    );
+    let history = inspector.history(b"key05")?;
+    assert_eq!(history.entries.len(), 1);
+    assert_eq!(history.damaged.len(), 1);
+    assert_eq!(history.damaged[0].0, tombstone.file);
+    Ok(())
+}